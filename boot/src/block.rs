@@ -0,0 +1,62 @@
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// No card or device didn't respond
+    NoDevice,
+    Timeout,
+    Crc,
+    /// Device or medium the driver doesn't handle
+    Unsupported,
+    /// Access past the end of the device
+    OutOfRange,
+    /// Any other controller or device error
+    Io,
+}
+
+impl Error {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Error::NoDevice => "no device",
+            Error::Timeout => "timeout",
+            Error::Crc => "CRC error",
+            Error::Unsupported => "unsupported",
+            Error::OutOfRange => "out of range",
+            Error::Io => "I/O error",
+        }
+    }
+}
+
+/// Storage read in [`BLOCK_SIZE`] blocks
+pub trait BlockDevice {
+    fn block_count(&self) -> u64;
+
+    /// Reads `buf.len() / BLOCK_SIZE` blocks starting at `lba`
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error>;
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        (**self).read_blocks(lba, buf)
+    }
+}
+
+/// Disk image in memory, for the tests
+#[cfg(test)]
+impl BlockDevice for std::vec::Vec<u8> {
+    fn block_count(&self) -> u64 {
+        (self.len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let start = lba as usize * BLOCK_SIZE;
+        let src = self.get(start..start + buf.len()).ok_or(Error::OutOfRange)?;
+        buf.copy_from_slice(src);
+
+        Ok(())
+    }
+}
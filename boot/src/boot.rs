@@ -1,35 +1,402 @@
-#![no_std]
-#![no_main]
+// Built for the host with std, the simulated register file and no entry
+// point by `cargo test` and the `sim` feature, see src/sim.rs
+#![cfg_attr(not(any(test, feature = "sim")), no_std)]
+#![cfg_attr(not(any(test, feature = "sim")), no_main)]
 #![allow(dead_code)]
 
+#[cfg(not(any(test, feature = "sim")))]
 use core::arch::global_asm;
 
+mod block;
 mod ccu;
+#[cfg(feature = "fs")]
+mod conf;
+mod diag;
 mod dram;
 mod elf;
+#[cfg(feature = "ext2")]
+mod ext2;
+#[cfg(feature = "fat")]
+mod fat;
+#[cfg(feature = "fs")]
+mod fs;
+mod handoff;
 mod mmio;
+mod monitor;
 mod panic;
+#[cfg(feature = "fs")]
+mod part;
+mod rtc;
+#[cfg(any(test, feature = "sim"))]
+mod sim;
+#[cfg(feature = "slots")]
+mod slot;
+#[cfg(feature = "sdcard")]
+mod smhc;
+#[cfg(feature = "spinor")]
+mod spi;
+#[cfg(feature = "spinor")]
+mod spinor;
 mod time;
+#[cfg(feature = "mmio-trace")]
+mod trace;
 mod uart;
+mod wdt;
+#[cfg(feature = "zmodem")]
 mod zmodem;
 
+#[cfg(not(any(test, feature = "sim")))]
 global_asm!(include_str!("boot.S"));
 
+const CONSOLE_BAUD: u32 = 115200;
+
+/// Baud rate for the ZMODEM transfer. When it differs from [`CONSOLE_BAUD`]
+/// the bootloader switches after the host confirms with a CR at the new rate,
+/// and goes back to [`CONSOLE_BAUD`] once the image is received.
+const ZMODEM_BAUD: u32 = CONSOLE_BAUD;
+
+/// Monitor command line run when the autoboot countdown isn't interrupted.
+/// SPI NOR boards keep the kernel ELF at 1 MiB into the flash, after boot.img.
+/// It's staged at 32 MiB into DRAM like the file system builds do, clear of
+/// the kernel's load address.
+#[cfg(not(any(feature = "fs", feature = "spinor")))]
+const BOOTCMD: &str = "loadz 40000000; bootelf 40000000";
+#[cfg(all(feature = "spinor", not(feature = "fs")))]
+const BOOTCMD: &str = "sfread 42000000 100000 300000; bootelf 42000000";
+#[cfg(feature = "fs")]
+const BOOTCMD: &str = "sysboot; load 42000000 /boot/kernel.elf; bootelf 42000000";
+
+/// Run instead of the boot command when the host starts `sz` during the
+/// autoboot countdown
+#[cfg(feature = "zmodem")]
+const ZMODEM_BOOTCMD: &str = "loadz 40000000; bootelf 40000000";
+
+/// Boot command of slot B, slot A uses [`BOOTCMD`]. Without storage the
+/// image has to be staged in DRAM, e.g. by the kernel before it reboots.
+#[cfg(all(feature = "slots", not(any(feature = "fs", feature = "spinor"))))]
+const BOOTCMD_B: &str = "bootelf 48000000";
+#[cfg(all(feature = "slots", feature = "spinor", not(feature = "fs")))]
+const BOOTCMD_B: &str = "sfread 42000000 400000 300000; bootelf 42000000";
+#[cfg(all(feature = "slots", feature = "fs"))]
+const BOOTCMD_B: &str = "load 42000000 /boot/kernel-b.elf; bootelf 42000000";
+
+/// Boots of an unconfirmed slot before falling back to the other one
+#[cfg(feature = "slots")]
+const BOOT_TRIES: u8 = 3;
+
+/// Watchdog period the kernel of an unconfirmed slot gets to confirm it, a
+/// kernel that hangs before resets the board and uses up a try
+#[cfg(feature = "slots")]
+const KERNEL_WATCHDOG_MS: u32 = 16000;
+
+/// What the DRAM init failure, panics and traps do after printing their message
+const ON_FATAL: wdt::OnFatal = wdt::OnFatal::Reset;
+
+/// Watchdog period over the clock and DRAM init, which take well below a
+/// second when they don't get stuck
+const INIT_WATCHDOG_MS: u32 = 4000;
+
+/// RISC-V core clock set at boot, e.g. 720 MHz for thermal tests or 1.2 GHz
+/// for benchmarks, [`ccu::CPU_FREQ_MAX`] at most. PLL_CPU gets the closest
+/// rate not above it in steps of 6 MHz or more, the `cpufreq` monitor command
+/// changes it later on.
+const CPU_FREQ: u64 = 1_008_000_000;
+
+const _: () = assert!(ccu::solve_cpu_pll(CPU_FREQ).is_some(), "CPU_FREQ is below PLL_CPU's range");
+const _: () = assert!(CPU_FREQ <= ccu::CPU_FREQ_MAX, "CPU_FREQ is above what the core runs at");
+
+/// Autoboot countdown in seconds, 0 goes straight to the monitor. The boot
+/// configuration's timeout takes precedence.
+const BOOTDELAY: u64 = 3;
+
+/// Boot configuration files, the first one found is used
+#[cfg(feature = "fs")]
+const CONF_PATHS: [&str; 2] = ["/boot/os5.conf", "/boot/extlinux/extlinux.conf"];
+
+/// DRAM layout used by `sysboot`. The configuration text stays in DRAM for
+/// the monitor's lifetime, right below the kernel's load address.
+#[cfg(feature = "fs")]
+const CONF_ADDR: u64 = 0x400f_0000;
+#[cfg(feature = "fs")]
+const CONF_MAX: usize = 0xf000;
+#[cfg(feature = "fs")]
+const FDT_ADDR: u64 = 0x4100_0000;
+#[cfg(feature = "fs")]
+const KERNEL_ADDR: u64 = 0x4200_0000;
+#[cfg(feature = "fs")]
+const INITRD_ADDR: u64 = 0x4300_0000;
+
+/// Size of the boot configuration text at [`CONF_ADDR`], 0 if there's none
+#[cfg(feature = "fs")]
+static mut CONF_SIZE: usize = 0;
+
+#[cfg(not(any(test, feature = "sim")))]
+unsafe extern "C" {
+    /// Continues in `f` with the stack pointer at `top` (boot.S)
+    fn switch_stack(top: u64, f: extern "C" fn() -> !) -> !;
+}
+
+#[cfg(not(any(test, feature = "sim")))]
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn _main() -> ! {
-    unsafe { uart::uart_init() };
+    unsafe { uart::uart_init(CONSOLE_BAUD) };
 
     uart::printf!("Bootloader is running\r\n");
 
+    diag::init();
+    diag::print_last();
+
+    wdt::WDT.enable(INIT_WATCHDOG_MS);
+
     unsafe { ccu::init_clocks() };
+
+    // The divisor from uart_init is based on APB1 as the boot ROM left it
+    let baud = unsafe { uart::set_baud(CONSOLE_BAUD) };
+
+    match baud {
+        Some(baud) => baud.print(),
+        None => uart::printf!("UART0: can't reach %d baud\r\n", CONSOLE_BAUD),
+    }
+
     unsafe { dram::init_dram() };
+    #[cfg(feature = "mmio-trace")]
+    unsafe { trace::use_dram() };
+
+    wdt::WDT.disable();
+
+    // SRAM only has room for the stack of the early init, reading files and
+    // the monitor take more
+    unsafe { switch_stack(dram::stack_top(), main) }
+}
+
+/// `cargo run --features sim -- clocks|dram` prints the register accesses of
+/// the clock or DRAM init on the simulated D1, see src/sim.rs, and what it
+/// printed on stderr. `registers` prints the register definitions.
+#[cfg(all(feature = "sim", not(test)))]
+fn main() {
+    let trace = match std::env::args().nth(1).as_deref() {
+        Some("clocks") => sim::init_clocks_trace(),
+        Some("dram") => sim::init_dram_trace(),
+        Some("registers") => sim::registers(),
+        _ => {
+            std::eprintln!("usage: boot clocks|dram|registers");
+            std::process::exit(2);
+        }
+    };
+
+    std::print!("{trace}");
+    std::eprint!("{}", sim::take_console());
+}
+
+#[cfg(not(any(test, feature = "sim")))]
+extern "C" fn main() -> ! {
+    diag::set_stage(diag::Stage::Monitor);
+    handoff::init();
+
+    #[cfg(feature = "fs")]
+    let bootdelay = match load_config() {
+        Some(config) => config.timeout.map_or(BOOTDELAY, |t| t.div_ceil(10) as u64),
+        None => BOOTDELAY,
+    };
+    #[cfg(not(feature = "fs"))]
+    let bootdelay = BOOTDELAY;
+
+    #[cfg(feature = "slots")]
+    let (bootcmd, bootdelay, retry) = match select_slot() {
+        Some((bootcmd, confirmed)) => (bootcmd, bootdelay, !confirmed),
+        None => ("", 0, false),
+    };
+    #[cfg(not(feature = "slots"))]
+    let bootcmd = BOOTCMD;
+
+    let monitor = monitor::Monitor::new(uart::Console, bootcmd);
+
+    let autoboot = match bootdelay {
+        0 => monitor::Autoboot::Interrupted,
+        _ => monitor::autoboot(bootdelay),
+    };
+
+    match autoboot {
+        monitor::Autoboot::Elapsed => {
+            monitor.execute(monitor::Command::Boot);
+
+            // The next reset counts as another try, and eventually falls back
+            #[cfg(feature = "slots")]
+            if retry {
+                uart::printf!("boot failed, resetting\r\n");
+                wdt::reset();
+            }
+        }
+        #[cfg(feature = "zmodem")]
+        monitor::Autoboot::Transfer => monitor.run_line(ZMODEM_BOOTCMD),
+        monitor::Autoboot::Interrupted => {}
+    }
 
+    monitor.run()
+}
+
+/// Counts a boot attempt in the stored slot state. Returns the boot command
+/// of the slot to boot and whether it's confirmed, None if both failed.
+#[cfg(feature = "slots")]
+fn select_slot() -> Option<(&'static str, bool)> {
+    let (state, slot) = slot::load().select(BOOT_TRIES);
+    slot::store(state);
+
+    let Some(slot) = slot else {
+        uart::printf!("no bootable slot\r\n");
+        return None;
+    };
+
+    slot::print_state(&state);
+
+    let info = handoff::boot_info();
+    info.slot = slot as u32;
+    info.confirm_addr = slot::state_addr();
+    info.confirm_value = state.confirm().encode();
+    info.watchdog_ms = if state.is_successful(slot) { 0 } else { KERNEL_WATCHDOG_MS };
+
+    match slot {
+        slot::Slot::A => Some((BOOTCMD, state.is_successful(slot))),
+        slot::Slot::B => Some((BOOTCMD_B, state.is_successful(slot))),
+    }
+}
+
+/// Receives a file into `buffer` via ZMODEM at [`ZMODEM_BAUD`], returns its size
+#[cfg(feature = "zmodem")]
+pub fn load_zmodem(buffer: &mut [u8]) -> usize {
     let zmodem = zmodem::ZModem::new(crate::uart::uart_read, crate::uart::uart_write);
-    let mut buffer =
-        unsafe { core::slice::from_raw_parts_mut(dram::dram_base(), 1024 * 1024 * 32) };
-    let file_size = zmodem.recv_file(&mut buffer);
 
-    uart::printf!("Received file of size %d\r\n", file_size as u64);
+    // Without the host following, the transfer runs at the console's rate
+    let switched = ZMODEM_BAUD != CONSOLE_BAUD && unsafe { uart::switch_baud(ZMODEM_BAUD, true) }.is_some();
+
+    let file_size = zmodem.recv_file(buffer);
+
+    if switched {
+        unsafe { uart::switch_baud(CONSOLE_BAUD, false) };
+    }
+
+    file_size
+}
+
+/// Loads `path` from the SD card to `addr`, at most up to `end`
+#[cfg(feature = "fs")]
+fn read_file(path: &str, addr: u64, end: u64) -> Result<usize, fs::Error> {
+    let buffer = dram::dram_buffer(addr).ok_or(fs::Error::TooLarge)?;
+    let len = (buffer.len() as u64).min(end.saturating_sub(addr)) as usize;
+    let buffer = buffer.get_mut(..len).ok_or(fs::Error::TooLarge)?;
+
+    fs::load(smhc::sd0()?, path, buffer)
+}
+
+/// Like [`read_file`], errors are reported on the console
+#[cfg(feature = "fs")]
+pub fn load_file(path: &str, addr: u64, end: u64) -> Option<usize> {
+    match read_file(path, addr, end) {
+        Ok(size) => {
+            uart::printf!("%s: %d bytes at 0x%x\r\n", path, size, addr);
+            Some(size)
+        }
+        Err(e) => {
+            uart::printf!("%s: %s\r\n", path, e.as_str());
+            None
+        }
+    }
+}
+
+/// Parses the boot configuration text kept at [`CONF_ADDR`]
+#[cfg(feature = "fs")]
+fn boot_config() -> Option<Result<conf::Config<'static>, conf::ParseError>> {
+    let size = unsafe { CONF_SIZE };
+    let text = unsafe { core::slice::from_raw_parts(CONF_ADDR as *const u8, size) };
+
+    // Checked to be ASCII when it was loaded
+    (size > 0).then(|| conf::Config::parse(unsafe { core::str::from_utf8_unchecked(text) }))
+}
+
+/// Reads and parses the first of [`CONF_PATHS`] found on the SD card
+#[cfg(feature = "fs")]
+fn load_config() -> Option<conf::Config<'static>> {
+    for path in CONF_PATHS {
+        let size = match read_file(path, CONF_ADDR, CONF_ADDR + CONF_MAX as u64) {
+            Ok(size) => size,
+            Err(fs::Error::NotFound) => continue,
+            Err(e) => {
+                uart::printf!("%s: %s\r\n", path, e.as_str());
+                return None;
+            }
+        };
+
+        let text = unsafe { core::slice::from_raw_parts(CONF_ADDR as *const u8, size) };
+        if !text.is_ascii() {
+            uart::printf!("%s: not a text file\r\n", path);
+            return None;
+        }
+
+        unsafe { CONF_SIZE = size };
+
+        return match boot_config()? {
+            Ok(config) => {
+                uart::printf!("%s: %d entries\r\n", path, config.entries().count());
+                Some(config)
+            }
+            Err(e) => {
+                uart::printf!("%s:%d: %s\r\n", path, e.line, e.kind.as_str());
+                unsafe { CONF_SIZE = 0 };
+                None
+            }
+        };
+    }
+
+    None
+}
+
+/// Boots the boot configuration entry `label`, or the default one. Only
+/// returns if something is missing.
+#[cfg(feature = "fs")]
+pub fn sysboot(label: Option<&str>) {
+    // Checked when it was loaded
+    let Some(Ok(config)) = boot_config() else {
+        uart::printf!("no boot configuration\r\n");
+        return;
+    };
+
+    let entry = match label {
+        Some(label) => config.find(label),
+        None => config.default_entry(),
+    };
+
+    let Some(entry) = entry else {
+        uart::printf!("no boot entry '%s'\r\n", label.unwrap_or(""));
+        return;
+    };
+
+    uart::printf!("booting '%s'\r\n", entry.label);
+
+    handoff::clear_images();
+    let info = handoff::boot_info();
+
+    if load_file(entry.kernel, KERNEL_ADDR, INITRD_ADDR).is_none() {
+        return;
+    }
+
+    if let Some(fdt) = entry.fdt {
+        if load_file(fdt, FDT_ADDR, KERNEL_ADDR).is_none() {
+            return;
+        }
+        info.fdt = FDT_ADDR;
+    }
+
+    if let Some(initrd) = entry.initrd {
+        let Some(size) = load_file(initrd, INITRD_ADDR, u64::MAX) else {
+            return;
+        };
+        info.initrd_start = INITRD_ADDR;
+        info.initrd_size = size as u64;
+    }
+
+    if let Some(append) = entry.append {
+        handoff::set_cmdline(append);
+    }
 
-    unsafe { elf::execute(buffer.as_mut_ptr()) }
+    unsafe { elf::execute(KERNEL_ADDR as *const u8) }
 }
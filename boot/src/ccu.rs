@@ -3,266 +3,1520 @@ use crate::time::*;
 
 const CCU_BASE: u64 = 0x02001000;
 
-const CCU_PLL_CPU_CTRL: u64 = 0x0000;
 const CCU_PLL_PERI_CTRL: u64 = 0x0020;
-const CCU_CPU_AXI_CFG: u64 = 0x0500;
+const CCU_PLL_AUDIO1_CTRL: u64 = 0x0080;
 const CCU_PSI_CLK: u64 = 0x0510;
 const CCU_APB0_CLK: u64 = 0x0520;
-const CCU_MBUS_CLK: u64 = 0x0540;
 const CCU_DMA_BGR: u64 = 0x070c;
+const CCU_SMHC0_CLK: u64 = 0x0830;
+const CCU_SMHC1_CLK: u64 = 0x0834;
+const CCU_SMHC2_CLK: u64 = 0x0838;
+const CCU_SMHC_BGR: u64 = 0x084c;
+const CCU_SPI0_CLK: u64 = 0x0940;
+const CCU_SPI1_CLK: u64 = 0x0944;
+const CCU_SPI_BGR: u64 = 0x096c;
 const CCU_UART_BGR: u64 = 0x090C;
-const CCU_RISCV_CLK: u64 = 0x0d00;
 
-pub unsafe fn init_uart() {
+const HOSC_FREQ: u64 = 24_000_000;
+const RTC_32K_FREQ: u64 = 32_768;
+const RC16M_FREQ: u64 = 16_000_000;
+
+register! {
+    /// The CCU registers the bootloader sets field by field, the clock tree
+    /// goes through [`Desc`] instead
+    pub mod regs @ CCU_BASE {
+        PLL_CPU_CTRL @ 0x0000 {
+            PLL_EN: 31, 1,
+            PLL_LDO_EN: 30, 1,
+            LOCK_ENABLE: 29, 1,
+            LOCK: 28, 1,
+            PLL_OUTPUT_GATE: 27, 1,
+            /// N - 1
+            PLL_N: 8, 8,
+            /// M - 1
+            PLL_M: 0, 2,
+        }
+        PLL_DDR_CTRL @ 0x0010 {
+            PLL_EN: 31, 1,
+            PLL_LDO_EN: 30, 1,
+            LOCK_ENABLE: 29, 1,
+            LOCK: 28, 1,
+            PLL_OUTPUT_GATE: 27, 1,
+            /// N - 1
+            PLL_N: 8, 8,
+            /// Input divider, M1 - 1
+            PLL_INPUT_DIV2: 1, 1,
+            /// Output divider, M0 - 1
+            PLL_OUTPUT_DIV2: 0, 1,
+        }
+        CPU_AXI_CFG @ 0x0500 {
+            CPU_CLK_SEL: 24, 3 => CpuClkSel,
+            /// CPUX_APB, N - 1
+            CPU_DIV2: 8, 2,
+            /// CPUX_AXI, M - 1
+            CPU_DIV1: 0, 2,
+        }
+        APB1_CLK @ 0x0524 {
+            CLK_SRC_SEL: 24, 2 => Apb1Source,
+            /// log2 N
+            FACTOR_N: 8, 2,
+            /// M - 1
+            FACTOR_M: 0, 5,
+        }
+        MBUS_CLK @ 0x0540 {
+            /// 0 holds the MBUS domain in reset
+            MBUS_RST: 30, 1,
+        }
+        DRAM_CLK @ 0x0800 {
+            DRAM_CLK_GATING: 31, 1,
+            /// 0 holds the DRAM module clock in reset
+            DRAM_MOD_RST: 30, 1,
+            /// Set to apply new settings, self-clearing
+            SDRCLK_UPD: 27, 1,
+            DRAM_CLK_SEL: 24, 3 => DramClkSel,
+            /// log2 N
+            DRAM_DIV2: 8, 2,
+            /// M - 1
+            DRAM_DIV1: 0, 2,
+        }
+        DRAM_BGR @ 0x080c {
+            DRAM_RST: 16, 1,
+            DRAM_GATING: 0, 1,
+        }
+        RISCV_CLK @ 0x0d00 {
+            RISCV_CLK_SEL: 24, 3 => RiscvClkSel,
+            /// N - 1
+            RISCV_AXI_DIV_CFG: 8, 2,
+            /// M - 1
+            RISCV_DIV_CFG: 0, 5,
+        }
+    }
+}
+
+use regs::{APB1_CLK, CPU_AXI_CFG, DRAM_BGR, MBUS_CLK, PLL_CPU_CTRL, RISCV_CLK};
+
+bitfield! {
+    pub enum CpuClkSel {
+        Hosc = 0,
+        Rtc32k = 1,
+        Rc16m = 2,
+        PllCpu = 3,
+        PllPeri1x = 4,
+        PllPeri2x = 5,
+        PllPeri800m = 6,
+    }
+}
+
+bitfield! {
+    pub enum DramClkSel {
+        PllDdr = 0,
+        PllAudio1Div2 = 1,
+        PllPeri2x = 2,
+        PllPeri800m = 3,
+    }
+}
+
+bitfield! {
+    pub enum RiscvClkSel {
+        Hosc = 0,
+        Rtc32k = 1,
+        Rc16m = 2,
+        PllPeri800m = 3,
+        PllPeri1x = 4,
+        PllCpu = 5,
+        PllAudio1Div2 = 6,
+    }
+}
+
+bitfield! {
+    pub enum Apb1Source {
+        Hosc = 0,
+        Rtc32k = 1,
+        Psi = 2,
+        PeriPll1x = 3,
+    }
+}
+
+/// APB1 clock (UART, TWI) = source / M / N
+#[derive(Clone, Copy, Debug)]
+pub struct Apb1Config {
+    pub src: Apb1Source,
+    /// 1..=32
+    pub m: u32,
+    /// 1, 2, 4 or 8
+    pub n: u32,
+}
+
+impl Apb1Config {
+    pub fn rate(&self) -> u64 {
+        let parent = match self.src {
+            Apb1Source::Hosc => HOSC_FREQ,
+            Apb1Source::Rtc32k => RTC_32K_FREQ,
+            Apb1Source::Psi => Clock::Psi.rate(),
+            Apb1Source::PeriPll1x => Clock::PllPeri1x.rate(),
+        };
+
+        parent / self.m.max(1) as u64 / self.n.max(1) as u64
+    }
+}
+
+/// A bit field of a CCU register, shift and length. Length 0 means the
+/// clock has no such field and reads it as 0.
+#[derive(Clone, Copy, Debug)]
+struct Field(u8, u8);
+
+const NONE: Field = Field(0, 0);
+
+impl Field {
+    fn max(self) -> u32 {
+        (1u32 << self.1).wrapping_sub(1)
+    }
+
+    fn get(self, reg: u32) -> u32 {
+        (reg >> self.0) & self.max()
+    }
+
+    fn set(self, reg: u32, v: u32) -> u32 {
+        reg & !(self.max() << self.0) | (v & self.max()) << self.0
+    }
+}
+
+/// Longest mux of the modelled clocks
+const PARENTS_MAX: usize = 7;
+
+/// How a clock derives its rate from its parent:
+/// parent * (mul + 1) / (div + 1) / 2^pow2 / 2^shift, 0 while the gate is
+/// closed
+struct Desc {
+    /// Register offset from CCU_BASE
+    reg: u16,
+    /// Indexed by the mux field, a clock without a mux has one parent
+    parents: [Option<Clock>; PARENTS_MAX],
+    mux: Field,
+    /// PLL_N
+    mul: Field,
+    /// FACTOR_M, PLL_M and the PLL_PERI post dividers
+    div: Field,
+    /// FACTOR_N of the bus and module clocks
+    pow2: Field,
+    /// Fixed power of two divider
+    shift: u8,
+    /// Enable bit of a PLL or module clock
+    gate: Field,
+}
+
+const DESC_FIXED: Desc = Desc {
+    reg: 0,
+    parents: [None; PARENTS_MAX],
+    mux: NONE,
+    mul: NONE,
+    div: NONE,
+    pow2: NONE,
+    shift: 0,
+    gate: NONE,
+};
+
+const fn parents(list: &[Clock]) -> [Option<Clock>; PARENTS_MAX] {
+    let mut parents = [None; PARENTS_MAX];
+
+    let mut i = 0;
+    while i < list.len() {
+        parents[i] = Some(list[i]);
+        i += 1;
+    }
+
+    parents
+}
+
+const fn apb_desc(reg: u64) -> Desc {
+    Desc {
+        reg: reg as u16,
+        parents: parents(&[Clock::Hosc, Clock::Rtc32k, Clock::Psi, Clock::PllPeri1x]),
+        mux: Field(24, 2),
+        div: Field(0, 5),
+        pow2: Field(8, 2),
+        ..DESC_FIXED
+    }
+}
+
+/// SMHC and SPI module clocks
+const fn module_desc(reg: u64, parents: [Option<Clock>; PARENTS_MAX]) -> Desc {
+    Desc {
+        reg: reg as u16,
+        parents,
+        mux: Field(24, 3),
+        div: Field(0, 4),
+        pow2: Field(8, 2),
+        gate: Field(31, 1),
+        ..DESC_FIXED
+    }
+}
+
+/// Clocks of the CCU as far as the bootloader uses them. PLL_CPU is relocked
+/// by [`set_cpu_freq`], the PLLs can also be set through
+/// [`Ccu::set_rate`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Clock {
+    Hosc,
+    Rtc32k,
+    Rc16m,
+    PllCpu,
+    /// PLL_PERI VCO, 24 MHz * N / M
+    PllPeri,
+    PllPeri2x,
+    PllPeri1x,
+    PllPeri800m,
+    /// PLL_AUDIO1 VCO, 24 MHz * N / M, left off by the boot ROM
+    PllAudio1,
+    PllAudio1Div2,
+    PllAudio1Div5,
+    Cpux,
+    CpuxAxi,
+    CpuxApb,
+    Riscv,
+    RiscvAxi,
+    /// PSI and AHB
+    Psi,
+    Apb0,
+    Apb1,
+    Smhc0,
+    Smhc1,
+    Smhc2,
+    Spi0,
+    Spi1,
+}
+
+/// In [`Clock`] order
+const NAMES: &str = "HOSC RTC_32K RC16M PLL_CPU PLL_PERI PLL_PERI(2X) PLL_PERI(1X) \
+    PLL_PERI(800M) PLL_AUDIO1 PLL_AUDIO1(DIV2) PLL_AUDIO1(DIV5) CPUX CPUX_AXI CPUX_APB RISCV \
+    RISCV_AXI PSI/AHB APB0 APB1 SMHC0 SMHC1 SMHC2 SPI0 SPI1";
+
+/// Indexed by [`Clock`]
+static DESCS: [Desc; Clock::ALL.len()] = {
+    use Clock::*;
+
+    [
+        DESC_FIXED, // Hosc
+        DESC_FIXED, // Rtc32k
+        DESC_FIXED, // Rc16m
+        // PllCpu
+        Desc {
+            reg: PLL_CPU_CTRL::OFFSET as u16,
+            parents: parents(&[Hosc]),
+            mul: Field(8, 8),
+            div: Field(0, 2),
+            gate: Field(31, 1),
+            ..DESC_FIXED
+        },
+        // PllPeri
+        Desc {
+            reg: CCU_PLL_PERI_CTRL as u16,
+            parents: parents(&[Hosc]),
+            mul: Field(8, 8),
+            div: Field(1, 1),
+            gate: Field(31, 1),
+            ..DESC_FIXED
+        },
+        // PllPeri2x
+        Desc {
+            reg: CCU_PLL_PERI_CTRL as u16,
+            parents: parents(&[PllPeri]),
+            div: Field(16, 3), // PLL_P0
+            ..DESC_FIXED
+        },
+        // PllPeri1x
+        Desc {
+            parents: parents(&[PllPeri2x]),
+            shift: 1,
+            ..DESC_FIXED
+        },
+        // PllPeri800m
+        Desc {
+            reg: CCU_PLL_PERI_CTRL as u16,
+            parents: parents(&[PllPeri]),
+            div: Field(20, 3), // PLL_P1
+            ..DESC_FIXED
+        },
+        // PllAudio1
+        Desc {
+            reg: CCU_PLL_AUDIO1_CTRL as u16,
+            parents: parents(&[Hosc]),
+            mul: Field(8, 8),
+            div: Field(1, 1),
+            gate: Field(31, 1),
+            ..DESC_FIXED
+        },
+        // PllAudio1Div2
+        Desc {
+            reg: CCU_PLL_AUDIO1_CTRL as u16,
+            parents: parents(&[PllAudio1]),
+            div: Field(16, 3), // PLL_P0
+            ..DESC_FIXED
+        },
+        // PllAudio1Div5
+        Desc {
+            reg: CCU_PLL_AUDIO1_CTRL as u16,
+            parents: parents(&[PllAudio1]),
+            div: Field(20, 3), // PLL_P1
+            ..DESC_FIXED
+        },
+        // Cpux
+        Desc {
+            reg: CPU_AXI_CFG::OFFSET as u16,
+            parents: parents(&[Hosc, Rtc32k, Rc16m, PllCpu, PllPeri1x, PllPeri2x, PllPeri800m]),
+            mux: Field(24, 3),
+            ..DESC_FIXED
+        },
+        // CpuxAxi
+        Desc {
+            reg: CPU_AXI_CFG::OFFSET as u16,
+            parents: parents(&[Cpux]),
+            div: Field(0, 2), // CPU_DIV1
+            ..DESC_FIXED
+        },
+        // CpuxApb
+        Desc {
+            reg: CPU_AXI_CFG::OFFSET as u16,
+            parents: parents(&[Cpux]),
+            div: Field(8, 2), // CPU_DIV2
+            ..DESC_FIXED
+        },
+        // Riscv
+        Desc {
+            reg: RISCV_CLK::OFFSET as u16,
+            parents: parents(&[Hosc, Rtc32k, Rc16m, PllPeri800m, PllPeri1x, PllCpu, PllAudio1Div2]),
+            mux: Field(24, 3),
+            div: Field(0, 5),
+            ..DESC_FIXED
+        },
+        // RiscvAxi
+        Desc {
+            reg: RISCV_CLK::OFFSET as u16,
+            parents: parents(&[Riscv]),
+            div: Field(8, 2),
+            ..DESC_FIXED
+        },
+        // Psi
+        Desc {
+            reg: CCU_PSI_CLK as u16,
+            parents: parents(&[Hosc, Rtc32k, Rc16m, PllPeri1x]),
+            mux: Field(24, 2),
+            div: Field(0, 2),
+            pow2: Field(8, 2),
+            ..DESC_FIXED
+        },
+        apb_desc(CCU_APB0_CLK), // Apb0
+        apb_desc(APB1_CLK::OFFSET), // Apb1
+        module_desc(CCU_SMHC0_CLK, SMHC_PARENTS), // Smhc0
+        module_desc(CCU_SMHC1_CLK, SMHC_PARENTS), // Smhc1
+        // Smhc2
+        module_desc(
+            CCU_SMHC2_CLK,
+            parents(&[Hosc, PllPeri1x, PllPeri2x, PllPeri800m, PllAudio1Div2]),
+        ),
+        module_desc(CCU_SPI0_CLK, SPI_PARENTS), // Spi0
+        module_desc(CCU_SPI1_CLK, SPI_PARENTS), // Spi1
+    ]
+};
+
+const SMHC_PARENTS: [Option<Clock>; PARENTS_MAX] =
+    parents(&[Clock::Hosc, Clock::PllPeri1x, Clock::PllPeri2x, Clock::PllAudio1Div2]);
+
+const SPI_PARENTS: [Option<Clock>; PARENTS_MAX] = parents(&[
+    Clock::Hosc,
+    Clock::PllPeri1x,
+    Clock::PllPeri2x,
+    Clock::PllAudio1Div2,
+    Clock::PllAudio1Div5,
+]);
+
+impl Clock {
+    pub const ALL: [Clock; 24] = [
+        Clock::Hosc,
+        Clock::Rtc32k,
+        Clock::Rc16m,
+        Clock::PllCpu,
+        Clock::PllPeri,
+        Clock::PllPeri2x,
+        Clock::PllPeri1x,
+        Clock::PllPeri800m,
+        Clock::PllAudio1,
+        Clock::PllAudio1Div2,
+        Clock::PllAudio1Div5,
+        Clock::Cpux,
+        Clock::CpuxAxi,
+        Clock::CpuxApb,
+        Clock::Riscv,
+        Clock::RiscvAxi,
+        Clock::Psi,
+        Clock::Apb0,
+        Clock::Apb1,
+        Clock::Smhc0,
+        Clock::Smhc1,
+        Clock::Smhc2,
+        Clock::Spi0,
+        Clock::Spi1,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        NAMES.split_ascii_whitespace().nth(self as usize).unwrap_or("")
+    }
+
+    /// Rate of the clocks without a register
+    fn fixed_rate(self) -> Option<u64> {
+        match self {
+            Clock::Hosc => Some(HOSC_FREQ),
+            Clock::Rtc32k => Some(RTC_32K_FREQ),
+            Clock::Rc16m => Some(RC16M_FREQ),
+            _ => None,
+        }
+    }
+
+    fn desc(self) -> &'static Desc {
+        &DESCS[self as usize]
+    }
+
+    pub fn parent(self) -> Option<Clock> {
+        CCU.parent(self)
+    }
+
+    pub fn rate(self) -> u64 {
+        CCU.rate(self)
+    }
+
+    pub fn is_enabled(self) -> bool {
+        CCU.is_enabled(self)
+    }
+
+    /// See [`Ccu::set_rate`]
+    pub unsafe fn set_rate(self, hz: u64) -> Option<u64> {
+        unsafe { CCU.set_rate(self, hz) }
+    }
+
+    /// See [`Ccu::set_parent`]
+    pub unsafe fn set_parent(self, parent: Clock, hz: u64) -> Option<u64> {
+        unsafe { CCU.set_parent(self, parent, hz) }
+    }
+
+    /// See [`Ccu::set_rate_any_parent`]
+    pub unsafe fn set_rate_any_parent(self, hz: u64) -> Option<u64> {
+        unsafe { CCU.set_rate_any_parent(self, hz) }
+    }
+
+    /// See [`Ccu::set_gate`]
+    pub unsafe fn set_gate(self, open: bool) {
+        unsafe { CCU.set_gate(self, open) }
+    }
+}
+
+/// The clock tree on top of the CCU registers at `base`
+pub struct Ccu {
+    base: u64,
+}
+
+impl Ccu {
+    pub const fn new(base: u64) -> Self {
+        Self { base }
+    }
+
+    fn read(&self, reg: u64) -> u32 {
+        unsafe { read32(self.base + reg) }
+    }
+
+    fn write(&self, reg: u64, v: u32) {
+        unsafe { write32(self.base + reg, v) }
+    }
+
+    /// None for the fixed clocks and for mux values without a known clock
+    pub fn parent(&self, clock: Clock) -> Option<Clock> {
+        let d = clock.desc();
+        let sel = if d.mux.1 == 0 { 0 } else { d.mux.get(self.read(d.reg as u64)) };
+
+        d.parents.get(sel as usize).copied().flatten()
+    }
+
+    pub fn rate(&self, clock: Clock) -> u64 {
+        if let Some(hz) = clock.fixed_rate() {
+            return hz;
+        }
+
+        let d = clock.desc();
+        let reg = self.read(d.reg as u64);
+
+        let Some(parent) = self.parent(clock) else {
+            return 0;
+        };
+
+        if d.gate.1 != 0 && d.gate.get(reg) == 0 {
+            return 0;
+        }
+
+        let mul = d.mul.get(reg) as u64 + 1;
+        let div = d.div.get(reg) as u64 + 1;
+
+        (self.rate(parent) * mul / div) >> (d.pow2.get(reg) + d.shift as u32)
+    }
+
+    /// Whether the gate or PLL_EN of `clock` and of every clock between it
+    /// and its oscillator is open. False for unknown mux values.
+    pub fn is_enabled(&self, clock: Clock) -> bool {
+        if clock.fixed_rate().is_some() {
+            return true;
+        }
+
+        let d = clock.desc();
+        let open = d.gate.1 == 0 || d.gate.get(self.read(d.reg as u64)) != 0;
+
+        open && self.parent(clock).is_some_and(|parent| self.is_enabled(parent))
+    }
+
+    /// Sets the dividers of `clock` for the closest rate not above `hz` from
+    /// its current parent, or the lowest one if `hz` can't be reached. Returns
+    /// the new rate, None for clocks without a divider.
+    ///
+    /// PLLs get N and M, preferring the smaller M, and are relocked if they
+    /// run. Keeping within the PLL's range and moving whatever runs from it
+    /// elsewhere meanwhile is up to the caller. None if it doesn't lock.
+    pub unsafe fn set_rate(&self, clock: Clock, hz: u64) -> Option<u64> {
+        let parent = self.parent(clock)?;
+
+        if clock.desc().mul.1 != 0 {
+            return unsafe { self.set_pll(clock, parent, hz) };
+        }
+
+        unsafe { self.set_parent(clock, parent, hz) }
+    }
+
+    unsafe fn set_pll(&self, clock: Clock, parent: Clock, hz: u64) -> Option<u64> {
+        let d = clock.desc();
+        let (rate, n, m) = solve_pll(d, self.rate(parent), hz);
+
+        let reg = d.mul.set(d.div.set(self.read(d.reg as u64), m), n);
+        self.write(d.reg as u64, reg);
+
+        if d.gate.get(reg) != 0 {
+            self.write(d.reg as u64, reg | PLL_LOCK_ENABLE);
+            let locked = unsafe {
+                poll_until(self.base + d.reg as u64, deadline(PLL_LOCK_TIMEOUT_US), |v| v & PLL_LOCK != 0)
+            };
+            self.write(d.reg as u64, reg);
+
+            locked.ok()?;
+        }
+
+        Some(rate)
+    }
+
+    /// Like [`Ccu::set_rate`] with `parent` as the new parent. The dividers
+    /// are written first, so the new parent never runs undivided. None if
+    /// `parent` can't be selected.
+    pub unsafe fn set_parent(&self, clock: Clock, parent: Clock, hz: u64) -> Option<u64> {
+        let d = clock.desc();
+        let sel = d.parents.iter().position(|&p| p == Some(parent))?;
+
+        if d.mul.1 != 0 || d.div.1 + d.pow2.1 == 0 {
+            return None;
+        }
+
+        let (rate, m, n) = solve_dividers(d, self.rate(parent), hz);
+
+        let reg = d.pow2.set(d.div.set(self.read(d.reg as u64), m), n);
+        self.write(d.reg as u64, reg);
+        self.write(d.reg as u64, d.mux.set(reg, sel as u32));
+
+        udelay(1);
+
+        Some(rate)
+    }
+
+    /// Like [`Ccu::set_parent`] with the parent that gets closest to `hz`,
+    /// the earliest in the mux on a tie and the first one if none gets below
+    /// `hz`
+    pub unsafe fn set_rate_any_parent(&self, clock: Clock, hz: u64) -> Option<u64> {
+        let d = clock.desc();
+
+        let mut best = (0, d.parents[0]?);
+        for &parent in d.parents.iter().flatten() {
+            let (rate, ..) = solve_dividers(d, self.rate(parent), hz);
+            if rate <= hz && rate > best.0 {
+                best = (rate, parent);
+            }
+        }
+
+        unsafe { self.set_parent(clock, best.1, hz) }
+    }
+
+    /// Opens or closes the gate of a PLL or module clock
+    pub unsafe fn set_gate(&self, clock: Clock, open: bool) {
+        let d = clock.desc();
+
+        if d.gate.1 != 0 {
+            self.write(d.reg as u64, d.gate.set(self.read(d.reg as u64), open as u32));
+        }
+    }
+}
+
+pub static CCU: Ccu = Ccu::new(CCU_BASE);
+
+/// Divider fields (M, N) of `d` for the closest rate not above `hz` from
+/// `parent_rate`, or the slowest one if `hz` can't be reached. Returns
+/// (rate, M, N).
+fn solve_dividers(d: &Desc, parent_rate: u64, hz: u64) -> (u64, u32, u32) {
+    // Slowest first, anything not above hz beats it
+    let mut best = (
+        (parent_rate / (d.div.max() as u64 + 1)) >> d.pow2.max(),
+        d.div.max(),
+        d.pow2.max(),
+    );
+    for n in 0..d.pow2.max() + 1 {
+        for m in 0..d.div.max() + 1 {
+            let rate = (parent_rate / (m as u64 + 1)) >> n;
+            if rate <= hz && rate > best.0 {
+                best = (rate, m, n);
+            }
+        }
+    }
+
+    best
+}
+
+/// PLL factors (N - 1, M - 1) of `d` for the closest rate not above `hz`
+/// from `parent_rate`, preferring the smaller M, or the slowest one if `hz`
+/// can't be reached. Returns (rate, N - 1, M - 1).
+fn solve_pll(d: &Desc, parent_rate: u64, hz: u64) -> (u64, u32, u32) {
+    let mut best = (parent_rate / (d.div.max() as u64 + 1), 0, d.div.max());
+    for m in 0..d.div.max() + 1 {
+        let n = (hz * (m as u64 + 1) / parent_rate.max(1)).clamp(1, d.mul.max() as u64 + 1);
+        let rate = parent_rate * n / (m as u64 + 1);
+        if rate <= hz && rate > best.0 {
+            best = (rate, n as u32 - 1, m);
+        }
+    }
+
+    best
+}
+
+/// Blocks with a bus gating and reset (BGR) register. Enabling one takes it
+/// out of reset and passes its bus clock, module clocks are set separately
+/// (see [`Clock`]).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Peripheral {
+    De,
+    Di,
+    G2d,
+    Ce,
+    Ve,
+    Dma,
+    Msgbox0,
+    Msgbox1,
+    Msgbox2,
+    Spinlock,
+    /// High speed timer
+    Hstimer,
+    Dbgsys,
+    Pwm,
+    Dram,
+    Smhc0,
+    Smhc1,
+    Smhc2,
+    Uart0,
+    Uart1,
+    Uart2,
+    Uart3,
+    Uart4,
+    Uart5,
+    Twi0,
+    Twi1,
+    Twi2,
+    Twi3,
+    Can0,
+    Can1,
+    Spi0,
+    Spi1,
+    Emac,
+    IrTx,
+    Gpadc,
+    /// Thermal sensor
+    Ths,
+    I2s0,
+    I2s1,
+    I2s2,
+    /// S/PDIF
+    Owa,
+    Dmic,
+    AudioCodec,
+    UsbOhci0,
+    UsbOhci1,
+    UsbEhci0,
+    UsbEhci1,
+    UsbOtg,
+    Lradc,
+    DpssTop,
+    Hdmi,
+    Dsi,
+    TconLcd0,
+    TconTv,
+    /// Reset only
+    Lvds0,
+    TveTop,
+    Tve,
+    TvdTop,
+    Tvd,
+    Ledc,
+    Csi,
+    Tpadc,
+    DspCfg,
+    RiscvCfg,
+    /// Reset only, in MBUS_CLK
+    Mbus,
+}
+
+/// BGR register of a [`Peripheral`] with its gate and reset bits
+struct Bgr {
+    reg: u16,
+    /// None for the blocks that only have a reset
+    gate: Option<u8>,
+    reset: u8,
+}
+
+/// Gate at `bit` and reset 16 above, as in every BGR register
+const fn bgr(reg: u64, bit: u8) -> Bgr {
+    Bgr {
+        reg: reg as u16,
+        gate: Some(bit),
+        reset: bit + 16,
+    }
+}
+
+/// Reset at `bit` and no gate, e.g. MBUS_RST in MBUS_CLK
+const fn reset_only(reg: u64, bit: u8) -> Bgr {
+    Bgr {
+        reg: reg as u16,
+        gate: None,
+        reset: bit,
+    }
+}
+
+/// Indexed by [`Peripheral`]. The accessors below are always inlined, so for
+/// a constant peripheral the lookup folds into the register access and the
+/// table doesn't take up SRAM.
+static BGRS: [Bgr; Peripheral::Mbus as usize + 1] = [
+        bgr(0x060c, 0), // De
+        bgr(0x062c, 0), // Di
+        bgr(0x063c, 0), // G2d
+        bgr(0x068c, 0), // Ce
+        bgr(0x069c, 0), // Ve
+        bgr(CCU_DMA_BGR, 0), // Dma
+        bgr(0x071c, 0), // Msgbox0
+        bgr(0x071c, 1), // Msgbox1
+        bgr(0x071c, 2), // Msgbox2
+        bgr(0x072c, 0), // Spinlock
+        bgr(0x073c, 0), // Hstimer
+        bgr(0x078c, 0), // Dbgsys
+        bgr(0x07ac, 0), // Pwm
+        bgr(DRAM_BGR::OFFSET, 0), // Dram
+        bgr(CCU_SMHC_BGR, 0), // Smhc0
+        bgr(CCU_SMHC_BGR, 1), // Smhc1
+        bgr(CCU_SMHC_BGR, 2), // Smhc2
+        bgr(CCU_UART_BGR, 0), // Uart0
+        bgr(CCU_UART_BGR, 1), // Uart1
+        bgr(CCU_UART_BGR, 2), // Uart2
+        bgr(CCU_UART_BGR, 3), // Uart3
+        bgr(CCU_UART_BGR, 4), // Uart4
+        bgr(CCU_UART_BGR, 5), // Uart5
+        bgr(0x091c, 0), // Twi0
+        bgr(0x091c, 1), // Twi1
+        bgr(0x091c, 2), // Twi2
+        bgr(0x091c, 3), // Twi3
+        bgr(0x092c, 0), // Can0
+        bgr(0x092c, 1), // Can1
+        bgr(CCU_SPI_BGR, 0), // Spi0
+        bgr(CCU_SPI_BGR, 1), // Spi1
+        bgr(0x097c, 0), // Emac
+        bgr(0x09cc, 0), // IrTx
+        bgr(0x09ec, 0), // Gpadc
+        bgr(0x09fc, 0), // Ths
+        bgr(0x0a20, 0), // I2s0
+        bgr(0x0a20, 1), // I2s1
+        bgr(0x0a20, 2), // I2s2
+        bgr(0x0a2c, 0), // Owa
+        bgr(0x0a4c, 0), // Dmic
+        bgr(0x0a5c, 0), // AudioCodec
+        bgr(0x0a8c, 0), // UsbOhci0
+        bgr(0x0a8c, 1), // UsbOhci1
+        bgr(0x0a8c, 4), // UsbEhci0
+        bgr(0x0a8c, 5), // UsbEhci1
+        bgr(0x0a8c, 8), // UsbOtg
+        bgr(0x0a9c, 0), // Lradc
+        bgr(0x0abc, 0), // DpssTop
+        bgr(0x0b1c, 0), // Hdmi
+        bgr(0x0b4c, 0), // Dsi
+        bgr(0x0b7c, 0), // TconLcd0
+        bgr(0x0b9c, 0), // TconTv
+        reset_only(0x0bac, 16), // Lvds0
+        bgr(0x0bbc, 0), // TveTop
+        bgr(0x0bbc, 1), // Tve
+        bgr(0x0bdc, 0), // TvdTop
+        bgr(0x0bdc, 1), // Tvd
+        bgr(0x0bfc, 0), // Ledc
+        bgr(0x0c1c, 0), // Csi
+        bgr(0x0c5c, 0), // Tpadc
+        bgr(0x0c7c, 1), // DspCfg
+        bgr(0x0d0c, 0), // RiscvCfg
+        reset_only(MBUS_CLK::OFFSET, 30), // Mbus
+];
+
+impl Peripheral {
+    fn bgr(self) -> &'static Bgr {
+        &BGRS[self as usize]
+    }
+}
+
+impl Bgr {
+    fn addr(&self) -> u64 {
+        CCU_BASE + self.reg as u64
+    }
+
+    fn gate(&self) -> u32 {
+        match self.gate {
+            Some(bit) => 1 << bit,
+            None => 0,
+        }
+    }
+
+    fn reset(&self) -> u32 {
+        1 << self.reset
+    }
+}
+
+/// Takes `p` out of reset and passes its bus clock 20 us later
+#[inline(always)]
+pub unsafe fn enable(p: Peripheral) {
+    let b = p.bgr();
+
+    unsafe {
+        Reg32::read(b.addr()).or(b.reset()).write();
+        udelay(20);
+        Reg32::read(b.addr()).or(b.gate()).write();
+    }
+}
+
+/// Gates the bus clock of `p` and holds it in reset
+#[inline(always)]
+pub unsafe fn disable(p: Peripheral) {
+    let b = p.bgr();
+
+    unsafe {
+        Reg32::read(b.addr()).and(!b.gate()).write();
+        Reg32::read(b.addr()).and(!b.reset()).write();
+    }
+}
+
+/// Pulses the reset of `p`, leaving the gate alone
+#[inline(always)]
+pub unsafe fn reset(p: Peripheral) {
+    let b = p.bgr();
+
+    unsafe {
+        Reg32::read(b.addr()).and(!b.reset()).write();
+        udelay(1);
+        Reg32::read(b.addr()).or(b.reset()).write();
+    }
+}
+
+/// Out of reset and, if it has a gate, passing its bus clock
+#[inline(always)]
+pub fn is_enabled(p: Peripheral) -> bool {
+    let b = p.bgr();
+    let mask = b.gate() | b.reset();
+
+    unsafe { read32(b.addr()) & mask == mask }
+}
+
+pub fn apb1_config() -> Apb1Config {
+    let reg = unsafe { APB1_CLK::read() };
+
+    let src = match reg.get(APB1_CLK::CLK_SRC_SEL) {
+        0 => Apb1Source::Hosc,
+        1 => Apb1Source::Rtc32k,
+        2 => Apb1Source::Psi,
+        _ => Apb1Source::PeriPll1x,
+    };
+
+    Apb1Config {
+        src,
+        m: reg.get(APB1_CLK::FACTOR_M) + 1,
+        n: 1 << reg.get(APB1_CLK::FACTOR_N),
+    }
+}
+
+pub unsafe fn set_apb1_config(cfg: Apb1Config) {
     unsafe {
-        Reg32::read(CCU_BASE + CCU_UART_BGR)
-            .set_field::<0, 1>(1) // UART0 gating enable
-            .set_field::<16, 1>(1) // UART0 reset deassert
+        // Dividers first, so the new source never runs undivided
+        APB1_CLK::read()
+            .set(APB1_CLK::FACTOR_M, cfg.m - 1)
+            .set(APB1_CLK::FACTOR_N, cfg.n.trailing_zeros())
             .write();
+
+        APB1_CLK::read().set(APB1_CLK::CLK_SRC_SEL, cfg.src).write();
+
+        udelay(1);
+    }
+}
+
+/// Prints every clock in [`Clock::ALL`] as currently programmed in the CCU,
+/// one line per clock for host scripts (see scripts/clkcheck):
+///
+/// `clk name=PLL_CPU parent=HOSC enabled=1 rate=1008000000`
+///
+/// Keys keep this order and names have no spaces, `parent=-` for the fixed
+/// clocks and unknown mux values. The rate is in Hz, `enabled` is what the
+/// gates and PLL_EN bits say, see [`Ccu::is_enabled`].
+pub fn dump() {
+    for clock in Clock::ALL {
+        let parent = clock.parent().map_or("-", Clock::as_str);
+
+        crate::uart::printf!(
+            "clk name=%s parent=%s enabled=%d rate=%d\r\n",
+            clock.as_str(),
+            parent,
+            clock.is_enabled() as u32,
+            clock.rate()
+        );
     }
 }
 
+pub unsafe fn init_uart() {
+    unsafe { enable(Peripheral::Uart0) };
+}
+
 pub unsafe fn init_clocks() {
     crate::uart::printf!("initializing clocks\r\n");
 
     unsafe {
         init_cpu();
-        init_peri_pll();
         init_ahb();
         init_apb();
         init_dma();
         init_mbus();
     }
 
+    dump();
+
     crate::uart::printf!("clocks initialized\r\n");
 }
 
-unsafe fn init_cpu() {
+/// Module clock of `periph`. The UARTs have none and run from APB1.
+fn module_clock(periph: Peripheral) -> Option<Clock> {
+    use Peripheral::*;
+
+    match periph {
+        Smhc0 => Some(Clock::Smhc0),
+        Smhc1 => Some(Clock::Smhc1),
+        Smhc2 => Some(Clock::Smhc2),
+        Spi0 => Some(Clock::Spi0),
+        Spi1 => Some(Clock::Spi1),
+        Uart0 | Uart1 | Uart2 | Uart3 | Uart4 | Uart5 => Some(Clock::Apb1),
+        _ => None,
+    }
+}
+
+/// Sets the module clock of `periph` to the closest rate not above `hz`
+/// from whichever source gets there and opens its gate, the BGR side is up
+/// to [`enable`]. Returns the actual rate, None for peripherals without a
+/// module clock.
+///
+/// The UARTs share APB1 with each other and the TWIs, their baud divisors
+/// are stale after this.
+pub unsafe fn set_module_clock(periph: Peripheral, hz: u64) -> Option<u64> {
+    let clock = module_clock(periph)?;
+
     unsafe {
-        crate::uart::printf!("initializing CPU/AXI clocks\r\n");
+        let rate = clock.set_rate_any_parent(hz)?;
+        clock.set_gate(true);
+
+        Some(rate)
+    }
+}
+
+/// PLL_CPU output range, 24 MHz * 11 at least
+const PLL_CPU_MIN: u64 = 264_000_000;
+const PLL_CPU_MAX: u64 = 3_000_000_000;
+
+/// Fastest RISC-V core clock [`set_cpu_freq`] sets. The core runs from
+/// PLL_CPU undivided and nothing raises its supply, so the PLL's own range
+/// is far beyond what the core survives.
+pub const CPU_FREQ_MAX: u64 = 1_200_000_000;
+
+/// PLL_CPU factors for the closest rate not above `hz` within
+/// [`PLL_CPU_MIN`]..=[`PLL_CPU_MAX`], preferring the smallest input divider.
+/// Returns (rate, N, M), None below the range.
+pub const fn solve_cpu_pll(hz: u64) -> Option<(u64, u32, u32)> {
+    let hz = if hz > PLL_CPU_MAX { PLL_CPU_MAX } else { hz };
+
+    let mut best = None;
+    let mut best_rate = 0;
+
+    let mut m = 1;
+    while m <= 4 {
+        let n = hz * m / HOSC_FREQ;
+        let rate = HOSC_FREQ * n / m;
 
+        if n <= 256 && rate >= PLL_CPU_MIN && rate > best_rate {
+            best = Some((rate, n as u32, m as u32));
+            best_rate = rate;
+        }
+
+        m += 1;
+    }
+
+    best
+}
+
+/// How long a PLL gets to lock, far more than it takes
+const PLL_LOCK_TIMEOUT_US: u64 = 10_000;
+
+/// LOCK_ENABLE and LOCK, the same in every PLL control register
+const PLL_LOCK_ENABLE: u32 = 1 << 29;
+const PLL_LOCK: u32 = 1 << 28;
+
+/// Why [`set_cpu_freq`] failed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CpuFreqError {
+    /// Below the PLL_CPU range, nothing changed
+    BelowRange,
+    /// Above [`CPU_FREQ_MAX`], nothing changed
+    AboveRange,
+    /// PLL_CPU didn't lock, the core is left running from HOSC
+    PllLock(Timeout),
+}
+
+/// Relocks PLL_CPU for the closest rate not above `hz`, see
+/// [`solve_cpu_pll`], and runs the RISC-V core from it with its AXI clock at
+/// half the rate. The core runs from HOSC meanwhile. Returns the new rate.
+pub unsafe fn set_cpu_freq(hz: u64) -> Result<u64, CpuFreqError> {
+    if hz > CPU_FREQ_MAX {
+        return Err(CpuFreqError::AboveRange);
+    }
+
+    let (rate, n, m) = solve_cpu_pll(hz).ok_or(CpuFreqError::BelowRange)?;
+
+    unsafe {
         // Temporarily reparent RISC core clock to 24MHz HOSC while we're setting up the PLL
-        Reg32::zero(CCU_BASE + CCU_RISCV_CLK)
-            .set_field::<24, 2>(0) // RISC-V_CLK_SEL = HOSC (0)
-            .set_field::<8, 2>(3) // RISC-V_AXI_DIV_CFG (Factor N) = 3
-            .set_field::<0, 1>(1) // RISC-V_DIV_CFG (Factor M) = 1
+        RISCV_CLK::zero()
+            .set(RISCV_CLK::RISCV_CLK_SEL, RiscvClkSel::Hosc)
+            .set(RISCV_CLK::RISCV_AXI_DIV_CFG, 3)
+            .set(RISCV_CLK::RISCV_DIV_CFG, 1)
             .write();
 
         udelay(1);
 
         // Disable gating
-        Reg32::read(CCU_BASE + CCU_PLL_CPU_CTRL)
-            .set_field::<27, 1>(0) // PLL_OUTPUT_GATE = 0
-            .write();
-        Reg32::read(CCU_BASE + CCU_PLL_CPU_CTRL)
-            .set_field::<30, 1>(1) // PLL_LDO_EN = 1
-            .write();
+        PLL_CPU_CTRL::read().set(PLL_CPU_CTRL::PLL_OUTPUT_GATE, 0).write();
+        PLL_CPU_CTRL::read().set(PLL_CPU_CTRL::PLL_LDO_EN, 1).write();
 
         udelay(5);
 
-        // PLL freq = 24MHz * N / M  = 24 * 42 / 1 = 1008 Mhz ~ 1GHz
-        Reg32::read(CCU_BASE + CCU_PLL_CPU_CTRL)
-            .set_field::<8, 8>(41) // PLL_N = 41
-            .set_field::<0, 2>(0) // PLL_M = 0
+        // PLL freq = 24MHz * N / M
+        PLL_CPU_CTRL::read()
+            .set(PLL_CPU_CTRL::PLL_N, n - 1)
+            .set(PLL_CPU_CTRL::PLL_M, m - 1)
             .write();
 
         // Enable PLL lock
-        Reg32::read(CCU_BASE + CCU_PLL_CPU_CTRL)
-            .set_field::<29, 1>(1)
-            .write();
+        PLL_CPU_CTRL::read().set(PLL_CPU_CTRL::LOCK_ENABLE, 1).write();
 
         // Enable PLL
-        Reg32::read(CCU_BASE + CCU_PLL_CPU_CTRL)
-            .set_field::<31, 1>(1)
-            .write();
+        PLL_CPU_CTRL::read().set(PLL_CPU_CTRL::PLL_EN, 1).write();
 
         // Wait for PLL lock to become stable
-        Reg32::read(CCU_BASE + CCU_PLL_CPU_CTRL).wait_bit::<28>(true);
+        PLL_CPU_CTRL::read()
+            .wait(PLL_CPU_CTRL::LOCK, 1, PLL_LOCK_TIMEOUT_US)
+            .map_err(CpuFreqError::PllLock)?;
         udelay(20);
 
         // Disable gating
-        Reg32::read(CCU_BASE + CCU_PLL_CPU_CTRL)
-            .set_field::<27, 1>(1)
-            .write();
+        PLL_CPU_CTRL::read().set(PLL_CPU_CTRL::PLL_OUTPUT_GATE, 1).write();
 
         // Disable PLL lock
-        Reg32::read(CCU_BASE + CCU_PLL_CPU_CTRL)
-            .set_field::<29, 1>(0)
+        PLL_CPU_CTRL::read().set(PLL_CPU_CTRL::LOCK_ENABLE, 0).write();
+
+        udelay(1);
+
+        // - Reparent RISCV core clock to CPU PLL
+        // - RISCV core clock freq = PLL_CPU / M
+        // - RISCV AXI freq = PLL_CPU / N
+        RISCV_CLK::read()
+            .set(RISCV_CLK::RISCV_DIV_CFG, 0)
+            .set(RISCV_CLK::RISCV_AXI_DIV_CFG, 1)
+            .set(RISCV_CLK::RISCV_CLK_SEL, RiscvClkSel::PllCpu)
             .write();
 
         udelay(1);
+    }
+
+    Ok(rate)
+}
+
+unsafe fn init_cpu() {
+    unsafe {
+        // Booting on slowly beats hanging with no message
+        if let Err(CpuFreqError::PllLock(t)) = set_cpu_freq(crate::CPU_FREQ) {
+            t.print("PLL_CPU");
+            return;
+        }
 
         // - Set CPUX clock source to PLL_CPU
-        // - Set CPUX AXI clock to 504 MHz (PLL_CPU / M)
-        // - Set CPUX APB clock to 504 MHz (PLL_CPU / N)
-        Reg32::read(CCU_BASE + CCU_CPU_AXI_CFG)
-            .set_field::<0, 2>(1) // CPU_DIV1 (factor M - 1) = 1
-            .set_field::<8, 2>(1) // CPU_DIV2 (factor N - 1) = 1
-            .set_field::<24, 3>(3) // CPU_CLK_SEL = PLL_CPU  (3)
+        // - Set CPUX AXI clock to PLL_CPU / M
+        // - Set CPUX APB clock to PLL_CPU / N
+        CPU_AXI_CFG::read()
+            .set(CPU_AXI_CFG::CPU_DIV1, 1)
+            .set(CPU_AXI_CFG::CPU_DIV2, 1)
+            .set(CPU_AXI_CFG::CPU_CLK_SEL, CpuClkSel::PllCpu)
             .write();
 
         udelay(1);
+    }
+}
 
-        // - Reparent RISCV core clock to CPU PLL
-        // - RISCV core clock freq = PLL_CPU / M = 1008 MHz ~ 1 GHz
-        // - RISCV AXI freq = PLL_CPU / N = 504 MHz
-        Reg32::read(CCU_BASE + CCU_RISCV_CLK)
-            .set_field::<0, 4>(0) // RISC-V_DIV_CFG (factor M - 1) = 0
-            .set_field::<8, 2>(1) // RISC-V_AXI_DIV_CFG (factor N - 1) = 1
-            .set_field::<24, 3>(5) // RISC-V_CLK_SEL = PLL_CPU
-            .write();
+unsafe fn init_ahb() {
+    // AHB clock = PLL_PERI(1X) / M / N = 200MHz
+    unsafe { Clock::Psi.set_parent(Clock::PllPeri1x, 200_000_000) };
+}
+
+unsafe fn init_apb() {
+    // APB0 clock = PLL_PERI(1X) / M / N = 100MHz
+    unsafe { Clock::Apb0.set_parent(Clock::PllPeri1x, 100_000_000) };
+}
+
+unsafe fn init_dma() {
+    unsafe { enable(Peripheral::Dma) };
+}
+
+unsafe fn init_mbus() {
+    unsafe {
+        // Reset MBUS domain
+        enable(Peripheral::Mbus);
 
         udelay(1);
+    }
+}
 
-        let pll_clk = {
-            let reg = Reg32::read(CCU_BASE + CCU_PLL_CPU_CTRL);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
 
-            24_000_000 * (reg.field::<8, 8>() + 1) as u64 / (reg.field::<0, 2>() as u64 + 1)
-        };
+    /// Loads `regs`, offsets from CCU_BASE and values, into a clean register
+    /// file
+    fn snapshot(regs: &[(u64, u32)]) {
+        sim::reset();
 
-        let (cpux_clk, cpux_axi_clk, cpux_apb_clk) = {
-            let reg = Reg32::read(CCU_BASE + CCU_CPU_AXI_CFG);
+        for &(reg, value) in regs {
+            sim::set(CCU_BASE + reg, value as u64);
+        }
+    }
 
-            let cpux_clk = pll_clk;
+    fn check_rates(expected: &[(Clock, Option<Clock>, u64)]) {
+        for &(clock, parent, rate) in expected {
+            assert_eq!((CCU.parent(clock), CCU.rate(clock)), (parent, rate), "{clock:?}");
+        }
+    }
 
-            (
-                cpux_clk,
-                cpux_clk * (reg.field::<0, 2>() + 1) as u64,
-                cpux_clk * (reg.field::<8, 2>() + 1) as u64,
-            )
-        };
+    #[test]
+    fn init_clocks_trace() {
+        sim::assert_trace(&sim::init_clocks_trace(), include_str!("../testdata/init_clocks.trace"), "clocks", "init_clocks.trace");
+    }
 
-        let (riscv_clk, riscv_axi_clk) = {
-            let reg = Reg32::read(CCU_BASE + CCU_RISCV_CLK);
+    #[test]
+    fn bus_gates_and_resets() {
+        let cases = [
+            (Peripheral::Uart0, CCU_UART_BGR, 1 << 16 | 1),
+            (Peripheral::Smhc2, CCU_SMHC_BGR, 1 << 18 | 1 << 2),
+            (Peripheral::UsbOtg, 0x0a8c, 1 << 24 | 1 << 8),
+            (Peripheral::Lvds0, 0x0bac, 1 << 16),
+            (Peripheral::Mbus, MBUS_CLK::OFFSET, 1 << 30),
+        ];
 
-            let riscv_clk = pll_clk / (reg.field::<0, 4>() + 1) as u64;
-            let riscv_axi_clk = pll_clk / (reg.field::<8, 2>() + 1) as u64;
+        for (p, reg, bits) in cases {
+            snapshot(&[(reg, 0x0000_8000)]);
 
-            (riscv_clk, riscv_axi_clk)
-        };
+            unsafe { enable(p) };
+            assert_eq!(sim::get(CCU_BASE + reg), 0x8000 | bits as u64, "{p:?}");
+            assert!(is_enabled(p), "{p:?}");
 
-        crate::uart::printf!("CPU PLL: %dHz\r\n", pll_clk);
-        crate::uart::printf!(
-            "CPU: %dMHz, CPU AXI: %dMHz, CPU APB: %dHz\r\n",
-            cpux_clk,
-            cpux_axi_clk,
-            cpux_apb_clk
-        );
-        crate::uart::printf!(
-            "RISCV core: %dHz, RISCV AXI: %dHz\r\n",
-            riscv_clk,
-            riscv_axi_clk
-        );
+            unsafe { disable(p) };
+            assert_eq!(sim::get(CCU_BASE + reg), 0x8000, "{p:?}");
+            assert!(!is_enabled(p), "{p:?}");
+        }
     }
-}
 
-unsafe fn init_peri_pll() {
-    unsafe {
-        crate::uart::printf!("initializing PERI PLL\r\n");
+    #[test]
+    fn cpu_pll_solver() {
+        let cases = [
+            (263_999_999, None),
+            (264_000_000, Some((264_000_000, 11, 1))),
+            (720_000_000, Some((720_000_000, 30, 1))),
+            // 24 MHz steps miss it, 8 MHz ones don't
+            (1_000_000_000, Some((1_000_000_000, 125, 3))),
+            (1_008_000_000, Some((1_008_000_000, 42, 1))),
+            (1_200_000_000, Some((1_200_000_000, 50, 1))),
+            (5_000_000_000, Some((3_000_000_000, 125, 1))),
+        ];
+
+        for (hz, factors) in cases {
+            assert_eq!(solve_cpu_pll(hz), factors, "{hz} Hz");
+        }
+    }
 
-        let reg = Reg32::read(CCU_BASE + CCU_PLL_PERI_CTRL);
+    #[test]
+    fn cpu_freq_limits() {
+        for (hz, error) in [(PLL_CPU_MIN - 1, CpuFreqError::BelowRange), (CPU_FREQ_MAX + 1, CpuFreqError::AboveRange)] {
+            sim::reset();
+            sim::d1();
 
-        let n = reg.field::<8, 8>() as u64 + 1; // PLL_N
-        let m = reg.field::<1, 1>() as u64 + 1; // PLL_INPUT_DIV2
-        let p0 = reg.field::<16, 3>() as u64 + 1; // PLL_P0
-        let p1 = reg.field::<20, 3>() as u64 + 1; // PLL_P1
+            assert_eq!(unsafe { set_cpu_freq(hz) }, Err(error), "{hz} Hz");
+            assert_eq!(sim::take_log(), [], "{hz} Hz");
+        }
 
-        let hosc = 24_000_000;
-        let peri_2x_clk = hosc * n / m / p0;
-        let peri_1x_clk = hosc * n / m / p0 / 2;
-        let peri_800m_clk = hosc * n / m / p1;
+        for hz in [PLL_CPU_MIN, CPU_FREQ_MAX] {
+            sim::reset();
+            sim::d1();
 
-        crate::uart::printf!("PERI PLL (2x): %dHz\r\n", peri_2x_clk);
-        crate::uart::printf!("PERI PLL (1x): %dHz\r\n", peri_1x_clk);
-        crate::uart::printf!("PERI PLL (800m): %dHz\r\n", peri_800m_clk);
+            assert_eq!(unsafe { set_cpu_freq(hz) }, Ok(hz));
+            assert_eq!((CCU.parent(Clock::Riscv), CCU.rate(Clock::Riscv)), (Some(Clock::PllCpu), hz));
+        }
     }
-}
 
-unsafe fn init_ahb() {
-    unsafe {
-        // AHB clock = PLL_PERI(1X) / M / N = 200Mhz
+    /// Mux, FACTOR_N and FACTOR_M of `clock` as programmed
+    fn factors(clock: Clock) -> (Option<Clock>, u32, u32) {
+        let d = clock.desc();
+        let reg = CCU.read(d.reg as u64);
 
-        Reg32::zero(CCU_BASE + CCU_PSI_CLK)
-            .set_field::<0, 2>(2) // FACTOR_M = 0
-            .set_field::<8, 2>(0) // FACTOR_N = 0
-            .write();
+        (CCU.parent(clock), d.pow2.get(reg), d.div.get(reg))
+    }
 
-        Reg32::read(CCU_BASE + CCU_PSI_CLK)
-            .set_field::<24, 2>(3) // CLK_SRC_SEL = PLL_PERI(1X)
-            .write();
+    /// PLL_PERI as the boot ROM leaves it, PSI at 200 MHz and, with `audio`,
+    /// PLL_AUDIO1 at 3072 MHz
+    fn solver_snapshot(audio: bool) {
+        snapshot(&[
+            (CCU_PLL_PERI_CTRL, 0xf821_6300),
+            (CCU_PSI_CLK, 0x0300_0002),
+            (CCU_PLL_AUDIO1_CTRL, if audio { 0x8041_7f00 } else { 0 }),
+        ]);
+    }
 
-        udelay(1);
+    #[test]
+    fn module_clock_solver() {
+        use Clock::*;
+        use Peripheral as P;
 
-        let psi_ahb_clk = {
-            let reg = Reg32::read(CCU_BASE + CCU_PSI_CLK);
+        // Peripheral, requested rate, PLL_AUDIO1 on, then parent, N (log2),
+        // M - 1 and the rate
+        let cases = [
+            // SMHC: card identification and high speed
+            (P::Smhc0, 400_000, false, Hosc, 2, 14, 400_000),
+            (P::Smhc0, 50_000_000, false, PllPeri1x, 0, 11, 50_000_000),
+            (P::Smhc2, 52_000_000, false, PllPeri1x, 0, 11, 50_000_000),
+            (P::Smhc1, 200_000_000, true, PllPeri1x, 0, 2, 200_000_000),
+            // SPI
+            (P::Spi0, 24_000_000, false, Hosc, 0, 0, 24_000_000),
+            (P::Spi0, 100_000_000, false, PllPeri1x, 0, 5, 100_000_000),
+            (P::Spi1, 153_600_000, true, PllAudio1Div2, 0, 9, 153_600_000),
+            // Out of reach: the slowest setting of the first parent
+            (P::Spi0, 1_000, false, Hosc, 3, 15, 187_500),
+            // UARTs, on APB1
+            (P::Uart0, 24_000_000, false, Hosc, 0, 0, 24_000_000),
+            (P::Uart3, 100_000_000, false, Psi, 0, 1, 100_000_000),
+            (P::Uart0, 1_000, false, Rtc32k, 1, 16, 963),
+        ];
 
-            let m = reg.field::<0, 2>() as u64 + 1;
-            let n = reg.field::<8, 2>() as u64 + 1;
+        for (periph, hz, audio, parent, n, m, rate) in cases {
+            solver_snapshot(audio);
 
-            600_000_000 / m / n
-        };
+            assert_eq!(unsafe { set_module_clock(periph, hz) }, Some(rate), "{periph:?} {hz}");
+
+            let clock = module_clock(periph).unwrap();
+            assert_eq!(factors(clock), (Some(parent), n, m), "{periph:?} {hz}");
+            assert_eq!(clock.rate(), rate, "{periph:?} {hz}");
+        }
 
-        crate::uart::printf!("AHB: %dHz", psi_ahb_clk);
+        assert_eq!(unsafe { set_module_clock(P::Dma, 1_000_000) }, None);
     }
-}
 
-unsafe fn init_apb() {
-    unsafe {
-        crate::uart::printf!("initializing APB clock\r\n");
+    #[test]
+    fn bus_clock_solver() {
+        use Clock::*;
 
-        // APB0 CLK = PLL_PERI (1x) / M / N = 100Mhz
+        // Clock, parent and requested rate, then N (log2), M - 1 and the rate
+        let cases = [
+            (Psi, PllPeri1x, 200_000_000, 0, 2, 200_000_000),
+            (Psi, PllPeri1x, 120_000_000, 1, 2, 100_000_000),
+            (Psi, Hosc, 24_000_000, 0, 0, 24_000_000),
+            (Psi, PllPeri1x, 1_000_000, 3, 3, 18_750_000),
+            (Apb0, PllPeri1x, 100_000_000, 0, 5, 100_000_000),
+            (Apb0, Psi, 100_000_000, 0, 1, 100_000_000),
+            (Apb1, PllPeri1x, 4_000_000, 3, 18, 3_947_368),
+        ];
 
-        Reg32::zero(CCU_BASE + CCU_APB0_CLK)
-            .set_field::<0, 5>(2) // FACTOR_M = 2
-            .set_field::<8, 2>(1) // FACTOR_N = 1
-            .write();
+        for (clock, parent, hz, n, m, rate) in cases {
+            solver_snapshot(false);
 
-        Reg32::read(CCU_BASE + CCU_APB0_CLK)
-            .set_field::<24, 2>(3) // CLK_SRC_SEL = PLL_PERI(1X)
-            .write();
+            assert_eq!(unsafe { clock.set_parent(parent, hz) }, Some(rate), "{clock:?} {hz}");
+            assert_eq!(factors(clock), (Some(parent), n, m), "{clock:?} {hz}");
+        }
 
-        udelay(1);
+        // The parent stays and ties go to the smaller N. Parents out of the
+        // mux and PLLs are refused.
+        solver_snapshot(false);
+        assert_eq!(unsafe { Apb0.set_rate(12_000_000) }, Some(12_000_000));
+        assert_eq!(factors(Apb0), (Some(Hosc), 0, 1));
+        assert_eq!(unsafe { Psi.set_parent(PllCpu, 1) }, None);
+        assert_eq!(unsafe { PllPeri.set_parent(Hosc, 1) }, None);
+    }
 
-        let apb0_clk = {
-            let reg = Reg32::read(CCU_BASE + CCU_APB0_CLK);
+    #[test]
+    fn pll_solver() {
+        use Clock::*;
 
-            let m = reg.field::<0, 5>() as u64 + 1;
-            let n = reg.field::<8, 2>() as u64 + 1;
+        // Requested rate, then the PLL's control register and its rate
+        let cases = [
+            (PllCpu, 1_008_000_000, 0x0000_2900, 1_008_000_000),
+            // 24 MHz * 125 / 3
+            (PllCpu, 1_000_000_000, 0x0000_7c02, 1_000_000_000),
+            (PllCpu, 3_000_000_000, 0x0000_7c00, 3_000_000_000),
+            // Factors only while it's off
+            (PllAudio1, 2_457_600_000, 0x0041_6500, 2_448_000_000),
+            // Relocked, LOCK_ENABLE left as it was
+            (PllPeri, 1_200_000_000, 0xf821_3100, 1_200_000_000),
+            // N = 1 with the larger M
+            (PllPeri, 1, 0xf821_0002, 12_000_000),
+        ];
 
-            200_000_000 / m / n
-        };
+        for (clock, hz, reg, rate) in cases {
+            solver_snapshot(false);
+            sim::set(CCU_BASE + CCU_PLL_AUDIO1_CTRL, 0x0041_7f00);
+            sim::d1();
+
+            assert_eq!(unsafe { clock.set_rate(hz) }, Some(rate), "{clock:?} {hz}");
+            assert_eq!(CCU.read(clock.desc().reg as u64), reg, "{clock:?} {hz}");
+            assert_eq!(clock.rate(), if clock.is_enabled() { rate } else { 0 }, "{clock:?} {hz}");
+        }
 
-        crate::uart::printf!("AHB: %dHz", apb0_clk);
+        // PLL_PERI(1X) follows
+        assert_eq!(PllPeri1x.rate(), 3_000_000);
+
+        // Never locks: the factors stay, LOCK_ENABLE goes back
+        snapshot(&[(CCU_PLL_PERI_CTRL, 0xe821_6300)]);
+        assert_eq!(unsafe { PllPeri.set_rate(1_200_000_000) }, None);
+        assert_eq!(CCU.read(CCU_PLL_PERI_CTRL), 0xe821_3100);
     }
-}
 
-unsafe fn init_dma() {
-    unsafe {
-        crate::uart::printf!("initializing DMA clock\r\n");
+    #[test]
+    fn dump_gates() {
+        snapshot(&[
+            (CCU_PLL_PERI_CTRL, 0xf821_6300),
+            // PLL_EN clear with N = 128
+            (CCU_PLL_AUDIO1_CTRL, 0x0041_7f00),
+            // Gate open on PLL_AUDIO1(DIV2)
+            (CCU_SMHC0_CLK, 0x8300_0000),
+            // Gate open on PLL_PERI(1X) / 6
+            (CCU_SPI0_CLK, 0x8100_0005),
+            // Gate closed
+            (CCU_SPI1_CLK, 0x0100_0005),
+        ]);
+        sim::take_console();
 
-        // DMA reset
-        Reg32::read(CCU_BASE + CCU_DMA_BGR)
-            .set_field::<16, 1>(1) // DMA_RST = 1 (de-assert)
-            .write();
+        dump();
+        let console = sim::take_console();
 
-        udelay(20);
+        for line in [
+            "clk name=PLL_PERI(1X) parent=PLL_PERI(2X) enabled=1 rate=600000000\r\n",
+            "clk name=PLL_AUDIO1 parent=HOSC enabled=0 rate=0\r\n",
+            "clk name=PLL_AUDIO1(DIV2) parent=PLL_AUDIO1 enabled=0 rate=0\r\n",
+            "clk name=SMHC0 parent=PLL_AUDIO1(DIV2) enabled=0 rate=0\r\n",
+            "clk name=SPI0 parent=PLL_PERI(1X) enabled=1 rate=100000000\r\n",
+            "clk name=SPI1 parent=PLL_PERI(1X) enabled=0 rate=0\r\n",
+        ] {
+            assert!(console.contains(line), "{line:?} not in\n{console}");
+        }
+    }
 
-        // DMA Gating clock pass
-        Reg32::read(CCU_BASE + CCU_DMA_BGR)
-            .set_field::<0, 1>(1) // DMA_GATING = 1 (Pass)
-            .write();
+    /// The CCU as the boot ROM leaves it: everything on HOSC but PLL_PERI
+    #[test]
+    fn rates_at_reset() {
+        use Clock::*;
+
+        snapshot(&[(CCU_PLL_PERI_CTRL, 0xf821_6300)]);
+        check_rates(&[
+            (Hosc, None, 24_000_000),
+            (Rtc32k, None, 32_768),
+            (PllCpu, Some(Hosc), 0),
+            (PllPeri, Some(Hosc), 2_400_000_000),
+            (PllPeri2x, Some(PllPeri), 1_200_000_000),
+            (PllPeri1x, Some(PllPeri2x), 600_000_000),
+            (PllPeri800m, Some(PllPeri), 800_000_000),
+            (PllAudio1, Some(Hosc), 0),
+            (PllAudio1Div2, Some(PllAudio1), 0),
+            (Cpux, Some(Hosc), 24_000_000),
+            (CpuxApb, Some(Cpux), 24_000_000),
+            (Riscv, Some(Hosc), 24_000_000),
+            (RiscvAxi, Some(Riscv), 24_000_000),
+            (Psi, Some(Hosc), 24_000_000),
+            (Apb1, Some(Hosc), 24_000_000),
+            (Smhc0, Some(Hosc), 0),
+            (Spi0, Some(Hosc), 0),
+        ]);
     }
-}
 
-unsafe fn init_mbus() {
-    unsafe {
-        crate::uart::printf!("initializing MBUS clock\r\n");
+    /// After init_clocks, with an SD card and SPI flash clocked and
+    /// PLL_AUDIO1 running
+    #[test]
+    fn rates_after_init() {
+        use Clock::*;
 
-        // Reset MBUS domain
-        Reg32::read(CCU_BASE + CCU_MBUS_CLK)
-            .set_field::<30, 1>(1) // MBUS_RST = 1 (de-assert)
-            .write();
+        snapshot(&[
+            (PLL_CPU_CTRL::OFFSET, 0xc800_2900),
+            (CCU_PLL_PERI_CTRL, 0xf821_6300),
+            // N = 128, P0 = 2, P1 = 5
+            (CCU_PLL_AUDIO1_CTRL, 0x8041_7f00),
+            (CPU_AXI_CFG::OFFSET, 0x0300_0101),
+            (RISCV_CLK::OFFSET, 0x0500_0100),
+            (CCU_PSI_CLK, 0x0300_0002),
+            (CCU_APB0_CLK, 0x0300_0102),
+            // PLL_PERI(1X) / 2 / 2
+            (APB1_CLK::OFFSET, 0x0300_0101),
+            // PLL_PERI(1X) / 2 / 6
+            (CCU_SMHC0_CLK, 0x8100_0105),
+            // PLL_PERI(2X) / 8 / 16, gate closed
+            (CCU_SMHC1_CLK, 0x0200_030f),
+            // PLL_AUDIO1(DIV2) / 1 / 4
+            (CCU_SMHC2_CLK, 0x8400_0003),
+            // PLL_AUDIO1(DIV5) / 4 / 1
+            (CCU_SPI0_CLK, 0x8400_0200),
+            // No such source
+            (CCU_SPI1_CLK, 0x8700_0000),
+        ]);
+        check_rates(&[
+            (PllCpu, Some(Hosc), 1_008_000_000),
+            (PllAudio1, Some(Hosc), 3_072_000_000),
+            (PllAudio1Div2, Some(PllAudio1), 1_536_000_000),
+            (PllAudio1Div5, Some(PllAudio1), 614_400_000),
+            (Cpux, Some(PllCpu), 1_008_000_000),
+            (CpuxAxi, Some(Cpux), 504_000_000),
+            (CpuxApb, Some(Cpux), 504_000_000),
+            (Riscv, Some(PllCpu), 1_008_000_000),
+            (RiscvAxi, Some(Riscv), 504_000_000),
+            (Psi, Some(PllPeri1x), 200_000_000),
+            (Apb0, Some(PllPeri1x), 100_000_000),
+            (Apb1, Some(PllPeri1x), 150_000_000),
+            (Smhc0, Some(PllPeri1x), 50_000_000),
+            (Smhc1, Some(PllPeri2x), 0),
+            (Smhc2, Some(PllAudio1Div2), 384_000_000),
+            (Spi0, Some(PllAudio1Div5), 153_600_000),
+            (Spi1, None, 0),
+        ]);
 
-        udelay(1);
+        assert_eq!(apb1_config().rate(), 150_000_000);
     }
 }
@@ -0,0 +1,377 @@
+//! Boot configuration in the extlinux.conf format
+//!
+//! ```text
+//! default linux
+//! timeout 30
+//!
+//! label linux
+//!     kernel /boot/kernel.elf
+//!     fdt /boot/board.dtb
+//!     initrd /boot/initrd.img
+//!     append console=ttyS0,115200
+//! ```
+//!
+//! Keywords are case-insensitive, `linux` and `devicetree` are accepted as
+//! aliases of `kernel` and `fdt`. As in extlinux, `timeout` is in tenths of a
+//! second. Keywords this loader has no use for (`menu`, `prompt`, ...) are
+//! skipped, so an existing extlinux.conf can be used as is.
+//!
+//! Nothing is copied out of the text: [`Config::parse`] checks the whole file
+//! once and entries are found again by walking the text, which keeps the stack
+//! small and the number of entries unlimited.
+
+use crate::monitor::{parse_decimal, split_once};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Entry<'a> {
+    pub label: &'a str,
+    pub kernel: &'a str,
+    pub fdt: Option<&'a str>,
+    pub initrd: Option<&'a str>,
+    pub append: Option<&'a str>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Config<'a> {
+    text: &'a str,
+    pub default: Option<&'a str>,
+    /// In tenths of a second
+    pub timeout: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// Keyword without its argument
+    MissingValue,
+    /// Entry keyword before the first `label`
+    OutsideEntry,
+    InvalidNumber,
+    /// `label` without a `kernel` line
+    MissingKernel,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ParseError {
+    /// 1-based line number
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+impl ErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::MissingValue => "missing value",
+            ErrorKind::OutsideEntry => "keyword outside of a label",
+            ErrorKind::InvalidNumber => "invalid number",
+            ErrorKind::MissingKernel => "label without kernel",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Keyword {
+    Default,
+    Timeout,
+    Label,
+    Kernel,
+    Fdt,
+    Initrd,
+    Append,
+}
+
+const KEYWORDS: [(&str, Keyword); 9] = [
+    ("default", Keyword::Default),
+    ("timeout", Keyword::Timeout),
+    ("label", Keyword::Label),
+    ("kernel", Keyword::Kernel),
+    ("linux", Keyword::Kernel),
+    ("fdt", Keyword::Fdt),
+    ("devicetree", Keyword::Fdt),
+    ("initrd", Keyword::Initrd),
+    ("append", Keyword::Append),
+];
+
+/// Known keywords of `text` with their value and 1-based line number
+struct Lines<'a> {
+    rest: Option<&'a str>,
+    number: usize,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = (usize, Keyword, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let text = self.rest?;
+            let (line, rest) = match split_once(text, b'\n') {
+                Some((line, rest)) => (line, Some(rest)),
+                None => (text, None),
+            };
+
+            self.rest = rest;
+            self.number += 1;
+
+            let line = line.trim_ascii();
+            let (name, value) = match line.bytes().position(|c| c == b' ' || c == b'\t') {
+                Some(i) => line.split_at_checked(i).unwrap_or((line, "")),
+                None => (line, ""),
+            };
+
+            // Comments and blank lines don't match any keyword either
+            if let Some(&(_, keyword)) = KEYWORDS.iter().find(|(k, _)| k.eq_ignore_ascii_case(name))
+            {
+                return Some((self.number, keyword, value.trim_ascii()));
+            }
+        }
+    }
+}
+
+fn lines(text: &str) -> Lines<'_> {
+    Lines {
+        rest: Some(text),
+        number: 0,
+    }
+}
+
+impl<'a> Config<'a> {
+    pub fn parse(text: &'a str) -> Result<Self, ParseError> {
+        let mut config = Config {
+            text,
+            default: None,
+            timeout: None,
+        };
+
+        // Line of the current label and whether it has a kernel yet
+        let mut label: Option<(usize, bool)> = None;
+
+        for (line, keyword, value) in lines(text) {
+            let error = |kind| ParseError { line, kind };
+
+            if value.is_empty() {
+                return Err(error(ErrorKind::MissingValue));
+            }
+
+            match keyword {
+                Keyword::Default => config.default = Some(value),
+                Keyword::Timeout => {
+                    let timeout = parse_decimal(value).and_then(|v| u32::try_from(v).ok());
+                    config.timeout = Some(timeout.ok_or(error(ErrorKind::InvalidNumber))?);
+                }
+                Keyword::Label => {
+                    check_kernel(label)?;
+                    label = Some((line, false));
+                }
+                _ => match &mut label {
+                    Some((_, has_kernel)) => *has_kernel |= matches!(keyword, Keyword::Kernel),
+                    None => return Err(error(ErrorKind::OutsideEntry)),
+                },
+            }
+        }
+
+        check_kernel(label)?;
+
+        Ok(config)
+    }
+
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            lines: lines(self.text),
+            label: None,
+        }
+    }
+
+    pub fn find(&self, label: &str) -> Option<Entry<'a>> {
+        self.entries().find(|e| e.label == label)
+    }
+
+    /// The entry named by `default`, or the first one
+    pub fn default_entry(&self) -> Option<Entry<'a>> {
+        self.default
+            .and_then(|label| self.find(label))
+            .or_else(|| self.entries().next())
+    }
+}
+
+/// Checks the last entry once its lines are over
+fn check_kernel(label: Option<(usize, bool)>) -> Result<(), ParseError> {
+    match label {
+        Some((line, false)) => Err(ParseError {
+            line,
+            kind: ErrorKind::MissingKernel,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Entries of a [`Config`] in file order
+pub struct Entries<'a> {
+    lines: Lines<'a>,
+    /// Label line already consumed by the previous entry
+    label: Option<&'a str>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        let label = match self.label.take() {
+            Some(label) => label,
+            None => loop {
+                if let (_, Keyword::Label, label) = self.lines.next()? {
+                    break label;
+                }
+            },
+        };
+
+        let mut entry = Entry {
+            label,
+            ..Entry::default()
+        };
+
+        for (_, keyword, value) in self.lines.by_ref() {
+            match keyword {
+                Keyword::Label => {
+                    self.label = Some(value);
+                    break;
+                }
+                Keyword::Kernel => entry.kernel = value,
+                Keyword::Fdt => entry.fdt = Some(value),
+                Keyword::Initrd => entry.initrd = Some(value),
+                Keyword::Append => entry.append = Some(value),
+                Keyword::Default | Keyword::Timeout => {}
+            }
+        }
+
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const EXTLINUX: &str = "\
+# Generated by the OS5 image builder
+MENU TITLE OS5
+default rescue
+timeout 30
+prompt 0
+
+label linux
+    menu label Linux
+    kernel /boot/kernel.elf
+    fdt /boot/board.dtb
+    initrd /boot/initrd.img
+    append console=ttyS0,115200  root=/dev/mmcblk0p2
+
+LABEL rescue\r
+\tLINUX\t/boot/rescue.elf\r
+\tDeviceTree /boot/rescue.dtb\r
+";
+
+    fn entry<'a>(label: &'a str, kernel: &'a str) -> Entry<'a> {
+        Entry {
+            label,
+            kernel,
+            ..Entry::default()
+        }
+    }
+
+    #[test]
+    fn labels() {
+        let config = Config::parse(EXTLINUX).unwrap();
+        let linux = Entry {
+            fdt: Some("/boot/board.dtb"),
+            initrd: Some("/boot/initrd.img"),
+            append: Some("console=ttyS0,115200  root=/dev/mmcblk0p2"),
+            ..entry("linux", "/boot/kernel.elf")
+        };
+        let rescue = Entry {
+            fdt: Some("/boot/rescue.dtb"),
+            ..entry("rescue", "/boot/rescue.elf")
+        };
+
+        assert_eq!(config.entries().collect::<Vec<_>>(), [linux, rescue]);
+        assert_eq!((config.default, config.timeout), (Some("rescue"), Some(30)));
+        assert_eq!(config.find("linux"), Some(linux));
+        assert_eq!(config.find("Linux"), None);
+        assert_eq!(config.default_entry(), Some(rescue));
+    }
+
+    #[test]
+    fn default_entry() {
+        let cases = [
+            ("label a\nkernel /a\nlabel b\nkernel /b\n", None, Some("a")),
+            ("default b\nlabel a\nkernel /a\nlabel b\nkernel /b\n", Some("b"), Some("b")),
+            // Unknown labels fall back to the first entry
+            ("default c\nlabel a\nkernel /a\nlabel b\nkernel /b\n", Some("c"), Some("a")),
+            // The last default wins, wherever it is
+            ("default a\nlabel a\nkernel /a\ndefault b\nlabel b\nkernel /b", Some("b"), Some("b")),
+            ("default a\ntimeout 10\n", Some("a"), None),
+            ("", None, None),
+        ];
+
+        for (text, default, label) in cases {
+            let config = Config::parse(text).unwrap();
+            assert_eq!(config.default, default, "{text:?}");
+            assert_eq!(config.default_entry().map(|e| e.label), label, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let cases = [
+            ("timeout 0", Ok(Some(0))),
+            ("TIMEOUT 50", Ok(Some(50))),
+            ("timeout 4294967295", Ok(Some(u32::MAX))),
+            ("prompt 1", Ok(None)),
+            ("timeout 4294967296", Err(ErrorKind::InvalidNumber)),
+            ("timeout 18446744073709551616", Err(ErrorKind::InvalidNumber)),
+            ("timeout -1", Err(ErrorKind::InvalidNumber)),
+            ("timeout 0x10", Err(ErrorKind::InvalidNumber)),
+            ("timeout 3 s", Err(ErrorKind::InvalidNumber)),
+        ];
+
+        for (text, expected) in cases {
+            let timeout = Config::parse(text).map(|c| c.timeout).map_err(|e| e.kind);
+            assert_eq!(timeout, expected, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn append_and_fdt() {
+        let config = Config::parse(
+            "label a\nkernel /a\nappend  quiet  loglevel=3 \nfdt /a.dtb\nfdt /b.dtb\n\
+             label b\nlinux /b\ndevicetree /b.dtb\nappend x\nappend y\n",
+        )
+        .unwrap();
+        let entries: Vec<_> = config.entries().map(|e| (e.fdt, e.append)).collect();
+
+        // Later lines replace earlier ones, the value's spaces are kept
+        // between its words
+        assert_eq!(entries, [(Some("/b.dtb"), Some("quiet  loglevel=3")), (Some("/b.dtb"), Some("y"))]);
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let cases = [
+            ("kernel /a\n", 1, ErrorKind::OutsideEntry),
+            ("# comment\n\nappend quiet\nlabel a\nkernel /a\n", 3, ErrorKind::OutsideEntry),
+            ("label a\nkernel /a\nlabel b\nlabel c\nkernel /c\n", 3, ErrorKind::MissingKernel),
+            ("label a\nkernel /a\nlabel b\nappend quiet", 3, ErrorKind::MissingKernel),
+            ("label a\nkernel\n", 2, ErrorKind::MissingValue),
+            ("label a\nkernel /a\nfdt \t\n", 3, ErrorKind::MissingValue),
+            ("label\nkernel /a\n", 1, ErrorKind::MissingValue),
+            ("default\n", 1, ErrorKind::MissingValue),
+            ("timeout 1\ntimeout 1.5\n", 2, ErrorKind::InvalidNumber),
+        ];
+
+        for (text, line, kind) in cases {
+            assert_eq!(Config::parse(text), Err(ParseError { line, kind }), "{text:?}");
+        }
+
+        // Unknown keywords are skipped even without a value
+        assert!(Config::parse("menu\nlabel a\n  kernel /a\n  kernelx\n  ipappend 2\n").is_ok());
+    }
+}
@@ -0,0 +1,309 @@
+//! Boot diagnostic record
+//!
+//! Four RTC general purpose registers keep what the last boot got to across
+//! resets: the stage it reached, the fatal error that ended it and, for
+//! traps, mcause and mepc. A record that doesn't check out, as after a power
+//! cycle, counts as no record.
+//!
+//! Layout, keep in sync with kernel/src/diag.rs:
+//!
+//! | word | contents                                          |
+//! |------|---------------------------------------------------|
+//! | 0    | magic in 31:16, CRC-16/CCITT of words 1-3 in 15:0 |
+//! | 1    | boots since power-on in 31:16, stage in 15:8, error in 7:0 |
+//! | 2    | mcause, the interrupt bit moved to bit 31         |
+//! | 3    | mepc, low 32 bits                                 |
+
+use crate::rtc;
+
+/// First of the four registers, right below the A/B slot state
+const FIRST_REG: usize = 3;
+
+const MAGIC: u32 = 0xd1a6;
+
+/// Printed and handed over as numbers
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stage {
+    /// Clock and DRAM init
+    Init = 1,
+    Monitor = 2,
+    /// Running the boot command
+    Boot = 3,
+    /// Jumped to the kernel
+    Kernel = 4,
+}
+
+/// Printed and handed over as numbers
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    None = 0,
+    DramInit = 1,
+    /// `boot_panic!`
+    BootPanic = 2,
+    Panic = 3,
+    Trap = 4,
+    KernelPanic = 5,
+    KernelTrap = 6,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Record {
+    /// Including the one that wrote the record
+    pub boots: u16,
+    pub stage: u8,
+    pub error: u8,
+    pub mcause: u32,
+    pub mepc: u32,
+}
+
+/// CRC-16/CCITT-FALSE of the words in little endian byte order
+fn crc16(words: &[u32; 3]) -> u16 {
+    let mut crc = 0xffffu16;
+
+    for w in words {
+        for i in 0..4 {
+            crc ^= ((w >> (i * 8)) as u16 & 0xff) << 8;
+
+            for _ in 0..8 {
+                crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+            }
+        }
+    }
+
+    crc
+}
+
+impl Record {
+    pub fn encode(&self) -> [u32; 4] {
+        let w1 = (self.boots as u32) << 16 | (self.stage as u32) << 8 | self.error as u32;
+        let body = [w1, self.mcause, self.mepc];
+
+        [MAGIC << 16 | crc16(&body) as u32, w1, self.mcause, self.mepc]
+    }
+
+    pub fn decode(words: [u32; 4]) -> Option<Record> {
+        let [w0, w1, mcause, mepc] = words;
+
+        if w0 >> 16 != MAGIC || w0 & 0xffff != crc16(&[w1, mcause, mepc]) as u32 {
+            return None;
+        }
+
+        Some(Record {
+            boots: (w1 >> 16) as u16,
+            stage: (w1 >> 8) as u8,
+            error: w1 as u8,
+            mcause,
+            mepc,
+        })
+    }
+
+    /// mcause with the interrupt bit back in bit 63
+    pub fn mcause64(&self) -> u64 {
+        (self.mcause as u64 & 0x7fff_ffff) | (self.mcause as u64 >> 31) << 63
+    }
+}
+
+struct State {
+    /// Record of the previous boot, taken by [`init`]
+    last: Option<Record>,
+    /// This boot's record, written back on every change
+    current: Record,
+}
+
+const STATE_INIT: State = State {
+    last: None,
+    current: Record {
+        boots: 1,
+        stage: 0,
+        error: 0,
+        mcause: 0,
+        mepc: 0,
+    },
+};
+
+#[cfg(not(any(test, feature = "sim")))]
+fn state() -> &'static mut State {
+    static mut STATE: State = STATE_INIT;
+
+    unsafe { &mut *core::ptr::addr_of_mut!(STATE) }
+}
+
+/// The host tests run in parallel, each thread gets a state of its own
+#[cfg(any(test, feature = "sim"))]
+fn state() -> &'static mut State {
+    std::thread_local! {
+        static STATE: *mut State = std::boxed::Box::leak(std::boxed::Box::new(STATE_INIT));
+    }
+
+    unsafe { &mut *STATE.with(|state| *state) }
+}
+
+fn load() -> Option<Record> {
+    let mut words = [0; 4];
+
+    for (i, w) in words.iter_mut().enumerate() {
+        *w = rtc::gp_read(FIRST_REG + i);
+    }
+
+    Record::decode(words)
+}
+
+fn store(record: &Record) {
+    for (i, w) in record.encode().into_iter().enumerate() {
+        rtc::gp_write(FIRST_REG + i, w);
+    }
+}
+
+fn update(f: impl FnOnce(&mut Record)) {
+    let record = &mut state().current;
+
+    f(record);
+    store(record);
+}
+
+/// Keeps the previous boot's record for [`last`] and starts this one
+pub fn init() {
+    let last = load();
+    state().last = last;
+
+    update(|r| {
+        r.boots = last.map_or(1, |l| l.boots.saturating_add(1));
+        r.stage = Stage::Init as u8;
+    });
+}
+
+pub fn last() -> Option<Record> {
+    state().last
+}
+
+pub fn set_stage(stage: Stage) {
+    update(|r| r.stage = stage as u8);
+}
+
+/// Records the fatal error ending this boot, mcause and mepc are for traps
+pub fn set_error(error: Error, mcause: u64, mepc: u64) {
+    update(|r| {
+        r.error = error as u8;
+        r.mcause = (mcause & 0x7fff_ffff) as u32 | ((mcause >> 63) as u32) << 31;
+        r.mepc = mepc as u32;
+    });
+}
+
+/// Address of the record, for the kernel
+pub fn addr() -> u64 {
+    rtc::gp_addr(FIRST_REG)
+}
+
+pub fn print_last() {
+    let Some(last) = last() else {
+        crate::uart::printf!("last boot: none since power-on\r\n");
+        return;
+    };
+
+    crate::uart::printf!(
+        "last boot: #%d, stage %d, error %d, mcause 0x%x, mepc 0x%x\r\n",
+        last.boots,
+        last.stage,
+        last.error,
+        last.mcause64(),
+        last.mepc
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+
+    fn stored() -> [u32; 4] {
+        core::array::from_fn(|i| sim::get(addr() + i as u64 * 4) as u32)
+    }
+
+    /// A kernel trap on the second boot, as the kernel writes it over the
+    /// bootloader's record. Pins the layout both sides rely on.
+    #[test]
+    fn kernel_layout() {
+        let words = [0xd1a6_60fb, 0x0002_0406, 0x8000_0007, 0x4020_1234];
+        let record = Record {
+            boots: 2,
+            stage: Stage::Kernel as u8,
+            error: Error::KernelTrap as u8,
+            mcause: 0x8000_0007,
+            mepc: 0x4020_1234,
+        };
+
+        assert_eq!(record.encode(), words);
+        assert_eq!(Record::decode(words), Some(record));
+        assert_eq!(addr(), 0x0709_010c);
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let records = [
+            Record { boots: 1, stage: 1, error: 0, mcause: 0, mepc: 0 },
+            Record { boots: 0xffff, stage: 0xff, error: 0xff, mcause: u32::MAX, mepc: u32::MAX },
+            Record { boots: 7, stage: 3, error: 4, mcause: 2, mepc: 0x4000_8a10 },
+        ];
+
+        for record in records {
+            let words = record.encode();
+            assert_eq!(Record::decode(words), Some(record), "{words:08x?}");
+
+            // Any other magic or CRC, or a changed body
+            for (word, bit) in [(0, 31), (0, 16), (0, 0), (0, 15), (1, 24), (2, 31), (3, 1)] {
+                let mut bad = words;
+                bad[word] ^= 1 << bit;
+                assert_eq!(Record::decode(bad), None, "{words:08x?} word {word} bit {bit}");
+            }
+        }
+
+        // As after a power cycle
+        assert_eq!(Record::decode([0; 4]), None);
+    }
+
+    #[test]
+    fn mcause() {
+        sim::reset();
+
+        // Machine external interrupt, then an illegal instruction
+        set_error(Error::Trap, 1 << 63 | 11, 0x1_4020_0000);
+        let record = load().unwrap();
+        assert_eq!((record.mcause, record.mcause64(), record.mepc), (0x8000_000b, 1 << 63 | 11, 0x4020_0000));
+
+        set_error(Error::Trap, 2, 0x4020_0004);
+        assert_eq!(load().unwrap().mcause64(), 2);
+    }
+
+    #[test]
+    fn boots_since_power_on() {
+        sim::reset();
+
+        // Nothing in the RTC registers
+        init();
+        assert_eq!(last(), None);
+        assert_eq!(load().map(|r| (r.boots, r.stage)), Some((1, Stage::Init as u8)));
+
+        // Each reset finds the previous record
+        set_stage(Stage::Monitor);
+        for boots in 2..5 {
+            init();
+            assert_eq!(last().map(|r| r.boots), Some(boots - 1));
+            assert_eq!(load().map(|r| (r.boots, r.stage)), Some((boots, Stage::Init as u8)));
+        }
+        assert_eq!(last().map(|r| r.stage), Some(Stage::Init as u8));
+
+        // Damaged: counting starts over
+        sim::set(addr() + 4, sim::get(addr() + 4) ^ 0x100);
+        init();
+        assert_eq!(last(), None);
+        assert_eq!(load().map(|r| r.boots), Some(1));
+
+        // The count stops at the top
+        let words = Record { boots: 0xffff, stage: 2, error: 0, mcause: 0, mepc: 0 }.encode();
+        for (i, w) in words.into_iter().enumerate() {
+            sim::set(addr() + i as u64 * 4, w as u64);
+        }
+        init();
+        assert_eq!(stored()[1] >> 16, 0xffff);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::ccu::DramClkSel;
+use crate::ccu::regs::{DRAM_BGR, DRAM_CLK, MBUS_CLK, PLL_CPU_CTRL, PLL_DDR_CTRL};
 use crate::{mmio, time::udelay, uart};
 
 const CFG_SYS_SDRAM_BASE: u64 = 0x4000_0000;
@@ -8,7 +10,6 @@ const SUNXI_DRAM_TYPE_LPDDR2: u32 = 6;
 const SUNXI_DRAM_TYPE_LPDDR3: u32 = 7;
 
 const SUNXI_SID_BASE: u64 = 0x3006200;
-const SUNXI_CCM_BASE: u64 = 0x2001000;
 
 const CONFIG_DRAM_SUNXI_ODT_EN: u32 = 0x1;
 const CONFIG_DRAM_SUNXI_TPR0: u32 = 0x004a2195;
@@ -21,6 +22,13 @@ const CONFIG_SUNXI_DRAM_TYPE: u32 = 3; // DDR3
 
 static mut DETECTED_DRAM_SIZE: u64 = 0;
 
+/// Controller configuration detected by the last successful `init_dram`
+static mut DETECTED_DRAM_CONFIG: DRAMConfig = DRAMConfig {
+    dram_para1: 0,
+    dram_para2: 0,
+    dram_tpr13: 0,
+};
+
 struct DRAMParam {
     /* normal configuration */
     dram_clk: u32,
@@ -62,6 +70,22 @@ unsafe fn writel(v: u32, addr: u64) {
     unsafe { mmio::write32(addr, v) };
 }
 
+/// Generous for PLL_DDR's and the controller's status bits, which never get
+/// there if the DRAM or its wiring is off
+const WAIT_TIMEOUT_US: u64 = 100_000;
+
+/// Waits for `(readl(addr) & mask) == expected`, false with the register
+/// reported if it times out
+unsafe fn wait_for(addr: u64, mask: u32, expected: u32) -> bool {
+    match unsafe { mmio::wait_mask(addr, mask, expected, WAIT_TIMEOUT_US) } {
+        Ok(_) => true,
+        Err(t) => {
+            t.print("DRAM");
+            false
+        }
+    }
+}
+
 unsafe fn setbits_le32(addr: u64, set: u32) {
     unsafe { clrsetbits_le32(addr, 0, set) }
 }
@@ -575,9 +599,8 @@ unsafe fn mctl_set_timing_params(para: &DRAMParam, config: &DRAMConfig) {
 // Purpose of this routine seems to be to initialize the PLL driving
 // the MBUS and sdram.
 //
-unsafe fn ccu_set_pll_ddr_clk(para: &DRAMParam, config: &DRAMConfig) -> u32 {
+unsafe fn ccu_set_pll_ddr_clk(para: &DRAMParam, config: &DRAMConfig) -> Option<u32> {
     unsafe { 
-    let mut val: u32;
     let clk: u32;
     let n: u32;
 
@@ -590,65 +613,84 @@ unsafe fn ccu_set_pll_ddr_clk(para: &DRAMParam, config: &DRAMConfig) -> u32 {
     // set VCO clock divider
     n = (clk * 2) / 24;
 
-    val = readl(SUNXI_CCM_BASE + 0x10);
-    val &= !0x0007ff03; // clear dividers
-    val |= (n - 1) << 8; // set PLL division
-    val |= bit(31) | bit(30); // enable PLL and LDO
-    writel(val | bit(29), SUNXI_CCM_BASE + 0x10);
+    // enable PLL and LDO, PLL = 24 MHz * N
+    PLL_DDR_CTRL::read()
+        .set(PLL_DDR_CTRL::PLL_N, n - 1)
+        .set(PLL_DDR_CTRL::PLL_INPUT_DIV2, 0)
+        .set(PLL_DDR_CTRL::PLL_OUTPUT_DIV2, 0)
+        .set(PLL_DDR_CTRL::PLL_EN, 1)
+        .set(PLL_DDR_CTRL::PLL_LDO_EN, 1)
+        .set(PLL_DDR_CTRL::LOCK_ENABLE, 1)
+        .write();
 
     // wait for PLL to lock
-    while (readl(SUNXI_CCM_BASE + 0x10) & bit(28)) == 0 {}
+    if !wait_for(PLL_DDR_CTRL::ADDR, bit(28), bit(28)) {
+        return None;
+    }
 
     udelay(20);
 
-    // enable PLL output
-    setbits_le32(SUNXI_CCM_BASE + 0x0, bit(27));
+    // enable PLL output, on PLL_CPU as in U-Boot
+    PLL_CPU_CTRL::read().set(PLL_CPU_CTRL::PLL_OUTPUT_GATE, 1).write();
 
-    // turn clock gate on
-    val = readl(SUNXI_CCM_BASE + 0x800);
-    val &= !0x03000303; // select DDR clk source, n=1, m=1
-    val |= bit(31); // turn clock on
-    writel(val, SUNXI_CCM_BASE + 0x800);
+    // select DDR clk source, n=1, m=1, turn clock gate on
+    DRAM_CLK::read()
+        .set(DRAM_CLK::DRAM_CLK_SEL, DramClkSel::PllDdr)
+        .set(DRAM_CLK::DRAM_DIV2, 0)
+        .set(DRAM_CLK::DRAM_DIV1, 0)
+        .set(DRAM_CLK::DRAM_CLK_GATING, 1)
+        .write();
 
-    n * 24
+    Some(n * 24)
     }
 }
 
 /* Set up the PLL and clock gates for the DRAM controller and MBUS clocks. */
-unsafe fn mctl_sys_init(para: &DRAMParam, config: &DRAMConfig) {
+unsafe fn mctl_sys_init(para: &DRAMParam, config: &DRAMConfig) -> bool {
     unsafe { 
     // assert MBUS reset
-    clrbits_le32(SUNXI_CCM_BASE + 0x540, bit(30));
+    MBUS_CLK::read().set(MBUS_CLK::MBUS_RST, 0).write();
 
     // turn off sdram clock gate, assert sdram reset
-    clrbits_le32(SUNXI_CCM_BASE + 0x80c, 0x10001);
-    clrsetbits_le32(SUNXI_CCM_BASE + 0x800, bit(31) | bit(30), bit(27));
+    DRAM_BGR::read().set(DRAM_BGR::DRAM_RST, 0).set(DRAM_BGR::DRAM_GATING, 0).write();
+    DRAM_CLK::read()
+        .set(DRAM_CLK::DRAM_CLK_GATING, 0)
+        .set(DRAM_CLK::DRAM_MOD_RST, 0)
+        .set(DRAM_CLK::SDRCLK_UPD, 1)
+        .write();
     udelay(10);
 
     // set ddr pll clock
-    ccu_set_pll_ddr_clk(para, config);
+    if ccu_set_pll_ddr_clk(para, config).is_none() {
+        return false;
+    }
     udelay(100);
     dram_disable_all_master();
 
     // release sdram reset
-    setbits_le32(SUNXI_CCM_BASE + 0x80c, bit(16));
+    DRAM_BGR::read().set(DRAM_BGR::DRAM_RST, 1).write();
 
     // release MBUS reset
-    setbits_le32(SUNXI_CCM_BASE + 0x540, bit(30));
-    setbits_le32(SUNXI_CCM_BASE + 0x800, bit(30));
+    MBUS_CLK::read().set(MBUS_CLK::MBUS_RST, 1).write();
+    DRAM_CLK::read().set(DRAM_CLK::DRAM_MOD_RST, 1).write();
 
     udelay(5);
 
     // turn on sdram clock gate
-    setbits_le32(SUNXI_CCM_BASE + 0x80c, bit(0));
+    DRAM_BGR::read().set(DRAM_BGR::DRAM_GATING, 1).write();
 
     // turn dram clock gate on, trigger sdr clock update
-    setbits_le32(SUNXI_CCM_BASE + 0x800, bit(31) | bit(27));
+    DRAM_CLK::read()
+        .set(DRAM_CLK::DRAM_CLK_GATING, 1)
+        .set(DRAM_CLK::SDRCLK_UPD, 1)
+        .write();
     udelay(5);
 
     // mCTL clock enable
     writel(0x8000, 0x310300c);
     udelay(10);
+
+    true
     }
 }
 
@@ -932,7 +974,10 @@ unsafe fn mctl_channel_init(para: &DRAMParam, config: &DRAMConfig) -> bool {
         //writel(0x52, 0x3103000); // prep PHY reset + PLL init + z-cal
         writel(0x53, 0x3103000); // Go
 
-        while (readl(0x3103010) & 0x1) == 0 {} // wait for IDONE
+        // wait for IDONE
+        if !wait_for(0x3103010, 0x1, 0x1) {
+            return false;
+        }
         udelay(10);
 
         // 0x520 = prep DQS gating + DRAM init + d-cal
@@ -958,7 +1003,10 @@ unsafe fn mctl_channel_init(para: &DRAMParam, config: &DRAMConfig) -> bool {
     setbits_le32(0x3103000, 0x1); // GO
 
     udelay(10);
-    while (readl(0x3103010) & 0x1) == 0 {} // wait for IDONE
+    // wait for IDONE
+    if !wait_for(0x3103010, 0x1, 0x1) {
+        return false;
+    }
 
     if (readl(0x70005d4) & bit(16)) > 0 {
         clrsetbits_le32(0x310310c, 0x06000000, 0x04000000);
@@ -966,14 +1014,18 @@ unsafe fn mctl_channel_init(para: &DRAMParam, config: &DRAMConfig) -> bool {
 
         setbits_le32(0x3103004, 0x1);
 
-        while (readl(0x3103018) & 0x7) != 0x3 {}
+        if !wait_for(0x3103018, 0x7, 0x3) {
+            return false;
+        }
 
         clrbits_le32(0x7010250, 0x1);
         udelay(10);
 
         clrbits_le32(0x3103004, 0x1);
 
-        while (readl(0x3103018) & 0x7) != 0x1 {}
+        if !wait_for(0x3103018, 0x7, 0x1) {
+            return false;
+        }
 
         udelay(15);
 
@@ -983,7 +1035,10 @@ unsafe fn mctl_channel_init(para: &DRAMParam, config: &DRAMConfig) -> bool {
             udelay(1);
             writel(0x401, 0x3103000);
 
-            while (readl(0x3103010) & 0x1) == 0 {}
+            // wait for IDONE
+            if !wait_for(0x3103010, 0x1, 0x1) {
+                return false;
+            }
         }
     }
 
@@ -994,7 +1049,9 @@ unsafe fn mctl_channel_init(para: &DRAMParam, config: &DRAMConfig) -> bool {
     }
 
     // STATR = Zynq STAT? Wait for status 'normal'?
-    while (readl(0x3103018) & 0x1) == 0 {}
+    if !wait_for(0x3103018, 0x1, 0x1) {
+        return false;
+    }
 
     setbits_le32(0x310308c, bit(31));
     udelay(10);
@@ -1125,14 +1182,14 @@ unsafe fn dramc_simple_wr_test(mem_mb: u32, len: u32) -> bool {
         v2 = patt1 + i;
         if v1 != v2 {
             uart::printf!("DRAM: simple test FAIL\r\n");
-            uart::printf!("%x != %x at address %x\r\n", v1, v2, addr);
+            uart::printf!("%08x != %08x at address 0x%x\r\n", v1, v2, addr);
             return true;
         }
         v1 = readl(addr + offs);
         v2 = patt2 + i;
         if v1 != v2 {
             uart::printf!("DRAM: simple test FAIL\r\n");
-            uart::printf!("%x != %x at address %x\r\n", v1, v2, addr + offs);
+            uart::printf!("%08x != %08x at address 0x%x\r\n", v1, v2, addr + offs);
             return true;
         }
 
@@ -1168,7 +1225,9 @@ unsafe fn mctl_vrefzq_init(para: &DRAMParam, config: &DRAMConfig) {
 //
 unsafe fn mctl_core_init(para: &DRAMParam, config: &DRAMConfig) -> bool {
     unsafe { 
-    mctl_sys_init(para, config);
+    if !mctl_sys_init(para, config) {
+        return false;
+    }
 
     mctl_vrefzq_init(para, config);
 
@@ -1404,9 +1463,7 @@ unsafe fn auto_scan_dram_rank_width(para: &DRAMParam, config: &mut DRAMConfig) -
     /* set DQS probe mode */
     config.dram_tpr13 = (config.dram_tpr13 & !0x8) | bit(2) | bit(0);
 
-    mctl_core_init(para, config);
-
-    if (readl(0x3103010) & bit(20)) > 0 {
+    if !mctl_core_init(para, config) || (readl(0x3103010) & bit(20)) > 0 {
         return false;
     }
 
@@ -1464,7 +1521,7 @@ unsafe fn do_init_dram(para: &DRAMParam) -> Option<u32> {
     if (para.dram_odt_en & 0x1) == 0 {
         uart::printf!("DRAMC read ODT off\r\n");
     } else {
-        uart::printf!("DRAMC ZQ value: 0x%x\r\n", para.dram_zq);
+        uart::printf!("DRAMC ZQ value: 0x%08x\r\n", para.dram_zq);
     }
 
     /* Test ZQ status */
@@ -1481,7 +1538,7 @@ unsafe fn do_init_dram(para: &DRAMParam) -> Option<u32> {
         udelay(10);
         setbits_le32(0x3000160, bit(0));
         udelay(20);
-        uart::printf!("ZQ value = 0x%x\r\n", readl(0x300016c));
+        uart::printf!("ZQ value = 0x%08x\r\n", readl(0x300016c));
     }
 
     dram_voltage_set(para);
@@ -1499,7 +1556,7 @@ unsafe fn do_init_dram(para: &DRAMParam) -> Option<u32> {
     if (rc & 0x44) == 0 {
         uart::printf!("DRAM ODT off\r\n");
     } else {
-        uart::printf!("DRAM ODT value: 0x%x\r\n", rc);
+        uart::printf!("DRAM ODT value: 0x%08x\r\n", rc);
     }
 
     /* Init core, final run */
@@ -1571,38 +1628,48 @@ unsafe fn do_init_dram(para: &DRAMParam) -> Option<u32> {
 
     uart::printf!("initialized DRAM: memory size = %d MB\r\n", mem_size_mb);
 
+    DETECTED_DRAM_CONFIG = config;
+
     return Some(mem_size_mb);
     }
 }
 
-pub unsafe fn init_dram() {
-    let para = DRAMParam {
-        dram_clk: CONFIG_DRAM_CLK,
-        dram_type: CONFIG_SUNXI_DRAM_TYPE,
-        dram_zq: CONFIG_DRAM_ZQ,
-        dram_odt_en: CONFIG_DRAM_SUNXI_ODT_EN,
-        dram_mr0: 0x1c70,
-        dram_mr1: 0x42,
-        dram_mr2: 0x18,
-        dram_mr3: 0,
-        dram_tpr0: 0x004a2195,
-        dram_tpr1: 0x02423190,
-        dram_tpr2: 0x0008b061,
-        dram_tpr3: 0xb4787896, // unused
-        dram_tpr4: 0,
-        dram_tpr5: 0x48484848,
-        dram_tpr6: 0x00000048,
-        dram_tpr7: 0x1620121e, // unused
-        dram_tpr8: 0,
-        dram_tpr9: 0, // clock?
-        dram_tpr10: 0,
-        dram_tpr11: CONFIG_DRAM_SUNXI_TPR11,
-        dram_tpr12: CONFIG_DRAM_SUNXI_TPR12,
-    };
+const DRAM_PARA: DRAMParam = DRAMParam {
+    dram_clk: CONFIG_DRAM_CLK,
+    dram_type: CONFIG_SUNXI_DRAM_TYPE,
+    dram_zq: CONFIG_DRAM_ZQ,
+    dram_odt_en: CONFIG_DRAM_SUNXI_ODT_EN,
+    dram_mr0: 0x1c70,
+    dram_mr1: 0x42,
+    dram_mr2: 0x18,
+    dram_mr3: 0,
+    dram_tpr0: 0x004a2195,
+    dram_tpr1: 0x02423190,
+    dram_tpr2: 0x0008b061,
+    dram_tpr3: 0xb4787896, // unused
+    dram_tpr4: 0,
+    dram_tpr5: 0x48484848,
+    dram_tpr6: 0x00000048,
+    dram_tpr7: 0x1620121e, // unused
+    dram_tpr8: 0,
+    dram_tpr9: 0, // clock?
+    dram_tpr10: 0,
+    dram_tpr11: CONFIG_DRAM_SUNXI_TPR11,
+    dram_tpr12: CONFIG_DRAM_SUNXI_TPR12,
+};
 
-    let Some(size_mb) = (unsafe{ do_init_dram(&para) }) else {
+pub unsafe fn init_dram() {
+    let Some(size_mb) = (unsafe{ do_init_dram(&DRAM_PARA) }) else {
         uart::printf!("failed to initialize DRAM\r\n");
-        loop {}
+        // The trace is in SRAM, print it before resetting. Printing takes
+        // longer than the watchdog leaves.
+        #[cfg(feature = "mmio-trace")]
+        {
+            crate::wdt::WDT.disable();
+            crate::trace::dump();
+        }
+        crate::diag::set_error(crate::diag::Error::DramInit, 0, 0);
+        crate::wdt::fatal()
     };
 
     unsafe { DETECTED_DRAM_SIZE = size_mb as u64 * 1024 * 1024 };
@@ -1610,6 +1677,57 @@ pub unsafe fn init_dram() {
     uart::printf!("initialized DRAM: %d MB at 0x%x\r\n", size_mb, CFG_SYS_SDRAM_BASE);
 }
 
+/// Prints the detected size and the parameters the controller was trained with
+pub fn print_info() {
+    let para = &DRAM_PARA;
+    let config = unsafe { &*core::ptr::addr_of!(DETECTED_DRAM_CONFIG) };
+
+    uart::printf!(
+        "size: %d MB at 0x%x\r\n",
+        dram_size() / (1024 * 1024),
+        CFG_SYS_SDRAM_BASE
+    );
+    uart::printf!(
+        "clk: %d MHz, type: %d, zq: 0x%08x, odt_en: %d\r\n",
+        para.dram_clk,
+        para.dram_type,
+        para.dram_zq,
+        para.dram_odt_en
+    );
+    uart::printf!(
+        "mr0-3: 0x%08x 0x%08x 0x%08x 0x%08x\r\n",
+        para.dram_mr0,
+        para.dram_mr1,
+        para.dram_mr2,
+        para.dram_mr3
+    );
+    uart::printf!(
+        "tpr0-5: 0x%08x 0x%08x 0x%08x 0x%08x 0x%08x 0x%08x\r\n",
+        para.dram_tpr0,
+        para.dram_tpr1,
+        para.dram_tpr2,
+        para.dram_tpr3,
+        para.dram_tpr4,
+        para.dram_tpr5
+    );
+    uart::printf!(
+        "tpr6-12: 0x%08x 0x%08x 0x%08x 0x%08x 0x%08x 0x%08x 0x%08x\r\n",
+        para.dram_tpr6,
+        para.dram_tpr7,
+        para.dram_tpr8,
+        para.dram_tpr9,
+        para.dram_tpr10,
+        para.dram_tpr11,
+        para.dram_tpr12
+    );
+    uart::printf!(
+        "para1: 0x%08x, para2: 0x%08x, tpr13: 0x%08x\r\n",
+        config.dram_para1,
+        config.dram_para2,
+        config.dram_tpr13
+    );
+}
+
 pub fn dram_size() -> u64 {
     unsafe { DETECTED_DRAM_SIZE }
 }
@@ -1617,3 +1735,54 @@ pub fn dram_size() -> u64 {
 pub fn dram_base() -> *mut u8 {
     CFG_SYS_SDRAM_BASE as *mut u8
 }
+
+/// Stack the bootloader moves to once DRAM is up, at the very end of DRAM
+pub const STACK_SIZE: u64 = 64 * 1024;
+
+pub fn stack_top() -> u64 {
+    CFG_SYS_SDRAM_BASE + dram_size()
+}
+
+/// DRAM from `addr` up to the handoff page below the bootloader's stack, or
+/// the MMIO trace below that, None if `addr` is outside of it
+pub fn dram_buffer(addr: u64) -> Option<&'static mut [u8]> {
+    let base = dram_base() as u64;
+    #[cfg(not(feature = "mmio-trace"))]
+    let end = crate::handoff::addr();
+    #[cfg(feature = "mmio-trace")]
+    let end = crate::trace::dram_ring();
+
+    if addr < base || addr >= end {
+        return None;
+    }
+
+    Some(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, (end - addr) as usize) })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sim;
+
+    #[test]
+    fn init_dram_trace() {
+        sim::assert_trace(&sim::init_dram_trace(), include_str!("../testdata/init_dram.trace"), "dram", "init_dram.trace");
+
+        let console = sim::take_console();
+        assert!(console.contains("rank 0 row = 15\r\nrank 0 bank = 8\r\nrank 0 page size = 2 KB\r\n"));
+        assert!(console.ends_with("initialized DRAM: 512 MB at 0x40000000\r\n"));
+        assert_eq!(super::dram_size(), 512 * 1024 * 1024);
+    }
+
+    #[test]
+    fn init_dram_reports_stuck_phy() {
+        sim::reset();
+        sim::d1();
+        // PIR never finishes
+        sim::on_access(0x0310_3000, |a| a.value);
+
+        let reset = std::panic::catch_unwind(|| unsafe { super::init_dram() });
+
+        assert!(reset.is_err());
+        assert!(sim::take_console().contains("DRAM timeout: 0x3103010 = 0x0\r\n"));
+    }
+}
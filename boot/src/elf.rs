@@ -68,11 +68,30 @@ pub unsafe fn execute(binary: *const u8) -> ! {
 
         crate::uart::printf!("Jumping to kernel at 0x%x\r\n", (*ehdr).e_entry);
 
+        jump((*ehdr).e_entry)
+    }
+}
+
+/// Enters the kernel with the boot arguments described in [`crate::handoff`]
+#[cfg(not(any(test, feature = "sim")))]
+pub unsafe fn jump(entry: u64) -> ! {
+    let info = crate::handoff::hand_over();
+
+    unsafe {
         core::arch::asm!(
             "jalr x0, t0, 0",
-            in("t0") (*ehdr).e_entry
+            in("t0") entry,
+            in("a0") 0,
+            in("a1") info.fdt,
+            in("a2") info as *const _ as u64,
         );
 
         core::hint::unreachable_unchecked();
     }
 }
+
+/// The host builds have no kernel to enter
+#[cfg(any(test, feature = "sim"))]
+pub unsafe fn jump(entry: u64) -> ! {
+    panic!("jump to 0x{entry:x} on the host")
+}
@@ -0,0 +1,629 @@
+//! Read-only ext2/ext3/ext4 reader
+//!
+//! Understands extents and indirect blocks, 64 bit group descriptors and
+//! flex_bg. The journal is ignored, so a file system that wasn't unmounted
+//! cleanly is read as it is on disk. Directories are searched linearly,
+//! which also covers htree directories.
+//!
+//! Metadata is read a field at a time through a single cached sector, file
+//! data goes straight into the destination.
+
+use crate::block::{BLOCK_SIZE, BlockDevice};
+use crate::fs::{Error, FileSystem, components, le16, le32};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_RECOVER: u32 = 0x0004;
+const INCOMPAT_EXTENTS: u32 = 0x0040;
+const INCOMPAT_64BIT: u32 = 0x0080;
+const INCOMPAT_MMP: u32 = 0x0100;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_EA_INODE: u32 = 0x0400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+
+const EXTENTS_FL: u32 = 0x0008_0000;
+const INLINE_DATA_FL: u32 = 0x1000_0000;
+
+const EXTENT_MAGIC: u32 = 0xf30a;
+const EXTENT_MAX_DEPTH: usize = 5;
+/// Extents longer than this are preallocated but uninitialized
+const EXTENT_INIT_MAX_LEN: u32 = 32768;
+
+const DIRECT_BLOCKS: u64 = 12;
+
+struct Inode {
+    mode: u16,
+    size: u64,
+    flags: u32,
+    /// i_block: block map or extent tree root
+    block: [u32; 15],
+}
+
+impl Inode {
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+/// An extent tree node, either the root in the inode or a block on disk
+#[derive(Clone, Copy)]
+enum Node {
+    Inode,
+    Disk(u64),
+}
+
+pub struct Ext2<D> {
+    dev: D,
+    /// log2 of the block size in bytes
+    block_log2: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    desc_size: u64,
+    /// Byte offset of the group descriptor table
+    gdt: u64,
+    buf: [u8; BLOCK_SIZE],
+    buf_lba: Option<u64>,
+}
+
+impl<D: BlockDevice> Ext2<D> {
+    /// Reads the superblock, fails with [`Error::NoFileSystem`] if `dev`
+    /// doesn't hold an ext2/3/4 file system
+    pub fn mount(dev: D) -> Result<Self, Error> {
+        let mut fs = Self {
+            dev,
+            block_log2: 10,
+            inodes_per_group: 0,
+            inode_size: 128,
+            desc_size: 32,
+            gdt: 0,
+            buf: [0; BLOCK_SIZE],
+            buf_lba: None,
+        };
+
+        let sb = |fs: &mut Self, offset| fs.disk_u32(SUPERBLOCK_OFFSET + offset);
+
+        if sb(&mut fs, 56)? & 0xffff != EXT2_MAGIC as u32 {
+            return Err(Error::NoFileSystem);
+        }
+
+        let first_data_block = sb(&mut fs, 20)? as u64;
+        let log_block_size = sb(&mut fs, 24)?;
+        let rev_level = sb(&mut fs, 76)?;
+        let inode_size = sb(&mut fs, 88)? & 0xffff;
+        let incompat = sb(&mut fs, 96)?;
+        let desc_size = sb(&mut fs, 252)? >> 16;
+
+        fs.inodes_per_group = sb(&mut fs, 40)?;
+
+        // Block sizes go from 1 KiB to 64 KiB
+        if log_block_size > 6 || fs.inodes_per_group == 0 {
+            return Err(Error::Corrupt);
+        }
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Error::Unsupported);
+        }
+
+        fs.block_log2 = 10 + log_block_size;
+
+        if rev_level > 0 {
+            if !inode_size.is_power_of_two() || inode_size < 128 || inode_size as usize > BLOCK_SIZE {
+                return Err(Error::Corrupt);
+            }
+            fs.inode_size = inode_size as u64;
+        }
+
+        if incompat & INCOMPAT_64BIT != 0 {
+            if desc_size < 64 || !desc_size.is_power_of_two() {
+                return Err(Error::Corrupt);
+            }
+            fs.desc_size = desc_size as u64;
+        }
+
+        // The descriptors follow the block holding the superblock
+        fs.gdt = (first_data_block + 1) << fs.block_log2;
+
+        Ok(fs)
+    }
+
+    fn read_sector(&mut self, lba: u64) -> Result<(), Error> {
+        if self.buf_lba != Some(lba) {
+            self.buf_lba = None;
+            self.dev.read_blocks(lba, &mut self.buf)?;
+            self.buf_lba = Some(lba);
+        }
+
+        Ok(())
+    }
+
+    /// Reads the byte at `offset` on the device
+    fn disk_u8(&mut self, offset: u64) -> Result<u8, Error> {
+        self.read_sector(offset / BLOCK_SIZE as u64)?;
+        Ok(self.buf[offset as usize % BLOCK_SIZE])
+    }
+
+    /// Reads the little endian word at the 4 byte aligned `offset`
+    fn disk_u32(&mut self, offset: u64) -> Result<u32, Error> {
+        self.read_sector(offset / BLOCK_SIZE as u64)?;
+        Ok(le32(&self.buf, offset as usize % BLOCK_SIZE))
+    }
+
+    fn disk_u16(&mut self, offset: u64) -> Result<u16, Error> {
+        self.read_sector(offset / BLOCK_SIZE as u64)?;
+        Ok(le16(&self.buf, offset as usize % BLOCK_SIZE))
+    }
+
+    fn block_offset(&self, block: u64) -> u64 {
+        block << self.block_log2
+    }
+
+    fn read_inode(&mut self, ino: u32) -> Result<Inode, Error> {
+        let index = ino.checked_sub(1).ok_or(Error::Corrupt)?;
+        let group = index.checked_div(self.inodes_per_group).ok_or(Error::Corrupt)? as u64;
+        let index = index.checked_rem(self.inodes_per_group).ok_or(Error::Corrupt)? as u64;
+
+        let desc = self.gdt + group * self.desc_size;
+        let mut table = self.disk_u32(desc + 8)? as u64;
+        if self.desc_size >= 64 {
+            table |= (self.disk_u32(desc + 0x28)? as u64) << 32;
+        }
+
+        let base = self.block_offset(table) + index * self.inode_size;
+
+        let mut block = [0u32; 15];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = self.disk_u32(base + 40 + i as u64 * 4)?;
+        }
+
+        Ok(Inode {
+            mode: self.disk_u16(base)?,
+            size: self.disk_u32(base + 4)? as u64 | (self.disk_u32(base + 108)? as u64) << 32,
+            flags: self.disk_u32(base + 32)?,
+            block,
+        })
+    }
+
+    fn node_u32(&mut self, inode: &Inode, node: Node, offset: u64) -> Result<u32, Error> {
+        match node {
+            Node::Inode => inode.block.get(offset as usize / 4).copied().ok_or(Error::Corrupt),
+            Node::Disk(base) => self.disk_u32(base + offset),
+        }
+    }
+
+    fn node_u16(&mut self, inode: &Inode, node: Node, offset: u64) -> Result<u32, Error> {
+        Ok((self.node_u32(inode, node, offset & !3)? >> ((offset & 2) * 8)) & 0xffff)
+    }
+
+    /// Maps a file block through the extent tree. Returns the physical block,
+    /// 0 for a hole, and how many following blocks are contiguous.
+    fn map_extent(&mut self, inode: &Inode, lblk: u64) -> Result<(u64, u64), Error> {
+        let mut node = Node::Inode;
+
+        for _ in 0..EXTENT_MAX_DEPTH {
+            if self.node_u16(inode, node, 0)? != EXTENT_MAGIC {
+                return Err(Error::Corrupt);
+            }
+
+            let entries = self.node_u16(inode, node, 2)? as u64;
+            let depth = self.node_u16(inode, node, 6)?;
+
+            if depth == 0 {
+                for i in 0..entries {
+                    let e = 12 + i * 12;
+                    let first = self.node_u32(inode, node, e)? as u64;
+                    let mut len = self.node_u16(inode, node, e + 4)?;
+                    let start = (self.node_u16(inode, node, e + 6)? as u64) << 32
+                        | self.node_u32(inode, node, e + 8)? as u64;
+
+                    let uninit = len > EXTENT_INIT_MAX_LEN;
+                    if uninit {
+                        len -= EXTENT_INIT_MAX_LEN;
+                    }
+
+                    let end = first + len as u64;
+
+                    if lblk < first {
+                        return Ok((0, first - lblk));
+                    }
+                    if lblk < end {
+                        let phys = if uninit { 0 } else { start + lblk - first };
+                        return Ok((phys, end - lblk));
+                    }
+                }
+
+                return Ok((0, u64::MAX));
+            }
+
+            // Last index starting at or before lblk
+            let mut leaf = None;
+            for i in 0..entries {
+                let e = 12 + i * 12;
+                if self.node_u32(inode, node, e)? as u64 > lblk {
+                    break;
+                }
+
+                leaf = Some(
+                    (self.node_u16(inode, node, e + 8)? as u64) << 32 | self.node_u32(inode, node, e + 4)? as u64,
+                );
+            }
+
+            match leaf {
+                Some(block) => node = Node::Disk(self.block_offset(block)),
+                None => return Ok((0, 1)),
+            }
+        }
+
+        Err(Error::Corrupt)
+    }
+
+    /// Maps a file block through the direct and indirect block pointers
+    fn map_indirect(&mut self, inode: &Inode, lblk: u64) -> Result<(u64, u64), Error> {
+        if lblk < DIRECT_BLOCKS {
+            return Ok((inode.block[lblk as usize % 12] as u64, 1));
+        }
+
+        let per_log2 = self.block_log2 - 2;
+        let mut index = lblk - DIRECT_BLOCKS;
+        let mut slot = 12;
+        let mut depth = 1;
+
+        while index >> (per_log2 * depth) != 0 {
+            index -= 1 << (per_log2 * depth);
+            slot += 1;
+            depth += 1;
+
+            if slot > 14 {
+                return Err(Error::Corrupt);
+            }
+        }
+
+        let mut block = inode.block[slot % 15] as u64;
+
+        for level in (0..depth).rev() {
+            if block == 0 {
+                break;
+            }
+
+            let i = (index >> (per_log2 * level)) & ((1 << per_log2) - 1);
+            block = self.disk_u32(self.block_offset(block) + i * 4)? as u64;
+        }
+
+        Ok((block, 1))
+    }
+
+    fn map(&mut self, inode: &Inode, lblk: u64) -> Result<(u64, u64), Error> {
+        if inode.flags & EXTENTS_FL != 0 {
+            self.map_extent(inode, lblk)
+        } else {
+            self.map_indirect(inode, lblk)
+        }
+    }
+
+    /// Looks up `name` in the directory `dir`, returns its inode number
+    fn find(&mut self, dir: &Inode, name: &[u8]) -> Result<u32, Error> {
+        let block_size = 1u64 << self.block_log2;
+        let blocks = dir.size.div_ceil(block_size);
+
+        for lblk in 0..blocks {
+            let (phys, _) = self.map(dir, lblk)?;
+            if phys == 0 {
+                continue;
+            }
+
+            let base = self.block_offset(phys);
+            let mut offset = 0;
+
+            while offset + 8 <= block_size {
+                let ino = self.disk_u32(base + offset)?;
+                let head = self.disk_u32(base + offset + 4)?;
+                let rec_len = (head & 0xffff) as u64;
+                let name_len = ((head >> 16) & 0xff) as usize;
+
+                if rec_len < 8 || !rec_len.is_multiple_of(4) || offset + rec_len > block_size {
+                    return Err(Error::Corrupt);
+                }
+
+                if ino != 0 && name_len == name.len() {
+                    let mut equal = true;
+                    for (i, &c) in name.iter().enumerate() {
+                        if self.disk_u8(base + offset + 8 + i as u64)? != c {
+                            equal = false;
+                            break;
+                        }
+                    }
+
+                    if equal {
+                        return Ok(ino);
+                    }
+                }
+
+                offset += rec_len;
+            }
+        }
+
+        Err(Error::NotFound)
+    }
+
+    fn open(&mut self, path: &str) -> Result<Inode, Error> {
+        let mut inode = self.read_inode(ROOT_INODE)?;
+
+        for name in components(path) {
+            if !inode.is_dir() {
+                return Err(Error::NotADirectory);
+            }
+
+            let ino = self.find(&inode, name)?;
+            inode = self.read_inode(ino)?;
+        }
+
+        Ok(inode)
+    }
+
+    fn read(&mut self, inode: &Inode, buf: &mut [u8]) -> Result<usize, Error> {
+        match inode.mode & S_IFMT {
+            S_IFREG => {}
+            S_IFDIR => return Err(Error::IsADirectory),
+            // Symlinks and special files
+            _ => return Err(Error::Unsupported),
+        }
+
+        if inode.flags & INLINE_DATA_FL != 0 {
+            return Err(Error::Unsupported);
+        }
+
+        let size = usize::try_from(inode.size).map_err(|_| Error::TooLarge)?;
+        let block_size = 1usize << self.block_log2;
+        let mut rest = buf.get_mut(..size).ok_or(Error::TooLarge)?;
+        let mut lblk = 0;
+
+        while !rest.is_empty() {
+            let (phys, run) = self.map(inode, lblk)?;
+
+            let blocks = (rest.len().div_ceil(block_size) as u64).min(run);
+            let len = rest.len().min((blocks as usize) << self.block_log2);
+            let whole = len / BLOCK_SIZE * BLOCK_SIZE;
+
+            let (chunk, tail) = rest.split_at_mut_checked(len).ok_or(Error::Corrupt)?;
+
+            if phys == 0 {
+                chunk.fill(0);
+            } else {
+                let lba = self.block_offset(phys) / BLOCK_SIZE as u64;
+                let (sectors, partial) = chunk.split_at_mut_checked(whole).ok_or(Error::Corrupt)?;
+
+                self.dev.read_blocks(lba, sectors)?;
+
+                if !partial.is_empty() {
+                    self.read_sector(lba + (whole / BLOCK_SIZE) as u64)?;
+                    for (dst, &src) in partial.iter_mut().zip(self.buf.iter()) {
+                        *dst = src;
+                    }
+                }
+            }
+
+            rest = tail;
+            lblk += blocks;
+        }
+
+        Ok(size)
+    }
+}
+
+impl<D: BlockDevice> FileSystem for Ext2<D> {
+    fn read_file(&mut self, path: &str, buf: &mut [u8]) -> Result<usize, Error> {
+        let inode = self.open(path)?;
+        self.read(&inode, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+    use std::path::{Path, PathBuf};
+    use std::vec::Vec;
+
+    /// Big enough for double indirect blocks at 1 KiB and 4 KiB blocks
+    const KERNEL_SIZE: usize = 5 << 20;
+    /// 16 islands of 4 KiB data, 64 KiB apart
+    const SPARSE_SIZE: usize = 1 << 20;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len as u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect()
+    }
+
+    fn sparse() -> Vec<u8> {
+        let mut data = vec![0u8; SPARSE_SIZE];
+        for (i, island) in data.chunks_mut(64 << 10).enumerate() {
+            island[..4096].fill(i as u8 + 1);
+        }
+
+        data
+    }
+
+    /// Scratch directory removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(what: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("boot-test-{}-{what}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write_sparse(path: &Path) {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let mut f = std::fs::File::create(path).unwrap();
+        for (i, island) in sparse().chunks(64 << 10).enumerate() {
+            f.seek(SeekFrom::Start((i * (64 << 10)) as u64)).unwrap();
+            f.write_all(&island[..4096]).unwrap();
+        }
+        f.set_len(SPARSE_SIZE as u64).unwrap();
+    }
+
+    /// Image of `size` made by `mke2fs -d` with `options` from a tree of:
+    /// - /boot/kernel.elf, [`KERNEL_SIZE`] bytes
+    /// - /boot/extlinux/extlinux.conf
+    /// - /sparse, [`SPARSE_SIZE`] bytes with holes
+    /// - /many/file-0 to /many/file-59, spanning several directory blocks
+    /// - /empty, and /link, a symlink to /boot/kernel.elf
+    fn mke2fs(what: &str, options: &[&str], size: &str) -> Vec<u8> {
+        let dir = TempDir::new(what);
+        let root = dir.0.join("root");
+        let image = dir.0.join("image");
+
+        std::fs::create_dir_all(root.join("boot/extlinux")).unwrap();
+        std::fs::create_dir_all(root.join("many")).unwrap();
+        std::fs::write(root.join("boot/kernel.elf"), pattern(KERNEL_SIZE)).unwrap();
+        std::fs::write(root.join("boot/extlinux/extlinux.conf"), "default linux\n").unwrap();
+        std::fs::write(root.join("empty"), "").unwrap();
+        write_sparse(&root.join("sparse"));
+        std::os::unix::fs::symlink("/boot/kernel.elf", root.join("link")).unwrap();
+
+        for i in 0..60 {
+            std::fs::write(root.join(format!("many/file-{i}")), format!("file {i}\n")).unwrap();
+        }
+
+        let out = std::process::Command::new("mke2fs")
+            .args(["-q", "-F"])
+            .args(options)
+            .arg("-d")
+            .arg(&root)
+            .arg(&image)
+            .arg(size)
+            .output()
+            .expect("mke2fs (e2fsprogs) is needed for the ext2 tests");
+
+        assert!(out.status.success(), "mke2fs: {}", String::from_utf8_lossy(&out.stderr));
+
+        std::fs::read(&image).unwrap()
+    }
+
+    fn read(fs: &mut Ext2<Vec<u8>>, path: &str) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0u8; KERNEL_SIZE];
+        let size = fs.read_file(path, &mut buf)?;
+        buf.truncate(size);
+
+        Ok(buf)
+    }
+
+    fn check_files(fs: &mut Ext2<Vec<u8>>, what: &str) {
+        let kernel = pattern(KERNEL_SIZE);
+        let cases: &[(&str, Result<&[u8], Error>)] = &[
+            ("/boot/kernel.elf", Ok(&kernel)),
+            ("boot//extlinux/./extlinux.conf", Ok(b"default linux\n")),
+            ("/boot/extlinux/../../boot/extlinux/extlinux.conf", Ok(b"default linux\n")),
+            ("/sparse", Ok(&sparse())),
+            ("/many/file-0", Ok(b"file 0\n")),
+            ("/many/file-59", Ok(b"file 59\n")),
+            ("/empty", Ok(b"")),
+            ("/boot/Kernel.elf", Err(Error::NotFound)),
+            ("/many/file-60", Err(Error::NotFound)),
+            ("/empty/x", Err(Error::NotADirectory)),
+            ("/boot", Err(Error::IsADirectory)),
+            ("/", Err(Error::IsADirectory)),
+            ("/link", Err(Error::Unsupported)),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(read(fs, path).as_deref().map_err(|e| *e), *expected, "{what} {path}");
+        }
+
+        let mut small = [0u8; 100];
+        assert_eq!(fs.read_file("/boot/kernel.elf", &mut small), Err(Error::TooLarge), "{what}");
+    }
+
+    fn inode(fs: &mut Ext2<Vec<u8>>, path: &str) -> Inode {
+        fs.open(path).unwrap()
+    }
+
+    #[test]
+    fn ext2_indirect_blocks() {
+        let mut fs = Ext2::mount(mke2fs("ext2", &["-t", "ext2", "-b", "1024"], "16M")).unwrap();
+        assert_eq!((fs.block_log2, fs.desc_size), (10, 32));
+
+        // Single, double indirect, and holes in the block map
+        let kernel = inode(&mut fs, "/boot/kernel.elf");
+        assert!(kernel.flags & EXTENTS_FL == 0 && kernel.block[12] != 0 && kernel.block[13] != 0);
+        assert_eq!(inode(&mut fs, "/sparse").block[4], 0);
+
+        check_files(&mut fs, "ext2");
+    }
+
+    #[test]
+    fn ext3_journal() {
+        let mut fs = Ext2::mount(mke2fs("ext3", &["-t", "ext3", "-b", "4096"], "32M")).unwrap();
+        assert_eq!((fs.block_log2, fs.desc_size), (12, 32));
+
+        let kernel = inode(&mut fs, "/boot/kernel.elf");
+        assert!(kernel.flags & EXTENTS_FL == 0 && kernel.block[13] != 0);
+
+        check_files(&mut fs, "ext3");
+    }
+
+    #[test]
+    fn ext4_extents_64bit() {
+        // Few inodes per group, so /many spills over into later groups
+        let options = ["-t", "ext4", "-b", "1024", "-O", "64bit", "-N", "80"];
+        let mut fs = Ext2::mount(mke2fs("ext4", &options, "40M")).unwrap();
+        assert_eq!((fs.block_log2, fs.desc_size, fs.inodes_per_group), (10, 64, 16));
+
+        let many = inode(&mut fs, "/many");
+        let last = fs.find(&many, b"file-59").unwrap();
+        assert!(last > 2 * fs.inodes_per_group, "inode {last}");
+
+        // /sparse has more extents than fit the inode, an extent tree of
+        // depth 1
+        let sparse = inode(&mut fs, "/sparse");
+        assert!(sparse.flags & EXTENTS_FL != 0);
+        assert_eq!(sparse.block[1] >> 16, 1);
+
+        check_files(&mut fs, "ext4");
+    }
+
+    #[test]
+    fn ext4_4k_blocks() {
+        let mut fs = Ext2::mount(mke2fs("ext4-4k", &["-t", "ext4", "-b", "4096"], "32M")).unwrap();
+        assert_eq!(fs.block_log2, 12);
+
+        check_files(&mut fs, "ext4 4 KiB");
+    }
+
+    #[test]
+    fn not_ext2() {
+        let mut image = mke2fs("bad", &["-t", "ext4", "-b", "1024"], "8M");
+
+        // Unknown incompatible feature
+        image[1024 + 96 + 3] |= 0x80;
+        assert_eq!(Ext2::mount(image.clone()).err(), Some(Error::Unsupported));
+
+        image[1024 + 56] = 0;
+        assert_eq!(Ext2::mount(image).err(), Some(Error::NoFileSystem));
+    }
+}
@@ -0,0 +1,710 @@
+//! Read-only FAT12/16/32 reader
+//!
+//! Works with 512 byte sectors only and keeps a single sector buffer, file
+//! data is read straight into the destination. Long file names are matched
+//! case-insensitively as ASCII, anything else in them never matches.
+
+use crate::block::{BLOCK_SIZE, BlockDevice};
+use crate::fs::{Error, FileSystem, components, le16, le32};
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+
+/// Offsets of the 13 UCS-2 characters in a long name entry
+const LFN_CHARS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LFN_MAX_ENTRIES: usize = 20;
+const LFN_LAST: u8 = 0x40;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// A directory entry resolved from a path
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    /// First cluster, 0 for the fixed FAT12/16 root directory
+    cluster: u32,
+    pub size: u32,
+    pub dir: bool,
+}
+
+pub struct Fat<D> {
+    dev: D,
+    kind: FatType,
+    cluster_log2: u32,
+    fat_start: u64,
+    root_start: u64,
+    root_sectors: u32,
+    data_start: u64,
+    clusters: u32,
+    root_cluster: u32,
+    buf: [u8; BLOCK_SIZE],
+    buf_lba: Option<u64>,
+}
+
+/// Position while walking a directory sector by sector
+struct DirPos {
+    cluster: u32,
+    sector: u32,
+    hops: u32,
+}
+
+/// Long name collected from the entries preceding a short entry
+struct LongName {
+    name: [u8; LFN_MAX_ENTRIES * 13],
+    len: usize,
+    checksum: u8,
+    /// Sequence number of the next entry expected, 0 once complete
+    next: u8,
+    valid: bool,
+}
+
+impl LongName {
+    fn new() -> Self {
+        Self {
+            name: [0; LFN_MAX_ENTRIES * 13],
+            len: 0,
+            checksum: 0,
+            next: 0,
+            valid: false,
+        }
+    }
+
+    fn push(&mut self, e: &[u8]) {
+        let ord = e[0] & 0x1f;
+
+        if e[0] & LFN_LAST != 0 {
+            self.valid = ord != 0 && ord as usize <= LFN_MAX_ENTRIES;
+            self.checksum = e[13];
+            self.next = ord;
+            self.len = ord as usize * 13;
+        }
+
+        // Sequence numbers start at 1, an entry numbered 0 after a complete
+        // name is corrupt
+        if !self.valid || ord == 0 || ord != self.next || e[13] != self.checksum {
+            self.valid = false;
+            return;
+        }
+
+        let base = (ord as usize - 1) * 13;
+
+        for (i, &offset) in LFN_CHARS.iter().enumerate() {
+            let c = le16(e, offset);
+            let pos = base + i;
+
+            match c {
+                0 => self.len = self.len.min(pos),
+                0xffff => {}
+                // 0xff never shows up in UTF-8, so non-ASCII never matches
+                _ => {
+                    if let Some(b) = self.name.get_mut(pos) {
+                        *b = if c < 0x80 { c as u8 } else { 0xff };
+                    }
+                }
+            }
+        }
+
+        self.next -= 1;
+    }
+
+    /// The long name belonging to the short entry `short`, if any
+    fn get(&self, short: &[u8]) -> Option<&[u8]> {
+        if self.valid && self.next == 0 && checksum(short) == self.checksum {
+            self.name.get(..self.len)
+        } else {
+            None
+        }
+    }
+}
+
+fn checksum(short: &[u8]) -> u8 {
+    short
+        .iter()
+        .take(11)
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// `name` in the padded upper-case 8.3 form of directory entries, None if it
+/// has no such form
+fn short_name(name: &[u8]) -> Option<[u8; 11]> {
+    let mut short = [b' '; 11];
+
+    if name == b".." {
+        short[..2].copy_from_slice(b"..");
+        return Some(short);
+    }
+
+    let (base, ext) = match name.iter().rposition(|&c| c == b'.') {
+        Some(0) => return None,
+        Some(i) => (name.get(..i)?, name.get(i + 1..)?),
+        None => (name, &b""[..]),
+    };
+
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+
+    let (short_base, short_ext) = short.split_at_mut(8);
+
+    for (dst, &c) in short_base.iter_mut().zip(base).chain(short_ext.iter_mut().zip(ext)) {
+        if c == b'.' || c == b' ' {
+            return None;
+        }
+        *dst = c.to_ascii_uppercase();
+    }
+
+    Some(short)
+}
+
+impl<D: BlockDevice> Fat<D> {
+    /// Parses the boot sector, fails with [`Error::NoFileSystem`] if `dev`
+    /// doesn't start with a FAT file system
+    pub fn mount(mut dev: D) -> Result<Self, Error> {
+        let mut buf = [0u8; BLOCK_SIZE];
+        dev.read_blocks(0, &mut buf)?;
+
+        let bytes_per_sector = le16(&buf, 11);
+        let sectors_per_cluster = buf[13] as u32;
+        let reserved = le16(&buf, 14) as u64;
+        let fats = buf[16] as u64;
+        let root_entries = le16(&buf, 17) as u32;
+        let total = match le16(&buf, 19) {
+            0 => le32(&buf, 32),
+            n => n as u32,
+        } as u64;
+        let fat_size = match le16(&buf, 22) {
+            0 => le32(&buf, 36),
+            n => n as u32,
+        } as u64;
+
+        if buf[510..] != [0x55, 0xaa]
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || fat_size == 0
+        {
+            return Err(Error::NoFileSystem);
+        }
+
+        if bytes_per_sector as usize != BLOCK_SIZE {
+            return Err(Error::Unsupported);
+        }
+
+        let root_sectors = (root_entries * DIR_ENTRY_SIZE as u32).div_ceil(BLOCK_SIZE as u32);
+        let fat_start = reserved;
+        let root_start = fat_start + fats * fat_size;
+        let data_start = root_start + root_sectors as u64;
+
+        if total <= data_start || total > dev.block_count() {
+            return Err(Error::NoFileSystem);
+        }
+
+        let cluster_log2 = sectors_per_cluster.trailing_zeros();
+        let clusters = ((total - data_start) >> cluster_log2) as u32;
+
+        // The cluster count alone decides the FAT type
+        let kind = match clusters {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+
+        let root_cluster = match kind {
+            FatType::Fat32 => le32(&buf, 44),
+            _ => 0,
+        };
+
+        Ok(Self {
+            dev,
+            kind,
+            cluster_log2,
+            fat_start,
+            root_start,
+            root_sectors,
+            data_start,
+            clusters,
+            root_cluster,
+            buf,
+            buf_lba: Some(0),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.kind
+    }
+
+    fn read_sector(&mut self, lba: u64) -> Result<(), Error> {
+        if self.buf_lba != Some(lba) {
+            self.buf_lba = None;
+            self.dev.read_blocks(lba, &mut self.buf)?;
+            self.buf_lba = Some(lba);
+        }
+
+        Ok(())
+    }
+
+    fn fat_byte(&mut self, offset: u64) -> Result<u8, Error> {
+        self.read_sector(self.fat_start + offset / BLOCK_SIZE as u64)?;
+        Ok(self.buf[offset as usize % BLOCK_SIZE])
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.clusters
+    }
+
+    /// Follows the FAT, returns None at the end of the chain
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, Error> {
+        let c = cluster as u64;
+
+        let (next, end) = match self.kind {
+            FatType::Fat12 => {
+                let offset = c + c / 2;
+                let v = self.fat_byte(offset)? as u32 | (self.fat_byte(offset + 1)? as u32) << 8;
+                let v = if c & 1 == 0 { v & 0xfff } else { v >> 4 };
+                (v, 0xff8)
+            }
+            FatType::Fat16 => {
+                let v = self.fat_byte(c * 2)? as u32 | (self.fat_byte(c * 2 + 1)? as u32) << 8;
+                (v, 0xfff8)
+            }
+            FatType::Fat32 => {
+                let offset = c * 4;
+                self.read_sector(self.fat_start + offset / BLOCK_SIZE as u64)?;
+                (le32(&self.buf, offset as usize % BLOCK_SIZE) & 0x0fff_ffff, 0x0fff_fff8)
+            }
+        };
+
+        if next >= end {
+            Ok(None)
+        } else if self.is_valid_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(Error::Corrupt)
+        }
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.data_start + (((cluster - 2) as u64) << self.cluster_log2)
+    }
+
+    /// Returns the next sector of a directory, None past its end
+    fn next_dir_sector(&mut self, pos: &mut DirPos) -> Result<Option<u64>, Error> {
+        if pos.cluster == 0 {
+            if pos.sector >= self.root_sectors {
+                return Ok(None);
+            }
+
+            pos.sector += 1;
+            return Ok(Some(self.root_start + pos.sector as u64 - 1));
+        }
+
+        if pos.sector == 1 << self.cluster_log2 {
+            // Guards against loops in a corrupt chain
+            pos.hops += 1;
+            if pos.hops > self.clusters {
+                return Err(Error::Corrupt);
+            }
+
+            match self.next_cluster(pos.cluster)? {
+                Some(next) => pos.cluster = next,
+                None => return Ok(None),
+            }
+
+            pos.sector = 0;
+        }
+
+        pos.sector += 1;
+        Ok(Some(self.cluster_lba(pos.cluster) + pos.sector as u64 - 1))
+    }
+
+    fn root(&self) -> Entry {
+        Entry {
+            cluster: self.root_cluster,
+            size: 0,
+            dir: true,
+        }
+    }
+
+    /// Looks up `name` in the directory `dir`
+    fn find(&mut self, dir: &Entry, name: &[u8]) -> Result<Entry, Error> {
+        if dir.cluster != 0 && !self.is_valid_cluster(dir.cluster) {
+            return Err(Error::Corrupt);
+        }
+
+        let short = short_name(name);
+        let mut long = LongName::new();
+        let mut pos = DirPos {
+            cluster: dir.cluster,
+            sector: 0,
+            hops: 0,
+        };
+
+        while let Some(lba) = self.next_dir_sector(&mut pos)? {
+            self.read_sector(lba)?;
+
+            for e in self.buf.chunks_exact(DIR_ENTRY_SIZE) {
+                let attr = e[11];
+
+                match e[0] {
+                    ENTRY_END => return Err(Error::NotFound),
+                    ENTRY_DELETED => {
+                        long.valid = false;
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                if attr & 0x3f == ATTR_LONG_NAME {
+                    long.push(e);
+                    continue;
+                }
+
+                let matches = long.get(e).is_some_and(|l| l.eq_ignore_ascii_case(name))
+                    || short.is_some_and(|s| {
+                        // 0x05 stands for a leading 0xe5
+                        let first = if e[0] == 0x05 { ENTRY_DELETED } else { e[0] };
+                        s[0] == first && s[1..] == e[1..11]
+                    });
+
+                long.valid = false;
+
+                if matches && attr & ATTR_VOLUME_ID == 0 {
+                    let cluster = (le16(e, 20) as u32) << 16 | le16(e, 26) as u32;
+                    let dir = attr & ATTR_DIRECTORY != 0;
+
+                    return Ok(Entry {
+                        // ".." of a first level directory points at cluster 0
+                        cluster: if dir && cluster == 0 { self.root_cluster } else { cluster },
+                        size: le32(e, 28),
+                        dir,
+                    });
+                }
+            }
+        }
+
+        Err(Error::NotFound)
+    }
+
+    /// Resolves an absolute path
+    pub fn open(&mut self, path: &str) -> Result<Entry, Error> {
+        let mut entry = self.root();
+
+        for name in components(path) {
+            if !entry.dir {
+                return Err(Error::NotADirectory);
+            }
+
+            entry = self.find(&entry, name)?;
+        }
+
+        Ok(entry)
+    }
+
+    /// Reads the whole file into `buf`, cluster by cluster
+    pub fn read(&mut self, file: &Entry, buf: &mut [u8]) -> Result<usize, Error> {
+        if file.dir {
+            return Err(Error::IsADirectory);
+        }
+
+        let size = file.size as usize;
+        let cluster_size = BLOCK_SIZE << self.cluster_log2;
+        let buf = buf.get_mut(..size).ok_or(Error::TooLarge)?;
+
+        let mut cluster = file.cluster;
+        let mut rest = buf;
+
+        if !self.is_valid_cluster(cluster) && !rest.is_empty() {
+            return Err(Error::Corrupt);
+        }
+
+        while !rest.is_empty() {
+            let lba = self.cluster_lba(cluster);
+            let len = rest.len().min(cluster_size);
+            let whole = len / BLOCK_SIZE * BLOCK_SIZE;
+
+            let (chunk, tail) = rest.split_at_mut_checked(len).ok_or(Error::Corrupt)?;
+            let (blocks, partial) = chunk.split_at_mut_checked(whole).ok_or(Error::Corrupt)?;
+
+            self.dev.read_blocks(lba, blocks)?;
+
+            if !partial.is_empty() {
+                self.read_sector(lba + (whole / BLOCK_SIZE) as u64)?;
+                for (dst, &src) in partial.iter_mut().zip(self.buf.iter()) {
+                    *dst = src;
+                }
+            }
+
+            rest = tail;
+
+            if !rest.is_empty() {
+                cluster = self.next_cluster(cluster)?.ok_or(Error::Corrupt)?;
+            }
+        }
+
+        Ok(size)
+    }
+}
+
+impl<D: BlockDevice> FileSystem for Fat<D> {
+    fn read_file(&mut self, path: &str, buf: &mut [u8]) -> Result<usize, Error> {
+        let file = self.open(path)?;
+        self.read(&file, buf)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::format;
+    use std::path::PathBuf;
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    /// Geometry of the test images: bits, KiB, sectors per cluster. The
+    /// cluster counts, ~2000, ~8000 and ~80000, decide the FAT type.
+    fn geometry(kind: FatType) -> (u64, u64, u64) {
+        match kind {
+            FatType::Fat12 => (12, 1024, 1),
+            FatType::Fat16 => (16, 16384, 4),
+            FatType::Fat32 => (32, 40960, 1),
+        }
+    }
+
+    fn put16(b: &mut [u8], offset: usize, v: u64) {
+        b[offset..offset + 2].copy_from_slice(&(v as u16).to_le_bytes());
+    }
+
+    /// FAT image file formatted by mkfs.fat and filled by mtools, removed
+    /// when dropped
+    pub struct Image {
+        path: PathBuf,
+    }
+
+    impl Image {
+        /// Formats an empty file system of type `kind`
+        pub fn new(kind: FatType) -> Self {
+            use std::sync::atomic::{AtomicU32, Ordering};
+
+            static N: AtomicU32 = AtomicU32::new(0);
+
+            let (bits, kib, spc) = geometry(kind);
+            let image = Self {
+                path: std::env::temp_dir().join(format!(
+                    "boot-test-{}-{}.img",
+                    std::process::id(),
+                    N.fetch_add(1, Ordering::Relaxed)
+                )),
+            };
+
+            let out = std::process::Command::new("mkfs.fat")
+                .args(["-F", &bits.to_string(), "-s", &spc.to_string(), "-n", "BOOT", "-C"])
+                .arg(&image.path)
+                .arg(kib.to_string())
+                .output()
+                .expect("mkfs.fat (dosfstools) is needed for the FAT tests");
+
+            assert!(out.status.success(), "mkfs.fat: {}", String::from_utf8_lossy(&out.stderr));
+
+            image
+        }
+
+        /// Runs the mtools command `cmd` on the image
+        fn mtools(&self, cmd: &str, args: &[&std::ffi::OsStr]) {
+            let out = std::process::Command::new(cmd)
+                .env("MTOOLS_SKIP_CHECK", "1")
+                .arg("-i")
+                .arg(&self.path)
+                .args(args)
+                .output()
+                .expect("mtools is needed for the FAT tests");
+
+            assert!(out.status.success(), "{cmd}: {}", String::from_utf8_lossy(&out.stderr));
+        }
+
+        pub fn mkdir(&mut self, path: &str) -> &mut Self {
+            self.mtools("mmd", &[format!("::{path}").as_ref()]);
+            self
+        }
+
+        pub fn add(&mut self, path: &str, data: &[u8]) -> &mut Self {
+            let src = self.path.with_extension("src");
+            std::fs::write(&src, data).unwrap();
+
+            self.mtools("mcopy", &[src.as_ref(), format!("::{path}").as_ref()]);
+
+            let _ = std::fs::remove_file(&src);
+            self
+        }
+
+        pub fn data(&self) -> Vec<u8> {
+            std::fs::read(&self.path).unwrap()
+        }
+    }
+
+    impl Drop for Image {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    /// `len` bytes not repeating within a cluster
+    pub fn pattern(len: usize, seed: u32) -> Vec<u8> {
+        (0..len as u32).map(|i| (i.wrapping_mul(2654435761).wrapping_add(seed) >> 13) as u8).collect()
+    }
+
+    const KERNEL_SIZE: usize = 70000;
+
+    fn image(kind: FatType) -> Image {
+        let mut image = Image::new(kind);
+
+        image
+            .mkdir("/boot")
+            .mkdir("/boot/extlinux")
+            .add("/boot/kernel.elf", &pattern(KERNEL_SIZE, 1))
+            .add("/boot/extlinux/extlinux.conf", b"default linux\n")
+            .add("/boot/A very long file name.txt", b"long")
+            .add("/README", b"read me\n")
+            .add("/empty", b"")
+            .mkdir("/many");
+
+        // Enough entries to span several sectors of the fixed root directory
+        // and several clusters of a subdirectory
+        for i in 0..30 {
+            image.add(&format!("/root file {i}"), &[i as u8; 3]);
+            image.add(&format!("/many/file number {i}.bin"), &[i as u8; 5]);
+        }
+
+        image
+    }
+
+    fn read(fs: &mut Fat<Vec<u8>>, path: &str) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0u8; KERNEL_SIZE];
+        let size = fs.read_file(path, &mut buf)?;
+        buf.truncate(size);
+
+        Ok(buf)
+    }
+
+    #[test]
+    fn read_files() {
+        for kind in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let mut fs = Fat::mount(image(kind).data()).unwrap();
+            assert_eq!(fs.fat_type(), kind);
+
+            let cases: &[(&str, &[u8])] = &[
+                ("/boot/kernel.elf", &pattern(KERNEL_SIZE, 1)),
+                ("/BOOT/Kernel.ELF", &pattern(KERNEL_SIZE, 1)),
+                ("/boot/extlinux/extlinux.conf", b"default linux\n"),
+                ("boot//extlinux/./EXTLINUX.CONF", b"default linux\n"),
+                ("/boot/a very long FILE NAME.txt", b"long"),
+                // The generated 8.3 alias of the long name
+                ("/boot/AVERYL~1.TXT", b"long"),
+                ("/README", b"read me\n"),
+                ("/boot/extlinux/../../boot/../readme", b"read me\n"),
+                ("/empty", b""),
+                ("/root file 29", &[29; 3]),
+                ("/many/file number 0.bin", &[0; 5]),
+                ("/many/FILE NUMBER 29.BIN", &[29; 5]),
+            ];
+
+            for &(path, data) in cases {
+                assert_eq!(read(&mut fs, path).as_deref(), Ok(data), "{kind:?} {path}");
+            }
+        }
+    }
+
+    #[test]
+    fn lookup_errors() {
+        for kind in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let mut fs = Fat::mount(image(kind).data()).unwrap();
+            let mut small = [0u8; 100];
+
+            assert_eq!(read(&mut fs, "/boot/missing"), Err(Error::NotFound));
+            assert_eq!(read(&mut fs, "/boot/A very long file name"), Err(Error::NotFound));
+            assert_eq!(read(&mut fs, "/many/file number 30.bin"), Err(Error::NotFound));
+            assert_eq!(read(&mut fs, "/README/x"), Err(Error::NotADirectory));
+            assert_eq!(read(&mut fs, "/boot"), Err(Error::IsADirectory));
+            assert_eq!(read(&mut fs, "/"), Err(Error::IsADirectory));
+            assert_eq!(fs.read_file("/boot/kernel.elf", &mut small), Err(Error::TooLarge));
+        }
+    }
+
+    #[test]
+    fn not_fat() {
+        let mut image = image(FatType::Fat16).data();
+
+        image[0..512].fill(0);
+        assert_eq!(Fat::mount(image.clone()).err(), Some(Error::NoFileSystem));
+
+        let mut image = Image::new(FatType::Fat12).data();
+        put16(&mut image, 11, 4096);
+        assert_eq!(Fat::mount(image).err(), Some(Error::Unsupported));
+    }
+
+    /// The long name entries mcopy stores for `name` in the root directory,
+    /// last one first, and the 8.3 name they belong to
+    fn stored_long_name(name: &str) -> (Vec<[u8; 32]>, [u8; 11]) {
+        let mut image = Image::new(FatType::Fat16);
+        let data = image.add(&format!("/{name}"), b"x").data();
+
+        let root = (le16(&data, 14) as usize + 2 * le16(&data, 22) as usize) * 512;
+        let mut long = Vec::new();
+
+        for e in data[root..].chunks(32) {
+            if e[11] == ATTR_LONG_NAME {
+                long.push(e.try_into().unwrap());
+            } else if !long.is_empty() {
+                return (long, e[..11].try_into().unwrap());
+            }
+        }
+
+        panic!("no long name for {name}");
+    }
+
+    #[test]
+    fn long_name_sequence() {
+        let (entries, short) = stored_long_name("kernel-image-b.elf");
+        assert_eq!((entries.len(), &short), (2, b"KERNEL~1ELF"));
+
+        let mut long = LongName::new();
+        entries.iter().for_each(|e| long.push(e));
+        assert_eq!(long.get(&short), Some(&b"kernel-image-b.elf"[..]));
+        assert_eq!(long.get(b"KERNEL~2ELF"), None);
+
+        // An entry numbered 0 after a complete name
+        let mut zero = entries[1];
+        zero[0] = 0x20;
+        long.push(&zero);
+        assert_eq!(long.get(&short), None);
+
+        // Numbered 0 and marked as the last one
+        zero[0] = LFN_LAST;
+        long.push(&zero);
+        long.push(&entries[1]);
+        assert_eq!(long.get(&short), None);
+
+        // Out of order
+        let mut long = LongName::new();
+        long.push(&entries[1]);
+        long.push(&entries[0]);
+        assert_eq!(long.get(&short), None);
+
+        // Checksum of another short entry
+        let mut long = LongName::new();
+        let mut other = entries.clone();
+        other.iter_mut().for_each(|e| e[13] = checksum(b"KERNEL~2ELF"));
+        long.push(&entries[0]);
+        long.push(&other[1]);
+        assert_eq!(long.get(&short), None);
+    }
+}
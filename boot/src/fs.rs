@@ -0,0 +1,116 @@
+//! Read-only file system access shared by the FAT and ext2 readers
+
+use crate::block::{self, BlockDevice};
+use crate::part;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    Io(block::Error),
+    /// No supported file system found
+    NoFileSystem,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    /// The file doesn't fit the buffer
+    TooLarge,
+    /// Inconsistent on-disk structures
+    Corrupt,
+    /// Valid but uses a feature the reader doesn't implement
+    Unsupported,
+}
+
+impl From<block::Error> for Error {
+    fn from(e: block::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl Error {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Error::Io(e) => e.as_str(),
+            Error::NoFileSystem => "no file system",
+            Error::NotFound => "not found",
+            Error::NotADirectory => "not a directory",
+            Error::IsADirectory => "is a directory",
+            Error::TooLarge => "file too large",
+            Error::Corrupt => "corrupt file system",
+            Error::Unsupported => "unsupported file system feature",
+        }
+    }
+}
+
+pub trait FileSystem {
+    /// Reads the file at the absolute `path` into `buf`, returns its size
+    fn read_file(&mut self, path: &str, buf: &mut [u8]) -> Result<usize, Error>;
+}
+
+pub fn le16(b: &[u8], offset: usize) -> u16 {
+    match b.get(offset..offset + 2) {
+        Some(&[b0, b1]) => u16::from_le_bytes([b0, b1]),
+        _ => 0,
+    }
+}
+
+pub fn le32(b: &[u8], offset: usize) -> u32 {
+    match b.get(offset..offset + 4) {
+        Some(&[b0, b1, b2, b3]) => u32::from_le_bytes([b0, b1, b2, b3]),
+        _ => 0,
+    }
+}
+
+pub fn le64(b: &[u8], offset: usize) -> u64 {
+    le32(b, offset) as u64 | (le32(b, offset + 4) as u64) << 32
+}
+
+/// The non-empty components of a '/' separated path, "." dropped
+pub fn components(path: &str) -> impl Iterator<Item = &[u8]> {
+    path.as_bytes()
+        .split(|&c| c == b'/')
+        .filter(|c| !c.is_empty() && *c != b".")
+}
+
+/// Reads `path` from the file system on `dev`
+///
+/// Takes a trait object so each file system gets instantiated only once.
+#[allow(unused_variables)]
+fn read_from(dev: &mut dyn BlockDevice, path: &str, buf: &mut [u8]) -> Result<usize, Error> {
+    #[cfg(feature = "fat")]
+    match crate::fat::Fat::mount(&mut *dev) {
+        Err(Error::NoFileSystem) => {}
+        fs => return fs?.read_file(path, buf),
+    }
+
+    #[cfg(feature = "ext2")]
+    match crate::ext2::Ext2::mount(&mut *dev) {
+        Err(Error::NoFileSystem) => {}
+        fs => return fs?.read_file(path, buf),
+    }
+
+    Err(Error::NoFileSystem)
+}
+
+/// Reads `path` from the first partition of `dev` holding a supported file
+/// system, or from the whole device if it has no partition table
+pub fn load<D: BlockDevice>(dev: &mut D, path: &str, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut n = 0;
+
+    while let Some(p) = part::nth(dev, n)? {
+        n += 1;
+
+        if p.blocks == 0 {
+            continue;
+        }
+
+        match read_from(&mut p.on(&mut *dev), path, buf) {
+            Err(Error::NoFileSystem) => continue,
+            result => return result,
+        }
+    }
+
+    if n > 0 {
+        return Err(Error::NoFileSystem);
+    }
+
+    read_from(dev, path, buf)
+}
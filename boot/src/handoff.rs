@@ -0,0 +1,144 @@
+//! Boot information passed to the kernel
+//!
+//! The kernel is entered with a0 = hart id, a1 = device tree address (0 if
+//! none) and a2 = address of [`BootInfo`]. The structure and the command line
+//! live in the handoff page right below the bootloader's stack at the end of
+//! DRAM, out of reach of the images staged in DRAM by `loadz`, `sfread` and
+//! `load`. The kernel starts on that stack, so it must move off it or use
+//! less than [`crate::dram::STACK_SIZE`] of it while it needs the page.
+//!
+//! The kernel owns the watchdog. For an unconfirmed A/B slot the bootloader
+//! leaves it running with the period in [`BootInfo::watchdog_ms`], so a
+//! kernel that hangs before confirming resets the board and uses up a try.
+//! The kernel has to feed it or turn it off, normally right after confirming.
+
+/// Size of the handoff page
+pub const PAGE_SIZE: u64 = 0x1000;
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"OS5BOOT\0");
+pub const BOOT_INFO_VERSION: u32 = 4;
+
+/// [`BootInfo::slot`] when the kernel wasn't booted from an A/B slot
+pub const NO_SLOT: u32 = u32::MAX;
+
+/// Offset of the command line in the handoff page
+const CMDLINE_OFFSET: u64 = 0x100;
+/// Room for the command line and its NUL up to the end of the page
+const CMDLINE_MAX: usize = (PAGE_SIZE - CMDLINE_OFFSET) as usize - 1;
+
+/// Keep in sync with kernel/src/handoff.rs
+#[repr(C)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    /// Size of this structure, grows with new versions
+    pub size: u32,
+    pub dram_base: u64,
+    pub dram_size: u64,
+    pub fdt: u64,
+    pub initrd_start: u64,
+    pub initrd_size: u64,
+    /// NUL terminated, 0 if there's no command line
+    pub cmdline: u64,
+    /// A/B slot the kernel was booted from, 0 = A, 1 = B or [`NO_SLOT`]
+    pub slot: u32,
+    /// Written by the kernel to the 32-bit register at `confirm_addr` once
+    /// it's up, which confirms the slot
+    pub confirm_value: u32,
+    /// 0 if there's nothing to confirm
+    pub confirm_addr: u64,
+    /// Boots since power-on including this one, the rest is about the
+    /// previous boot and 0 if there was none
+    pub boot_count: u32,
+    pub last_stage: u16,
+    pub last_error: u16,
+    pub last_mcause: u64,
+    pub last_mepc: u64,
+    /// Boot diagnostic record to report fatal errors in, see diag.rs
+    pub diag_addr: u64,
+    /// Period of the watchdog left running for the kernel, 0 if it's off
+    pub watchdog_ms: u32,
+}
+
+/// Start of the handoff page, needs DRAM
+pub fn addr() -> u64 {
+    crate::dram::stack_top() - crate::dram::STACK_SIZE - PAGE_SIZE
+}
+
+#[cfg(not(any(test, feature = "sim")))]
+pub fn boot_info() -> &'static mut BootInfo {
+    unsafe { &mut *(addr() as *mut BootInfo) }
+}
+
+/// The host builds have no DRAM, each thread gets a block of its own
+#[cfg(any(test, feature = "sim"))]
+pub fn boot_info() -> &'static mut BootInfo {
+    std::thread_local! {
+        static INFO: *mut BootInfo = std::boxed::Box::leak(std::boxed::Box::new(unsafe { core::mem::zeroed() }));
+    }
+
+    unsafe { &mut *INFO.with(|info| *info) }
+}
+
+/// Sets up an empty boot information block, needs DRAM
+pub fn init() {
+    let last = crate::diag::last();
+
+    *boot_info() = BootInfo {
+        magic: BOOT_INFO_MAGIC,
+        version: BOOT_INFO_VERSION,
+        size: size_of::<BootInfo>() as u32,
+        dram_base: crate::dram::dram_base() as u64,
+        dram_size: crate::dram::dram_size(),
+        fdt: 0,
+        initrd_start: 0,
+        initrd_size: 0,
+        cmdline: 0,
+        slot: NO_SLOT,
+        confirm_value: 0,
+        confirm_addr: 0,
+        boot_count: last.map_or(1, |r| r.boots as u32 + 1),
+        last_stage: last.map_or(0, |r| r.stage as u16),
+        last_error: last.map_or(0, |r| r.error as u16),
+        last_mcause: last.map_or(0, |r| r.mcause64()),
+        last_mepc: last.map_or(0, |r| r.mepc as u64),
+        diag_addr: crate::diag::addr(),
+        watchdog_ms: 0,
+    };
+}
+
+/// Last steps before the kernel takes over, starts the watchdog it gets
+/// handed
+pub fn hand_over() -> &'static BootInfo {
+    let info = boot_info();
+
+    crate::diag::set_stage(crate::diag::Stage::Kernel);
+
+    if info.watchdog_ms != 0 {
+        crate::wdt::WDT.enable(info.watchdog_ms);
+    }
+
+    info
+}
+
+/// Forgets the device tree, initrd and command line of an earlier attempt
+pub fn clear_images() {
+    let info = boot_info();
+
+    info.fdt = 0;
+    info.initrd_start = 0;
+    info.initrd_size = 0;
+    info.cmdline = 0;
+}
+
+/// Copies `cmdline` next to the boot information, truncated if it doesn't fit
+pub fn set_cmdline(cmdline: &str) {
+    let len = cmdline.len().min(CMDLINE_MAX);
+    let dst = (addr() + CMDLINE_OFFSET) as *mut u8;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(cmdline.as_ptr(), dst, len);
+        *dst.add(len) = 0;
+    }
+
+    boot_info().cmdline = dst as u64;
+}
@@ -1,65 +1,595 @@
+use crate::time::{TIMER_FREQ, timer_csr};
+
+/// A register width
+pub trait Word: Copy {
+    const BYTES: u8;
+
+    fn to_u64(self) -> u64;
+    fn from_u64(v: u64) -> Self;
+}
+
+macro_rules! word {
+    ($($t:ty),*) => {
+        $(impl Word for $t {
+            const BYTES: u8 = size_of::<$t>() as u8;
+
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+
+            fn from_u64(v: u64) -> Self {
+                v as $t
+            }
+        })*
+    };
+}
+
+word!(u8, u16, u32, u64);
+
+/// Where register accesses end up. Drivers go through [`Bus`], which is
+/// [`Volatile`] on the target, [`crate::trace::Traced`] with the `mmio-trace`
+/// feature and the simulated register file of [`crate::sim`] in the host
+/// builds, i.e. tests and the `sim` feature.
+pub trait Backend {
+    unsafe fn read<T: Word>(addr: u64) -> T;
+    unsafe fn write<T: Word>(addr: u64, v: T);
+}
+
+/// Volatile loads and stores at the register address
+pub struct Volatile;
+
+impl Backend for Volatile {
+    #[inline(always)]
+    unsafe fn read<T: Word>(addr: u64) -> T {
+        unsafe { core::ptr::read_volatile(addr as *const T) }
+    }
+
+    #[inline(always)]
+    unsafe fn write<T: Word>(addr: u64, v: T) {
+        unsafe { core::ptr::write_volatile(addr as *mut T, v) }
+    }
+}
+
+#[cfg(not(any(test, feature = "sim", feature = "mmio-trace")))]
+pub type Bus = Volatile;
+
+#[cfg(all(feature = "mmio-trace", not(any(test, feature = "sim"))))]
+pub type Bus = crate::trace::Traced;
+
+#[cfg(any(test, feature = "sim"))]
+pub type Bus = crate::sim::Sim;
+
 pub unsafe fn write32(addr: u64, v: u32) {
-    let p = addr as *mut u32;
-    unsafe { core::ptr::write_volatile(p, v) };
+    unsafe { Bus::write(addr, v) };
 }
 
 pub unsafe fn read32(addr: u64) -> u32 {
-    let p = addr as *const u32;
-    unsafe { core::ptr::read_volatile(p) }
+    unsafe { Bus::read(addr) }
 }
 
-#[derive(Clone, Copy)]
-#[must_use]
-pub struct Reg32 {
-    p: *mut u32,
-    v: u32,
+pub unsafe fn write8(addr: u64, v: u8) {
+    unsafe { Bus::write(addr, v) };
 }
 
-impl Reg32 {
-    pub fn zero(addr: u64) -> Self {
-        let p = addr as *mut u32;
-        Self { p, v: 0 }
+pub unsafe fn read8(addr: u64) -> u8 {
+    unsafe { Bus::read(addr) }
+}
+
+/// A register that didn't read as expected in time, with its address and
+/// the last value read
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Timeout {
+    pub addr: u64,
+    pub value: u32,
+}
+
+impl Timeout {
+    /// Reports the timeout on the console, `what` naming what was awaited
+    pub fn print(self, what: &str) {
+        crate::uart::printf!("%s timeout: 0x%x = 0x%x\r\n", what, self.addr, self.value);
     }
+}
 
-    pub unsafe fn read(addr: u64) -> Self {
-        let p = addr as *mut u32;
-        let v = unsafe { core::ptr::read_volatile(p) };
-        Self { p, v }
+/// The system timer `timeout_us` from now
+pub fn deadline(timeout_us: u64) -> u64 {
+    (unsafe { timer_csr() }) + timeout_us * (TIMER_FREQ / 1_000_000)
+}
+
+/// Reads the register at `addr` until `done` holds for its value, which is
+/// returned, or the system timer passes `deadline`
+pub unsafe fn poll_until(addr: u64, deadline: u64, done: impl Fn(u32) -> bool) -> Result<u32, Timeout> {
+    loop {
+        let value = unsafe { read32(addr) };
+        if done(value) {
+            return Ok(value);
+        }
+        if unsafe { timer_csr() } > deadline {
+            return Err(Timeout { addr, value });
+        }
     }
+}
+
+/// [`poll_until`] `(value & mask) == expected`, for at most `timeout_us`.
+/// Out of line, it's what most drivers wait with.
+#[inline(never)]
+pub unsafe fn wait_mask(addr: u64, mask: u32, expected: u32, timeout_us: u64) -> Result<u32, Timeout> {
+    unsafe { poll_until(addr, deadline(timeout_us), |v| v & mask == expected) }
+}
+
+/// Declares a register accessor for one width. Fields are checked at compile
+/// time to be within the register, values are masked to their field.
+macro_rules! reg_type {
+    ($(#[$m:meta])* $name:ident, $t:ty) => {
+        $(#[$m])*
+        #[derive(Clone, Copy)]
+        #[must_use]
+        pub struct $name {
+            p: *mut $t,
+            v: $t,
+        }
 
-    pub unsafe fn set_field<const SHIFT: usize, const LEN: usize>(mut self, v: u32) -> Self {
-        let mask = !((1u32 << LEN).wrapping_sub(1) << SHIFT);
-        self.v &= mask;
-        self.v |= v << SHIFT;
+        // Not every width uses every accessor
+        #[allow(dead_code)]
+        impl $name {
+            const BITS: usize = <$t>::BITS as usize;
+
+            const fn mask<const SHIFT: usize, const LEN: usize>() -> $t {
+                const { assert!(LEN > 0 && SHIFT + LEN <= Self::BITS, "field outside the register") };
+
+                <$t>::MAX >> (Self::BITS - LEN) << SHIFT
+            }
+
+            pub fn zero(addr: u64) -> Self {
+                let p = addr as *mut $t;
+                Self { p, v: 0 }
+            }
+
+            pub unsafe fn read(addr: u64) -> Self {
+                let p = addr as *mut $t;
+                let v = unsafe { Bus::read(addr) };
+                Self { p, v }
+            }
+
+            /// Reads the register at `addr`, passes it through `f` and
+            /// writes the result back
+            pub unsafe fn modify(addr: u64, f: impl FnOnce(Self) -> Self) {
+                unsafe { f(Self::read(addr)).write() }
+            }
+
+            /// Bits of `v` beyond `LEN` are dropped
+            pub unsafe fn set_field<const SHIFT: usize, const LEN: usize>(mut self, v: $t) -> Self {
+                let mask = Self::mask::<SHIFT, LEN>();
+                self.v &= !mask;
+                self.v |= (v << SHIFT) & mask;
+                self
+            }
+
+            pub unsafe fn clear_field<const SHIFT: usize, const LEN: usize>(self) -> Self {
+                unsafe { self.set_field::<SHIFT, LEN>(0) }
+            }
+
+            pub unsafe fn and(mut self, v: $t) -> Self {
+                self.v &= v;
+                self
+            }
+
+            pub unsafe fn or(mut self, v: $t) -> Self {
+                self.v |= v;
+                self
+            }
+
+            /// Re-reads the register until bit `SHIFT` reads `v`, for at
+            /// most `timeout_us`
+            pub unsafe fn wait_bit_timeout<const SHIFT: usize>(self, v: bool, timeout_us: u64) -> Result<(), Timeout> {
+                unsafe { self.wait_field::<SHIFT, 1>(v as $t, timeout_us) }
+            }
+
+            /// Re-reads the register until the field reads `v`, for at most
+            /// `timeout_us`
+            pub unsafe fn wait_field<const SHIFT: usize, const LEN: usize>(
+                mut self,
+                v: $t,
+                timeout_us: u64,
+            ) -> Result<(), Timeout> {
+                let deadline = deadline(timeout_us);
+
+                while unsafe { self.field::<SHIFT, LEN>() } != v {
+                    if unsafe { timer_csr() } > deadline {
+                        return Err(Timeout {
+                            addr: self.p as u64,
+                            value: self.v as u32,
+                        });
+                    }
+
+                    self.v = unsafe { Bus::read(self.p as u64) };
+                }
+
+                Ok(())
+            }
+
+            pub fn is_bit_set<const SHIFT: usize>(&self) -> bool {
+                self.v & Self::mask::<SHIFT, 1>() != 0
+            }
+
+            pub unsafe fn field<const SHIFT: usize, const LEN: usize>(&self) -> $t {
+                (self.v & Self::mask::<SHIFT, LEN>()) >> SHIFT
+            }
+
+            pub unsafe fn write(self) {
+                unsafe { Bus::write(self.p as u64, self.v) }
+            }
+        }
+    };
+}
+
+reg_type!(Reg32, u32);
+reg_type!(Reg16, u16);
+reg_type!(Reg8, u8);
+
+impl Reg32 {
+    /// Sets a field declared with [`register!`]. Enumerated values fit their
+    /// field by construction, numbers are checked in debug builds, which the
+    /// host tests are, and masked to the field in release builds.
+    #[track_caller]
+    pub unsafe fn set<F: RegField>(mut self, _: F, v: F::Value) -> Self {
+        debug_assert!(v.bits() <= F::MASK >> F::SHIFT, "value 0x{:x} doesn't fit {} bits", v.bits(), F::LEN);
+
+        self.v = self.v & !F::MASK | (v.bits() << F::SHIFT) & F::MASK;
         self
     }
 
-    pub unsafe fn and(mut self, v: u32) -> Self {
-        self.v &= v;
-        self
+    pub fn get<F: RegField>(&self, _: F) -> u32 {
+        (self.v & F::MASK) >> F::SHIFT
+    }
+
+    /// Re-reads the register until field `f` reads `v`, for at most
+    /// `timeout_us`
+    pub unsafe fn wait<F: RegField>(self, _: F, v: u32, timeout_us: u64) -> Result<(), Timeout> {
+        unsafe { wait_mask(self.p as u64, F::MASK, v << F::SHIFT & F::MASK, timeout_us) }.map(drop)
     }
+}
+
+/// A register field declared with [`register!`]
+pub trait RegField: Copy {
+    const SHIFT: u32;
+    /// 1..=32
+    const LEN: u32;
+    type Value: FieldValue;
+
+    const MASK: u32 = u32::MAX >> (32 - Self::LEN) << Self::SHIFT;
+}
+
+/// What a field holds: plain numbers or the values of a [`bitfield!`] enum
+pub trait FieldValue: Copy {
+    /// Names and values of the enumerated values, empty for plain numbers
+    const VALUES: &'static [(&'static str, u32)];
+
+    fn bits(self) -> u32;
+}
+
+impl FieldValue for u32 {
+    const VALUES: &'static [(&'static str, u32)] = &[];
 
-    pub unsafe fn or(mut self, v: u32) -> Self {
-        self.v |= v;
+    fn bits(self) -> u32 {
         self
     }
+}
+
+/// A field as declared, for listing and checking the definitions
+pub struct FieldDef {
+    pub name: &'static str,
+    pub shift: u32,
+    pub len: u32,
+    pub values: &'static [(&'static str, u32)],
+}
 
-    pub unsafe fn wait_bit<const SHIFT: usize>(mut self, v: bool) {
-        while self.is_bit_set::<SHIFT>() != v {
-            self.v = unsafe { core::ptr::read_volatile(self.p) };
+impl FieldDef {
+    pub const fn new<F: RegField>(name: &'static str) -> Self {
+        Self {
+            name,
+            shift: F::SHIFT,
+            len: F::LEN,
+            values: <F::Value as FieldValue>::VALUES,
         }
     }
+}
+
+/// A register as declared with [`register!`]
+pub struct RegisterDef {
+    pub name: &'static str,
+    pub addr: u64,
+    pub fields: &'static [FieldDef],
+}
+
+/// Whether `fields` fit in 32 bits without overlapping, and their enumerated
+/// values in their fields
+pub const fn fields_valid(fields: &[FieldDef]) -> bool {
+    let mut used = 0u32;
+
+    let mut i = 0;
+    while i < fields.len() {
+        let f = &fields[i];
+        if f.len == 0 || f.shift + f.len > 32 {
+            return false;
+        }
 
-    pub fn is_bit_set<const SHIFT: usize>(&self) -> bool {
-        (self.v & (1 << SHIFT)) != 0
+        let mask = u32::MAX >> (32 - f.len);
+        if used & mask << f.shift != 0 {
+            return false;
+        }
+        used |= mask << f.shift;
+
+        let mut j = 0;
+        while j < f.values.len() {
+            if f.values[j].1 & !mask != 0 {
+                return false;
+            }
+            j += 1;
+        }
+
+        i += 1;
     }
 
-    pub unsafe fn field<const SHIFT: usize, const LEN: usize>(&self) -> u32 {
-        let mask = (1u32 << LEN).wrapping_sub(1);
-        (self.v >> SHIFT) & mask
+    true
+}
+
+/// Declares the registers of a peripheral as a module with `BASE`, one module
+/// per register with its `OFFSET`, `ADDR`, `read()`, `zero()` and fields, and
+/// `REGISTERS` listing it all. A field is `NAME: shift, len`, followed by
+/// `=> Type` for a [`bitfield!`] enum. Fields that overlap, don't fit the
+/// register or can't hold their values fail to compile.
+///
+/// ```ignore
+/// register! {
+///     mod regs @ CCU_BASE {
+///         RISCV_CLK @ 0x0d00 {
+///             CLK_SEL: 24, 3 => RiscvClkSel,
+///             DIV_CFG: 0, 5,
+///         }
+///     }
+/// }
+///
+/// regs::RISCV_CLK::read().set(regs::RISCV_CLK::CLK_SEL, RiscvClkSel::PllCpu).write();
+/// ```
+macro_rules! register {
+    (
+        $(#[$pm:meta])*
+        $vis:vis mod $periph:ident @ $base:tt {
+            $(
+                $(#[$rm:meta])*
+                $reg:ident @ $offset:literal {
+                    $(
+                        $(#[$fm:meta])*
+                        $field:ident: $shift:literal, $len:literal $(=> $ty:ty)?
+                    ),* $(,)?
+                }
+            )*
+        }
+    ) => {
+        $(#[$pm])*
+        #[allow(dead_code)]
+        $vis mod $periph {
+            #[allow(unused_imports)]
+            use super::*;
+
+            pub const BASE: u64 = $base;
+
+            $(
+                $(#[$rm])*
+                #[allow(non_snake_case)]
+                pub mod $reg {
+                    #[allow(unused_imports)]
+                    use super::*;
+
+                    pub const OFFSET: u64 = $offset;
+                    pub const ADDR: u64 = BASE + OFFSET;
+
+                    pub unsafe fn read() -> $crate::mmio::Reg32 {
+                        unsafe { $crate::mmio::Reg32::read(ADDR) }
+                    }
+
+                    pub fn zero() -> $crate::mmio::Reg32 {
+                        $crate::mmio::Reg32::zero(ADDR)
+                    }
+
+                    $(
+                        $(#[$fm])*
+                        #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+                        #[derive(Clone, Copy)]
+                        pub struct $field;
+
+                        impl $crate::mmio::RegField for $field {
+                            const SHIFT: u32 = $shift;
+                            const LEN: u32 = $len;
+                            type Value = $crate::mmio::register!(@value $($ty)?);
+                        }
+                    )*
+
+                    pub const FIELDS: &[$crate::mmio::FieldDef] = &[
+                        $($crate::mmio::FieldDef::new::<$field>(stringify!($field)),)*
+                    ];
+
+                    const _: () = assert!(
+                        $crate::mmio::fields_valid(FIELDS),
+                        concat!("bad field in ", stringify!($reg))
+                    );
+                }
+            )*
+
+            pub const REGISTERS: &[$crate::mmio::RegisterDef] = &[
+                $($crate::mmio::RegisterDef {
+                    name: stringify!($reg),
+                    addr: $reg::ADDR,
+                    fields: $reg::FIELDS,
+                },)*
+            ];
+        }
+    };
+    (@value) => { u32 };
+    (@value $ty:ty) => { $ty };
+}
+
+pub(crate) use register;
+
+/// Declares the enumerated values of a register field, for use as its type
+/// in [`register!`]
+macro_rules! bitfield {
+    (
+        $(#[$m:meta])*
+        $vis:vis enum $name:ident {
+            $($(#[$vm:meta])* $variant:ident = $value:literal),* $(,)?
+        }
+    ) => {
+        $(#[$m])*
+        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+        $vis enum $name {
+            $($(#[$vm])* $variant = $value),*
+        }
+
+        impl $crate::mmio::FieldValue for $name {
+            const VALUES: &'static [(&'static str, u32)] = &[$((stringify!($variant), $value)),*];
+
+            fn bits(self) -> u32 {
+                self as u32
+            }
+        }
+    };
+}
+
+pub(crate) use bitfield;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+
+    register! {
+        mod test @ 0x1000 {
+            REG @ 0x10 {
+                HIGH: 28, 4,
+                FLAG: 8, 1,
+                LOW: 0, 3,
+            }
+        }
+    }
+
+    /// Compare testdata/registers.txt with the user manual when adding or
+    /// changing definitions
+    #[test]
+    fn register_definitions() {
+        sim::assert_trace(&sim::registers(), include_str!("../testdata/registers.txt"), "registers", "registers.txt");
+    }
+
+    #[test]
+    fn set_and_get_fields() {
+        sim::reset();
+        sim::set(test::REG::ADDR, 0x0fff_fff0);
+
+        let reg = unsafe { test::REG::read().set(test::REG::HIGH, 0xa).set(test::REG::LOW, 7).set(test::REG::FLAG, 0) };
+        assert_eq!((reg.get(test::REG::HIGH), reg.get(test::REG::FLAG), reg.get(test::REG::LOW)), (0xa, 0, 7));
+
+        unsafe { reg.write() };
+        assert_eq!(sim::get(test::REG::ADDR), 0xafff_fef7);
+    }
+
+    #[test]
+    #[should_panic(expected = "value 0x8 doesn't fit 3 bits")]
+    fn oversize_values_are_caught() {
+        let _ = unsafe { test::REG::zero().set(test::REG::LOW, 8) };
+    }
+
+    fn log() -> Vec<(sim::Op, u64, u64, u8)> {
+        sim::take_log().iter().map(|a| (a.op, a.addr, a.value, a.width)).collect()
+    }
+
+    #[test]
+    fn access_widths() {
+        use sim::Op::*;
+
+        sim::reset();
+        sim::set(0x2000, 0xfff0);
+        sim::set(0x2010, 0xff);
+
+        unsafe { Reg16::read(0x2000).set_field::<4, 8>(0xab).write() };
+        unsafe { Reg8::modify(0x2010, |r| r.clear_field::<4, 2>()) };
+        unsafe { Reg32::zero(0x2020).or(0x8000_0001).write() };
+
+        assert_eq!(
+            log(),
+            [
+                (Read, 0x2000, 0xfff0, 2),
+                (Write, 0x2000, 0xfab0, 2),
+                (Read, 0x2010, 0xff, 1),
+                (Write, 0x2010, 0xcf, 1),
+                (Write, 0x2020, 0x8000_0001, 4),
+            ]
+        );
     }
 
-    pub unsafe fn write(self) {
-        unsafe { core::ptr::write_volatile(self.p, self.v) }
+    #[test]
+    fn field_masking() {
+        sim::reset();
+
+        // Bits of the value beyond the field are dropped, the rest of the
+        // register is kept
+        let cases = [
+            (0x0000_0000, 0x0000_0001),
+            (0xffff_ffff, 0xffff_fffd),
+            (0x0000_0004, 0x0000_0005),
+        ];
+
+        for (old, new) in cases {
+            sim::set(0x2000, old);
+            unsafe { Reg32::modify(0x2000, |r| r.set_field::<0, 2>(5)) };
+            assert_eq!(sim::get(0x2000), new, "{old:#x}");
+
+            let reg = unsafe { Reg32::read(0x2000) };
+            assert_eq!((unsafe { reg.field::<0, 2>() }, reg.is_bit_set::<2>()), (1, old & 4 != 0));
+        }
+
+        let reg = unsafe { Reg32::zero(0).or(0xffff_ffff).clear_field::<8, 4>() };
+        assert_eq!(unsafe { reg.and(0xffff_00ff).field::<0, 32>() }, 0xffff_00ff);
+        assert_eq!(unsafe { reg.field::<8, 8>() }, 0xf0);
+        assert_eq!(unsafe { Reg8::zero(0).or(0xff).set_field::<6, 2>(0).field::<0, 8>() }, 0x3f);
+    }
+
+    #[test]
+    fn wait_field() {
+        sim::reset();
+
+        // Done on the third read
+        sim::script(0x2000, &[0x00, 0x0f, 0x1f]);
+        assert_eq!(unsafe { Reg32::read(0x2000).wait_field::<4, 2>(1, 100) }, Ok(()));
+        assert_eq!(sim::take_log().len(), 3);
+
+        sim::set(0x2010, 0x30);
+        let start = sim::ticks();
+        let result = unsafe { Reg16::read(0x2010).wait_field::<4, 2>(1, 100) };
+        let waited = (sim::ticks() - start) / (TIMER_FREQ / 1_000_000);
+
+        assert_eq!(result, Err(Timeout { addr: 0x2010, value: 0x30 }));
+        assert!((100..110).contains(&waited), "{waited} us");
+
+        assert_eq!(unsafe { Reg8::read(0x2010).wait_bit_timeout::<5>(true, 10) }, Ok(()));
+        assert_eq!(unsafe { Reg8::read(0x2010).wait_bit_timeout::<5>(false, 10) }, Err(Timeout { addr: 0x2010, value: 0x30 }));
+    }
+
+    #[test]
+    fn field_checks() {
+        let field = |name, shift, len| FieldDef {
+            name,
+            shift,
+            len,
+            values: &[],
+        };
+
+        assert!(fields_valid(&[field("A", 0, 16), field("B", 16, 16)]));
+        assert!(!fields_valid(&[field("A", 0, 16), field("B", 15, 2)]));
+        assert!(!fields_valid(&[field("A", 30, 3)]));
+        assert!(!fields_valid(&[field("A", 0, 0)]));
+        assert!(!fields_valid(&[FieldDef {
+            values: &[("Big", 4)],
+            ..field("A", 4, 2)
+        }]));
     }
 }
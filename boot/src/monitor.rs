@@ -0,0 +1,1117 @@
+use crate::time::{TIMER_FREQ, timer_csr};
+use crate::uart::{fprintf, printf};
+
+const LINE_MAX: usize = 80;
+const ARGS_MAX: usize = 4;
+
+/// Column of the command descriptions in the help text
+const HELP_COLUMN: usize = 38;
+
+/// Command synopsis and description are separated by a tab
+const HELP: &str = "\
+md[.b|.w|.l|.q] <addr> [count]\tdisplay memory\r
+mw[.b|.w|.l|.q] <addr> <value> [count]\twrite memory\r
+clk\tdump the clock tree, one key=value line per clock\r
+dram\tDRAM size and parameters\r
+go <addr>\tjump to addr\r
+bootelf <addr>\tload and run the ELF image at addr\r
+reset\treset the board\r
+boot\trun the default boot command\r
+";
+
+#[cfg(feature = "zmodem")]
+const HELP_ZMODEM: &str = "\
+loadz <addr>\treceive a file via ZMODEM\r
+";
+
+#[cfg(feature = "sdcard")]
+const HELP_SDCARD: &str = "\
+mmcinfo\tinitialize and describe the SD card\r
+mmcread <addr> <block> <count>\tread SD card blocks to addr\r
+";
+
+#[cfg(feature = "fs")]
+const HELP_FS: &str = "\
+load <addr> <path>\tload a file from the SD card to addr\r
+sysboot [label]\tboot an entry of the boot configuration\r
+";
+
+#[cfg(feature = "cpufreq")]
+const HELP_CPUFREQ: &str = "\
+cpufreq [MHz]\tshow or set the CPU clock, MHz in decimal\r
+";
+
+#[cfg(feature = "mmio-trace")]
+const HELP_MMIOTRACE: &str = "\
+mmiotrace\tprint the register accesses so far, one per line\r
+";
+
+#[cfg(feature = "spinor")]
+const HELP_SPINOR: &str = "\
+sfprobe\tidentify the SPI NOR flash\r
+sfread <addr> <offset> <len>\tread SPI NOR flash to addr\r
+sfwrite <addr> <offset> <len>\tprogram erased SPI NOR flash from addr\r
+sferase <offset> <len>\terase 4 KiB SPI NOR flash sectors\r
+";
+
+#[cfg(feature = "slots")]
+const HELP_SLOTS: &str = "\
+slot [a|b]\tshow the A/B slots, or make one active\r
+";
+
+const HELP_NOTES: &str = "\
+commands can be chained with ';'\r
+numbers are hex, with or without the 0x prefix\r
+";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Width {
+    B = 1,
+    W = 2,
+    L = 4,
+    Q = 8,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Command<'a> {
+    Help,
+    Md { width: Width, addr: u64, count: u64 },
+    Mw { width: Width, addr: u64, value: u64, count: u64 },
+    Clk,
+    #[cfg(feature = "cpufreq")]
+    CpuFreq { mhz: Option<u64> },
+    #[cfg(feature = "mmio-trace")]
+    MmioTrace,
+    Dram,
+    #[cfg(feature = "zmodem")]
+    Loadz { addr: u64 },
+    Go { addr: u64 },
+    BootElf { addr: u64 },
+    #[cfg(feature = "sdcard")]
+    MmcInfo,
+    #[cfg(feature = "sdcard")]
+    MmcRead { addr: u64, lba: u64, count: u64 },
+    #[cfg(feature = "fs")]
+    Load { addr: u64, path: &'a str },
+    #[cfg(feature = "fs")]
+    Sysboot { label: Option<&'a str> },
+    #[cfg(feature = "spinor")]
+    SfProbe,
+    #[cfg(feature = "spinor")]
+    SfRead { addr: u64, offset: u64, len: u64 },
+    #[cfg(feature = "spinor")]
+    SfWrite { addr: u64, offset: u64, len: u64 },
+    #[cfg(feature = "spinor")]
+    SfErase { offset: u64, len: u64 },
+    #[cfg(feature = "slots")]
+    Slot { active: Option<crate::slot::Slot> },
+    Reset,
+    Boot,
+    /// Holds on to `'a` in builds without the commands taking a path or a
+    /// label, can't be constructed
+    #[cfg(not(feature = "fs"))]
+    Never(core::convert::Infallible, core::marker::PhantomData<&'a str>),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    Empty,
+    UnknownCommand,
+    /// Wrong arguments, holds the usage line of the command
+    Usage(&'static str),
+}
+
+/// `str::split_once` for an ASCII separator. The `char` pattern searcher
+/// keeps a slice index panic path around, which doesn't link without a panic
+/// handler.
+pub fn split_once(s: &str, sep: u8) -> Option<(&str, &str)> {
+    let i = s.bytes().position(|b| b == sep)?;
+    let (head, tail) = s.split_at_checked(i)?;
+
+    Some((head, tail.get(1..)?))
+}
+
+pub fn parse_number(s: &str) -> Option<u64> {
+    let s = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+
+    let mut v = 0u64;
+    for c in s.bytes() {
+        let digit = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => return None,
+        };
+
+        v = (v << 4) | digit as u64;
+    }
+
+    Some(v)
+}
+
+/// Decimal counterpart of [`parse_number`]
+pub fn parse_decimal(s: &str) -> Option<u64> {
+    if s.is_empty() || s.len() > 19 {
+        return None;
+    }
+
+    let mut v = 0u64;
+    for c in s.bytes() {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+
+        v = v * 10 + (c - b'0') as u64;
+    }
+
+    Some(v)
+}
+
+pub fn parse(line: &str) -> Result<Command<'_>, ParseError> {
+    let mut args = [""; ARGS_MAX];
+    let mut argc = 0;
+
+    for arg in line.split_ascii_whitespace() {
+        if argc == ARGS_MAX {
+            // Too many arguments, left for the usage checks below
+            argc += 1;
+            break;
+        }
+
+        args[argc] = arg;
+        argc += 1;
+    }
+
+    let [name, a1, a2, a3] = args;
+
+    if argc == 0 {
+        return Err(ParseError::Empty);
+    }
+
+    let (name, suffix) = split_once(name, b'.').unwrap_or((name, "l"));
+    let width = match suffix {
+        "b" => Some(Width::B),
+        "w" => Some(Width::W),
+        "l" => Some(Width::L),
+        "q" => Some(Width::Q),
+        _ => None,
+    };
+
+    let num = |s: &str, usage| parse_number(s).ok_or(ParseError::Usage(usage));
+    let addr_only = |usage| match argc {
+        2 => num(a1, usage),
+        _ => Err(ParseError::Usage(usage)),
+    };
+
+    match name {
+        "help" | "?" => Ok(Command::Help),
+        "md" => {
+            const USAGE: &str = "md[.b|.w|.l|.q] <addr> [count]";
+
+            let width = width.ok_or(ParseError::Usage(USAGE))?;
+            let count = match argc {
+                2 => 64 / width as u64,
+                3 => num(a2, USAGE)?,
+                _ => return Err(ParseError::Usage(USAGE)),
+            };
+
+            Ok(Command::Md {
+                width,
+                addr: num(a1, USAGE)?,
+                count,
+            })
+        }
+        "mw" => {
+            const USAGE: &str = "mw[.b|.w|.l|.q] <addr> <value> [count]";
+
+            let width = width.ok_or(ParseError::Usage(USAGE))?;
+            let count = match argc {
+                3 => 1,
+                4 => num(a3, USAGE)?,
+                _ => return Err(ParseError::Usage(USAGE)),
+            };
+
+            Ok(Command::Mw {
+                width,
+                addr: num(a1, USAGE)?,
+                value: num(a2, USAGE)?,
+                count,
+            })
+        }
+        "clk" => Ok(Command::Clk),
+        #[cfg(feature = "cpufreq")]
+        "cpufreq" => {
+            const USAGE: &str = "cpufreq [MHz]";
+
+            match argc {
+                1 => Ok(Command::CpuFreq { mhz: None }),
+                2 => Ok(Command::CpuFreq {
+                    mhz: Some(parse_decimal(a1).ok_or(ParseError::Usage(USAGE))?),
+                }),
+                _ => Err(ParseError::Usage(USAGE)),
+            }
+        }
+        #[cfg(feature = "mmio-trace")]
+        "mmiotrace" => Ok(Command::MmioTrace),
+        "dram" => Ok(Command::Dram),
+        #[cfg(feature = "zmodem")]
+        "loadz" => Ok(Command::Loadz {
+            addr: addr_only("loadz <addr>")?,
+        }),
+        "go" => Ok(Command::Go {
+            addr: addr_only("go <addr>")?,
+        }),
+        "bootelf" => Ok(Command::BootElf {
+            addr: addr_only("bootelf <addr>")?,
+        }),
+        #[cfg(feature = "sdcard")]
+        "mmcinfo" => Ok(Command::MmcInfo),
+        #[cfg(feature = "sdcard")]
+        "mmcread" => {
+            const USAGE: &str = "mmcread <addr> <block> <count>";
+
+            if argc != 4 {
+                return Err(ParseError::Usage(USAGE));
+            }
+
+            Ok(Command::MmcRead {
+                addr: num(a1, USAGE)?,
+                lba: num(a2, USAGE)?,
+                count: num(a3, USAGE)?,
+            })
+        }
+        #[cfg(feature = "fs")]
+        "load" => {
+            const USAGE: &str = "load <addr> <path>";
+
+            if argc != 3 {
+                return Err(ParseError::Usage(USAGE));
+            }
+
+            Ok(Command::Load {
+                addr: num(a1, USAGE)?,
+                path: a2,
+            })
+        }
+        #[cfg(feature = "fs")]
+        "sysboot" => match argc {
+            1 => Ok(Command::Sysboot { label: None }),
+            2 => Ok(Command::Sysboot { label: Some(a1) }),
+            _ => Err(ParseError::Usage("sysboot [label]")),
+        },
+        #[cfg(feature = "spinor")]
+        "sfprobe" => Ok(Command::SfProbe),
+        #[cfg(feature = "spinor")]
+        "sfread" | "sfwrite" => {
+            let usage = if name == "sfread" {
+                "sfread <addr> <offset> <len>"
+            } else {
+                "sfwrite <addr> <offset> <len>"
+            };
+
+            if argc != 4 {
+                return Err(ParseError::Usage(usage));
+            }
+
+            let (addr, offset, len) = (num(a1, usage)?, num(a2, usage)?, num(a3, usage)?);
+
+            Ok(if name == "sfread" {
+                Command::SfRead { addr, offset, len }
+            } else {
+                Command::SfWrite { addr, offset, len }
+            })
+        }
+        #[cfg(feature = "spinor")]
+        "sferase" => {
+            const USAGE: &str = "sferase <offset> <len>";
+
+            if argc != 3 {
+                return Err(ParseError::Usage(USAGE));
+            }
+
+            Ok(Command::SfErase {
+                offset: num(a1, USAGE)?,
+                len: num(a2, USAGE)?,
+            })
+        }
+        #[cfg(feature = "slots")]
+        "slot" => {
+            const USAGE: &str = "slot [a|b]";
+
+            match argc {
+                1 => Ok(Command::Slot { active: None }),
+                2 => Ok(Command::Slot {
+                    active: Some(crate::slot::Slot::from_str(a1).ok_or(ParseError::Usage(USAGE))?),
+                }),
+                _ => Err(ParseError::Usage(USAGE)),
+            }
+        }
+        "reset" => Ok(Command::Reset),
+        "boot" => Ok(Command::Boot),
+        _ => Err(ParseError::UnknownCommand),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Autoboot {
+    /// The countdown ran out
+    Elapsed,
+    /// The operator pressed a key
+    Interrupted,
+    /// The host started a ZMODEM transfer (`sz`) during the countdown
+    #[cfg(feature = "zmodem")]
+    Transfer,
+}
+
+/// Counts down `seconds` on UART0 while polling for a keypress.
+///
+/// `sz` announces itself with "rz\r" followed by a ZRQINIT header
+/// ("**\x18B..."), so "rz\r" and XON don't stop the countdown, while any
+/// other CR does. The first '*' ends it right away instead: the caller runs
+/// `loadz`, which resyncs on the second '*'. Without the zmodem feature '*'
+/// is an ordinary key.
+pub fn autoboot(seconds: u64) -> Autoboot {
+    let start = unsafe { timer_csr() };
+
+    // Last two bytes received, telling the CR of "rz\r" from Enter
+    let mut last = [0u8; 2];
+
+    printf!("Hit any key to stop autoboot: %d", seconds);
+
+    for left in (0..seconds).rev() {
+        while unsafe { timer_csr() } - start < (seconds - left) * TIMER_FREQ {
+            let Some(c) = crate::uart::uart_try_read() else {
+                continue;
+            };
+
+            match c {
+                b'r' | b'z' | 0x11 => {}
+                b'\r' if last == *b"rz" => {}
+                #[cfg(feature = "zmodem")]
+                b'*' | 0x18 => {
+                    printf!("\r\n");
+                    return Autoboot::Transfer;
+                }
+                _ => {
+                    printf!("\r\n");
+                    return Autoboot::Interrupted;
+                }
+            }
+
+            last = [last[1], c];
+        }
+
+        // Redrawn as a whole, the trailing space covers the last digit when
+        // the count gets shorter, e.g. from 10 to 9
+        printf!("\rHit any key to stop autoboot: %d \x08", left);
+    }
+
+    printf!("\r\n");
+
+    Autoboot::Elapsed
+}
+
+/// Byte stream the monitor reads commands from and writes its replies to.
+/// Output of the drivers, e.g. `clk` or `mmcinfo`, goes straight to UART0.
+pub trait Terminal {
+    /// Waits for the next byte
+    fn getc(&self) -> u8;
+    fn putc(&self, b: u8);
+}
+
+impl Terminal for crate::uart::Console {
+    fn getc(&self) -> u8 {
+        crate::uart::uart_read()
+    }
+
+    fn putc(&self, b: u8) {
+        crate::uart::uart_write(b)
+    }
+}
+
+/// Line-editing command shell on top of a [`Terminal`]
+pub struct Monitor<'a, T: Terminal> {
+    term: T,
+    /// Command line run by `boot`
+    bootcmd: &'a str,
+}
+
+impl<'a, T: Terminal> Monitor<'a, T> {
+    pub fn new(term: T, bootcmd: &'a str) -> Self {
+        Self { term, bootcmd }
+    }
+
+    fn puts(&self, s: &str) {
+        for b in s.bytes() {
+            self.term.putc(b);
+        }
+    }
+
+    /// Prints help lines, padding what's before a tab to [`HELP_COLUMN`] with
+    /// at least one space
+    fn put_help(&self, s: &str) {
+        let mut column = 0;
+
+        for b in s.bytes() {
+            match b {
+                b'\t' => loop {
+                    self.term.putc(b' ');
+                    column += 1;
+
+                    if column >= HELP_COLUMN {
+                        break;
+                    }
+                },
+                b'\n' => {
+                    self.term.putc(b);
+                    column = 0;
+                }
+                _ => {
+                    self.term.putc(b);
+                    column += 1;
+                }
+            }
+        }
+    }
+
+    /// Reads a line with echo, backspace and Ctrl-U. Returns None on Ctrl-C.
+    pub fn read_line<'b>(&self, buf: &'b mut [u8; LINE_MAX]) -> Option<&'b str> {
+        let mut len = 0;
+
+        loop {
+            match self.term.getc() {
+                b'\r' => break,
+                // Ends the line unless it's the LF of a CRLF pair
+                b'\n' if len > 0 => break,
+                0x03 => {
+                    self.puts("^C\r\n");
+                    return None;
+                }
+                0x08 | 0x7f if len > 0 => {
+                    len -= 1;
+                    self.puts("\x08 \x08");
+                }
+                0x15 => {
+                    while len > 0 {
+                        len -= 1;
+                        self.puts("\x08 \x08");
+                    }
+                }
+                c @ 0x20..=0x7e if len < buf.len() => {
+                    *unsafe { buf.get_unchecked_mut(len) } = c;
+                    len += 1;
+                    self.term.putc(c);
+                }
+                _ => {}
+            }
+        }
+
+        self.puts("\r\n");
+
+        // Only printable ASCII ends up in the buffer
+        Some(unsafe { core::str::from_utf8_unchecked(buf.get_unchecked(..len)) })
+    }
+
+    /// The timer and UART0 don't depend on PLL_CPU, so the console and
+    /// delays carry on unaffected
+    #[cfg(feature = "cpufreq")]
+    fn cpufreq(&self, mhz: Option<u64>) {
+        let out = &mut |b| self.term.putc(b);
+
+        if let Some(mhz) = mhz {
+            match unsafe { crate::ccu::set_cpu_freq(mhz.saturating_mul(1_000_000)) } {
+                Ok(_) => {}
+                Err(crate::ccu::CpuFreqError::BelowRange) => self.puts("below the PLL_CPU range\r\n"),
+                Err(crate::ccu::CpuFreqError::AboveRange) => self.puts("above the core's limit\r\n"),
+                Err(crate::ccu::CpuFreqError::PllLock(t)) => t.print("PLL_CPU"),
+            }
+        }
+
+        fprintf!(out, "CPU: %dHz\r\n", crate::ccu::Clock::Riscv.rate());
+    }
+
+    fn md(&self, width: Width, addr: u64, count: u64) {
+        let out = &mut |b| self.term.putc(b);
+        let per_line = 16 / width as u64;
+
+        for i in 0..count {
+            let p = addr + i * width as u64;
+
+            if i % per_line == 0 {
+                fprintf!(out, "%08x:", p);
+            }
+
+            unsafe {
+                match width {
+                    Width::B => fprintf!(out, " %02x", core::ptr::read_volatile(p as *const u8)),
+                    Width::W => fprintf!(out, " %04x", core::ptr::read_volatile(p as *const u16)),
+                    Width::L => fprintf!(out, " %08x", core::ptr::read_volatile(p as *const u32)),
+                    Width::Q => fprintf!(out, " %016x", core::ptr::read_volatile(p as *const u64)),
+                }
+            }
+
+            if i % per_line == per_line - 1 || i == count - 1 {
+                self.puts("\r\n");
+            }
+        }
+    }
+
+    fn mw(&self, width: Width, addr: u64, value: u64, count: u64) {
+        for i in 0..count {
+            let p = addr + i * width as u64;
+
+            unsafe {
+                match width {
+                    Width::B => core::ptr::write_volatile(p as *mut u8, value as u8),
+                    Width::W => core::ptr::write_volatile(p as *mut u16, value as u16),
+                    Width::L => core::ptr::write_volatile(p as *mut u32, value as u32),
+                    Width::Q => core::ptr::write_volatile(p as *mut u64, value),
+                }
+            }
+        }
+    }
+
+    fn dram_buffer(&self, addr: u64) -> Option<&'static mut [u8]> {
+        let buffer = crate::dram::dram_buffer(addr);
+
+        if buffer.is_none() {
+            self.puts("address outside of DRAM\r\n");
+        }
+
+        buffer
+    }
+
+    #[cfg(feature = "sdcard")]
+    fn mmcinfo(&self) {
+        let out = &mut |b| self.term.putc(b);
+
+        match crate::smhc::sd0() {
+            Ok(sd) => sd.print_info(),
+            Err(e) => fprintf!(out, "SD card: %s\r\n", e.as_str()),
+        }
+    }
+
+    #[cfg(feature = "sdcard")]
+    fn mmcread(&self, addr: u64, lba: u64, count: u64) {
+        use crate::block::{BLOCK_SIZE, BlockDevice};
+
+        let out = &mut |b| self.term.putc(b);
+
+        let Some(buffer) = self.dram_buffer(addr) else {
+            return;
+        };
+        let Some(buffer) = buffer.get_mut(..(count as usize).saturating_mul(BLOCK_SIZE)) else {
+            self.puts("not enough DRAM after addr\r\n");
+            return;
+        };
+
+        match crate::smhc::sd0().and_then(|sd| sd.read_blocks(lba, buffer)) {
+            Ok(()) => fprintf!(out, "read %d blocks to 0x%x\r\n", count, addr),
+            Err(e) => fprintf!(out, "SD card: %s\r\n", e.as_str()),
+        }
+    }
+
+    #[cfg(feature = "zmodem")]
+    fn loadz(&self, addr: u64) {
+        let out = &mut |b| self.term.putc(b);
+
+        if let Some(buffer) = self.dram_buffer(addr) {
+            let size = crate::load_zmodem(buffer);
+            fprintf!(out, "received %d bytes at 0x%x\r\n", size, addr);
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    fn load(&self, addr: u64, path: &str) {
+        crate::load_file(path, addr, u64::MAX);
+    }
+
+    #[cfg(feature = "fs")]
+    fn sysboot(&self, label: Option<&str>) {
+        crate::sysboot(label);
+    }
+
+    /// Changing the active slot takes effect at the next reset. The kernel
+    /// booted before that doesn't get to confirm the old state.
+    #[cfg(feature = "slots")]
+    fn slot(&self, active: Option<crate::slot::Slot>) {
+        let mut state = crate::slot::load();
+
+        if let Some(slot) = active {
+            state = state.set_active(slot);
+            crate::slot::store(state);
+
+            let info = crate::handoff::boot_info();
+            info.slot = crate::handoff::NO_SLOT;
+            info.confirm_addr = 0;
+        }
+
+        crate::slot::print_state(&state);
+    }
+
+    #[cfg(feature = "spinor")]
+    fn sfprobe(&self) {
+        let out = &mut |b| self.term.putc(b);
+
+        match crate::spinor::flash0() {
+            Ok(flash) => crate::spinor::print_info(flash),
+            Err(e) => fprintf!(out, "SPI NOR: %s\r\n", e.as_str()),
+        }
+    }
+
+    /// DRAM at `addr` for a flash transfer of `len` bytes at `offset`
+    #[cfg(feature = "spinor")]
+    fn sf_buffer(&self, addr: u64, offset: u64, len: u64) -> Option<(&'static mut [u8], u32)> {
+        let buffer = self.dram_buffer(addr)?;
+
+        let Some(buffer) = buffer.get_mut(..len as usize) else {
+            self.puts("not enough DRAM after addr\r\n");
+            return None;
+        };
+
+        let Ok(offset) = u32::try_from(offset) else {
+            self.puts("offset out of range\r\n");
+            return None;
+        };
+
+        Some((buffer, offset))
+    }
+
+    #[cfg(feature = "spinor")]
+    fn sfread(&self, addr: u64, offset: u64, len: u64) {
+        let out = &mut |b| self.term.putc(b);
+
+        let Some((buffer, offset)) = self.sf_buffer(addr, offset, len) else {
+            return;
+        };
+
+        match crate::spinor::flash0().and_then(|flash| flash.read(offset, buffer)) {
+            Ok(()) => fprintf!(out, "read %d bytes to 0x%x\r\n", len, addr),
+            Err(e) => fprintf!(out, "SPI NOR: %s\r\n", e.as_str()),
+        }
+    }
+
+    #[cfg(feature = "spinor")]
+    fn sfwrite(&self, addr: u64, offset: u64, len: u64) {
+        let out = &mut |b| self.term.putc(b);
+
+        let Some((buffer, offset)) = self.sf_buffer(addr, offset, len) else {
+            return;
+        };
+
+        match crate::spinor::flash0().and_then(|flash| flash.write(offset, buffer)) {
+            Ok(()) => fprintf!(out, "wrote %d bytes from 0x%x\r\n", len, addr),
+            Err(e) => fprintf!(out, "SPI NOR: %s\r\n", e.as_str()),
+        }
+    }
+
+    #[cfg(feature = "spinor")]
+    fn sferase(&self, offset: u64, len: u64) {
+        let out = &mut |b| self.term.putc(b);
+
+        let (Ok(offset), Ok(len)) = (u32::try_from(offset), u32::try_from(len)) else {
+            self.puts("offset out of range\r\n");
+            return;
+        };
+
+        match crate::spinor::flash0().and_then(|flash| flash.erase(offset, len)) {
+            Ok(()) => fprintf!(out, "erased %d bytes at 0x%x\r\n", len, offset),
+            Err(crate::block::Error::Unsupported) => self.puts("offset and len must be 4 KiB aligned\r\n"),
+            Err(e) => fprintf!(out, "SPI NOR: %s\r\n", e.as_str()),
+        }
+    }
+
+    pub fn execute(&self, cmd: Command<'_>) {
+        let out = &mut |b| self.term.putc(b);
+
+        match cmd {
+            Command::Help => {
+                self.put_help(HELP);
+                #[cfg(feature = "zmodem")]
+                self.put_help(HELP_ZMODEM);
+                #[cfg(feature = "sdcard")]
+                self.put_help(HELP_SDCARD);
+                #[cfg(feature = "fs")]
+                self.put_help(HELP_FS);
+                #[cfg(feature = "cpufreq")]
+                self.put_help(HELP_CPUFREQ);
+                #[cfg(feature = "mmio-trace")]
+                self.put_help(HELP_MMIOTRACE);
+                #[cfg(feature = "spinor")]
+                self.put_help(HELP_SPINOR);
+                #[cfg(feature = "slots")]
+                self.put_help(HELP_SLOTS);
+                self.puts(HELP_NOTES);
+            }
+            Command::Md { width, addr, .. } | Command::Mw { width, addr, .. }
+                if addr % width as u64 != 0 =>
+            {
+                self.puts("unaligned address\r\n")
+            }
+            Command::Md { width, addr, count } => self.md(width, addr, count),
+            Command::Mw {
+                width,
+                addr,
+                value,
+                count,
+            } => self.mw(width, addr, value, count),
+            Command::Clk => crate::ccu::dump(),
+            #[cfg(feature = "cpufreq")]
+            Command::CpuFreq { mhz } => self.cpufreq(mhz),
+            #[cfg(feature = "mmio-trace")]
+            Command::MmioTrace => crate::trace::dump(),
+            Command::Dram => crate::dram::print_info(),
+            #[cfg(feature = "zmodem")]
+            Command::Loadz { addr } => self.loadz(addr),
+            Command::Go { addr } => {
+                fprintf!(out, "starting at 0x%x\r\n", addr);
+                unsafe { crate::elf::jump(addr) }
+            }
+            Command::BootElf { addr } => unsafe { crate::elf::execute(addr as *const u8) },
+            #[cfg(feature = "sdcard")]
+            Command::MmcInfo => self.mmcinfo(),
+            #[cfg(feature = "sdcard")]
+            Command::MmcRead { addr, lba, count } => self.mmcread(addr, lba, count),
+            #[cfg(feature = "fs")]
+            Command::Load { addr, path } => self.load(addr, path),
+            #[cfg(feature = "fs")]
+            Command::Sysboot { label } => self.sysboot(label),
+            #[cfg(feature = "spinor")]
+            Command::SfProbe => self.sfprobe(),
+            #[cfg(feature = "spinor")]
+            Command::SfRead { addr, offset, len } => self.sfread(addr, offset, len),
+            #[cfg(feature = "spinor")]
+            Command::SfWrite { addr, offset, len } => self.sfwrite(addr, offset, len),
+            #[cfg(feature = "spinor")]
+            Command::SfErase { offset, len } => self.sferase(offset, len),
+            #[cfg(feature = "slots")]
+            Command::Slot { active } => self.slot(active),
+            Command::Reset => crate::wdt::reset(),
+            Command::Boot => {
+                crate::diag::set_stage(crate::diag::Stage::Boot);
+                fprintf!(out, "%s\r\n", self.bootcmd);
+
+                self.run_commands(self.bootcmd, false);
+            }
+        }
+    }
+
+    /// Runs a line of ';' separated commands
+    pub fn run_line(&self, line: &str) {
+        self.run_commands(line, true);
+    }
+
+    fn run_commands(&self, line: &str, allow_boot: bool) {
+        let mut rest = Some(line);
+
+        while let Some(line) = rest {
+            let (part, tail) = match split_once(line, b';') {
+                Some((part, tail)) => (part, Some(tail)),
+                None => (line, None),
+            };
+
+            // `boot` within the boot command would recurse forever
+            if allow_boot || !matches!(parse(part), Ok(Command::Boot)) {
+                self.run_command(part);
+            }
+
+            rest = tail;
+        }
+    }
+
+    fn run_command(&self, line: &str) {
+        let out = &mut |b| self.term.putc(b);
+
+        match parse(line) {
+            Ok(cmd) => self.execute(cmd),
+            Err(ParseError::Empty) => {}
+            Err(ParseError::UnknownCommand) => {
+                self.puts("unknown command, try 'help'\r\n");
+            }
+            Err(ParseError::Usage(usage)) => fprintf!(out, "usage: %s\r\n", usage),
+        }
+    }
+
+    pub fn run(&self) -> ! {
+        let mut buf = [0u8; LINE_MAX];
+
+        loop {
+            self.puts("os5> ");
+
+            if let Some(line) = self.read_line(&mut buf) {
+                self.run_line(line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    /// Terminal replaying scripted keystrokes and keeping what's written
+    #[derive(Default)]
+    struct Script {
+        input: RefCell<VecDeque<u8>>,
+        output: RefCell<Vec<u8>>,
+    }
+
+    impl Terminal for Script {
+        fn getc(&self) -> u8 {
+            self.input.borrow_mut().pop_front().expect("script ran out of input")
+        }
+
+        fn putc(&self, b: u8) {
+            self.output.borrow_mut().push(b);
+        }
+    }
+
+    fn monitor<'a>(input: &[u8], bootcmd: &'a str) -> Monitor<'a, Script> {
+        let term = Script::default();
+        term.input.borrow_mut().extend(input);
+
+        Monitor::new(term, bootcmd)
+    }
+
+    fn output(m: &Monitor<'_, Script>) -> String {
+        String::from_utf8(m.term.output.take()).unwrap()
+    }
+
+    #[test]
+    fn parse_commands() {
+        let cases = [
+            ("help", Ok(Command::Help)),
+            ("  ?  ", Ok(Command::Help)),
+            ("md 40000000", Ok(Command::Md { width: Width::L, addr: 0x4000_0000, count: 16 })),
+            ("md.b 0x10 20", Ok(Command::Md { width: Width::B, addr: 0x10, count: 0x20 })),
+            ("md.q 8", Ok(Command::Md { width: Width::Q, addr: 8, count: 8 })),
+            ("mw.w 10 ABCD", Ok(Command::Mw { width: Width::W, addr: 0x10, value: 0xabcd, count: 1 })),
+            ("mw 10 1 4", Ok(Command::Mw { width: Width::L, addr: 0x10, value: 1, count: 4 })),
+            ("go 42000000", Ok(Command::Go { addr: 0x4200_0000 })),
+            ("bootelf 0x42000000", Ok(Command::BootElf { addr: 0x4200_0000 })),
+            ("clk", Ok(Command::Clk)),
+            ("dram", Ok(Command::Dram)),
+            ("reset", Ok(Command::Reset)),
+            ("boot", Ok(Command::Boot)),
+            ("", Err(ParseError::Empty)),
+            ("   ", Err(ParseError::Empty)),
+            ("mdx 0", Err(ParseError::UnknownCommand)),
+            ("md.x 0", Err(ParseError::Usage("md[.b|.w|.l|.q] <addr> [count]"))),
+            ("md", Err(ParseError::Usage("md[.b|.w|.l|.q] <addr> [count]"))),
+            ("md 0 1 2", Err(ParseError::Usage("md[.b|.w|.l|.q] <addr> [count]"))),
+            ("mw 10", Err(ParseError::Usage("mw[.b|.w|.l|.q] <addr> <value> [count]"))),
+            ("mw 10 1 2 3", Err(ParseError::Usage("mw[.b|.w|.l|.q] <addr> <value> [count]"))),
+            ("go", Err(ParseError::Usage("go <addr>"))),
+            ("go 0x", Err(ParseError::Usage("go <addr>"))),
+            ("go 12345678123456789", Err(ParseError::Usage("go <addr>"))),
+            ("bootelf 4g", Err(ParseError::Usage("bootelf <addr>"))),
+        ];
+
+        for (line, expected) in cases {
+            assert_eq!(parse(line), expected, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn parse_feature_commands() {
+        let cases: &[(&str, Result<Command<'static>, ParseError>)] = &[
+            #[cfg(feature = "zmodem")]
+            ("loadz 40000000", Ok(Command::Loadz { addr: 0x4000_0000 })),
+            #[cfg(not(feature = "zmodem"))]
+            ("loadz 40000000", Err(ParseError::UnknownCommand)),
+            #[cfg(feature = "sdcard")]
+            ("mmcread 40000000 0 8", Ok(Command::MmcRead { addr: 0x4000_0000, lba: 0, count: 8 })),
+            #[cfg(feature = "sdcard")]
+            ("mmcread 40000000", Err(ParseError::Usage("mmcread <addr> <block> <count>"))),
+            #[cfg(not(feature = "sdcard"))]
+            ("mmcinfo", Err(ParseError::UnknownCommand)),
+            #[cfg(feature = "fs")]
+            ("load 42000000 /boot/kernel.elf", Ok(Command::Load { addr: 0x4200_0000, path: "/boot/kernel.elf" })),
+            #[cfg(feature = "fs")]
+            ("sysboot linux", Ok(Command::Sysboot { label: Some("linux") })),
+            #[cfg(feature = "fs")]
+            ("sysboot a b", Err(ParseError::Usage("sysboot [label]"))),
+            #[cfg(not(feature = "fs"))]
+            ("sysboot", Err(ParseError::UnknownCommand)),
+            #[cfg(feature = "cpufreq")]
+            ("cpufreq 1008", Ok(Command::CpuFreq { mhz: Some(1008) })),
+            #[cfg(feature = "cpufreq")]
+            ("cpufreq 0x10", Err(ParseError::Usage("cpufreq [MHz]"))),
+            #[cfg(feature = "spinor")]
+            ("sfread 40000000 100000 1000", Ok(Command::SfRead { addr: 0x4000_0000, offset: 0x10_0000, len: 0x1000 })),
+            #[cfg(feature = "spinor")]
+            ("sferase 0 1000", Ok(Command::SfErase { offset: 0, len: 0x1000 })),
+            #[cfg(feature = "slots")]
+            ("slot b", Ok(Command::Slot { active: Some(crate::slot::Slot::B) })),
+            #[cfg(feature = "slots")]
+            ("slot c", Err(ParseError::Usage("slot [a|b]"))),
+        ];
+
+        for (line, expected) in cases {
+            assert_eq!(parse(line), *expected, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn read_line_editing() {
+        // Backspace, DEL, Ctrl-U and non-printable bytes, then the LF of a CRLF
+        let m = monitor(b"mdx\x08 1\x7f2\x15md 1\x01\r\n\ndram\nclk\r", "");
+        let mut buf = [0; LINE_MAX];
+
+        assert_eq!(m.read_line(&mut buf), Some("md 1"));
+        assert_eq!(output(&m), "mdx\x08 \x08 1\x08 \x082\x08 \x08\x08 \x08\x08 \x08\x08 \x08md 1\r\n");
+
+        // LFs on an empty line are skipped, e.g. the one of a CRLF, others
+        // end the line
+        assert_eq!(m.read_line(&mut buf), Some("dram"));
+        assert_eq!(m.read_line(&mut buf), Some("clk"));
+        assert_eq!(output(&m), "dram\r\nclk\r\n");
+
+        let m = monitor(b"reset\x03", "");
+        assert_eq!(m.read_line(&mut buf), None);
+        assert_eq!(output(&m), "reset^C\r\n");
+    }
+
+    #[test]
+    fn read_line_limit() {
+        let mut input = vec![b'x'; LINE_MAX + 10];
+        input.push(b'\r');
+        let m = monitor(&input, "");
+        let mut buf = [0; LINE_MAX];
+
+        assert_eq!(m.read_line(&mut buf), Some("x".repeat(LINE_MAX).as_str()));
+    }
+
+    #[test]
+    fn help_lists_built_commands() {
+        let m = monitor(b"", "");
+        m.run_line("help");
+        let help = output(&m);
+
+        assert!(help.starts_with("md[.b|.w|.l|.q] <addr> [count]        display memory\r\n"));
+        assert!(help.ends_with("numbers are hex, with or without the 0x prefix\r\n"));
+
+        for (command, built) in [
+            ("loadz", cfg!(feature = "zmodem")),
+            ("mmcinfo", cfg!(feature = "sdcard")),
+            ("mmcread", cfg!(feature = "sdcard")),
+            ("load <addr>", cfg!(feature = "fs")),
+            ("sysboot", cfg!(feature = "fs")),
+            ("cpufreq", cfg!(feature = "cpufreq")),
+            ("sfprobe", cfg!(feature = "spinor")),
+            ("slot", cfg!(feature = "slots")),
+            ("mmiotrace", cfg!(feature = "mmio-trace")),
+        ] {
+            assert_eq!(help.contains(&format!("\n{command}")), built, "{command}");
+        }
+
+        // Every command in the help text parses, i.e. is built in
+        for line in help.lines().filter(|l| l.contains("  ")) {
+            let name = line.split([' ', '[']).next().unwrap();
+            assert_ne!(parse(name), Err(ParseError::UnknownCommand), "{line}");
+        }
+    }
+
+    #[test]
+    fn memory_commands() {
+        let mut mem = [0u64; 4];
+        let addr = mem.as_mut_ptr() as u64;
+
+        let m = monitor(b"", "");
+        m.run_line(&format!("mw.q {addr:x} 1122334455667788; mw.b {:x} aa 2", addr + 8));
+        assert_eq!(mem[0], 0x1122_3344_5566_7788);
+        assert_eq!(mem[1], 0xaaaa);
+
+        m.run_line(&format!("md.w {addr:x} 5"));
+        assert_eq!(output(&m), format!("{addr:08x}: 7788 5566 3344 1122 aaaa\r\n"));
+
+        m.run_line(&format!("md.b {addr:x} 12"));
+        assert_eq!(
+            output(&m),
+            format!(
+                "{addr:08x}: 88 77 66 55 44 33 22 11 aa aa 00 00 00 00 00 00\r\n{:08x}: 00 00\r\n",
+                addr + 16
+            )
+        );
+
+        m.run_line(&format!("md.l {:x}; mw.q {:x} 0", addr + 2, addr + 4));
+        assert_eq!(output(&m), "unaligned address\r\nunaligned address\r\n");
+    }
+
+    #[test]
+    fn errors_and_chaining() {
+        let m = monitor(b"", "");
+        m.run_line("bogus; ; md; go");
+
+        assert_eq!(
+            output(&m),
+            "unknown command, try 'help'\r\n\
+             usage: md[.b|.w|.l|.q] <addr> [count]\r\n\
+             usage: go <addr>\r\n"
+        );
+    }
+
+    #[test]
+    fn autoboot_countdown() {
+        crate::sim::reset();
+
+        assert_eq!(autoboot(2), Autoboot::Elapsed);
+        assert_eq!(
+            crate::sim::take_console(),
+            "Hit any key to stop autoboot: 2\
+             \rHit any key to stop autoboot: 1 \x08\
+             \rHit any key to stop autoboot: 0 \x08\r\n"
+        );
+    }
+
+    #[test]
+    fn autoboot_keys() {
+        let cases: &[(&[u8], Autoboot, &[u8])] = &[
+            (b"\r", Autoboot::Interrupted, b""),
+            (b"\n", Autoboot::Interrupted, b""),
+            (b"x", Autoboot::Interrupted, b""),
+            // The CR of "rz\r" and XON are skipped, Enter after them isn't
+            (b"rz\r\x11\rx", Autoboot::Interrupted, b"x"),
+            (b"r\r", Autoboot::Interrupted, b""),
+            #[cfg(feature = "zmodem")]
+            (b"rz\r**\x18B0000", Autoboot::Transfer, b"*\x18B0000"),
+            #[cfg(feature = "zmodem")]
+            (b"\x18B0000", Autoboot::Transfer, b"B0000"),
+            #[cfg(not(feature = "zmodem"))]
+            (b"rz\r**", Autoboot::Interrupted, b"*"),
+        ];
+
+        for &(input, expected, left) in cases {
+            crate::sim::reset();
+            crate::sim::input(input);
+
+            assert_eq!(autoboot(1), expected, "{input:?}");
+
+            let rest: Vec<u8> = core::iter::from_fn(crate::uart::uart_try_read).collect();
+            assert_eq!(rest, left, "{input:?}");
+            assert!(crate::sim::take_console().ends_with(": 1\r\n"));
+        }
+    }
+
+    #[test]
+    fn boot_runs_bootcmd_without_recursing() {
+        crate::sim::reset();
+
+        let m = monitor(b"", "bogus; boot; go");
+        m.run_line("boot");
+
+        assert_eq!(
+            output(&m),
+            "bogus; boot; go\r\nunknown command, try 'help'\r\nusage: go <addr>\r\n"
+        );
+    }
+}
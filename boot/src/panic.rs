@@ -1,18 +1,62 @@
+// The host builds use std's handler
+#[cfg(not(any(test, feature = "sim")))]
 use core::panic::PanicInfo;
 
+#[cfg(not(any(test, feature = "sim", feature = "panic-info")))]
 unsafe extern "C" {
     pub unsafe fn rust_panic_called_where_shouldnt() -> !;
 }
 
+/// Without the `panic-info` feature any reachable panic path is turned into a
+/// link error, so the formatting machinery never ends up in the image.
+#[cfg(not(any(test, feature = "sim", feature = "panic-info")))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     unsafe { rust_panic_called_where_shouldnt(); }
 }
 
+/// Prints the panic location and message over UART0 using the polled driver,
+/// which does not depend on anything but the UART having been initialized.
+#[cfg(all(feature = "panic-info", not(any(test, feature = "sim"))))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let mut w = crate::uart::Console;
+
+    let _ = w.write_str("\r\npanic in bootloader");
+
+    if let Some(location) = info.location() {
+        let _ = write!(
+            w,
+            " at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        );
+    }
+
+    let _ = write!(w, ": {}\r\n", info.message());
+
+    crate::diag::set_error(crate::diag::Error::Panic, 0, 0);
+    crate::wdt::fatal()
+}
+
+/// Entered from `_trap` (boot.S) on any exception or interrupt, none of
+/// which the bootloader expects
+#[cfg(not(any(test, feature = "sim")))]
+#[unsafe(no_mangle)]
+extern "C" fn trap_handler(mcause: u64, mepc: u64) -> ! {
+    crate::uart::printf!("trap in bootloader: mcause 0x%x, mepc 0x%x\r\n", mcause, mepc);
+    crate::diag::set_error(crate::diag::Error::Trap, mcause, mepc);
+    crate::wdt::fatal()
+}
+
 #[macro_export]
 macro_rules! boot_panic {
     ($msg:expr $(,$arg:expr)*) => {{
-        $crate::uart::printf!(concat!("panic in bootloader: ",$msg), $($arg),*);
-        loop {}
+        $crate::uart::printf!(concat!("panic in bootloader: ", $msg, "\r\n"), $($arg),*);
+        $crate::diag::set_error($crate::diag::Error::BootPanic, 0, 0);
+        $crate::wdt::fatal()
     }};
 }
@@ -0,0 +1,327 @@
+//! MBR and GPT partition tables
+
+use crate::block::{BLOCK_SIZE, BlockDevice, Error};
+use crate::fs::{le32, le64};
+
+const MBR_ENTRIES: u64 = 4;
+const MBR_TYPE_GPT: u8 = 0xee;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Partition {
+    /// First block
+    pub start: u64,
+    /// Size in blocks, 0 for an unused slot
+    pub blocks: u64,
+}
+
+impl Partition {
+    /// The partition as a block device of its own
+    pub fn on<D: BlockDevice>(self, dev: D) -> Slice<D> {
+        Slice { dev, part: self }
+    }
+}
+
+/// Block device view of a partition
+pub struct Slice<D> {
+    dev: D,
+    part: Partition,
+}
+
+impl<D: BlockDevice> BlockDevice for Slice<D> {
+    fn block_count(&self) -> u64 {
+        self.part.blocks
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        if lba + (buf.len() / BLOCK_SIZE) as u64 > self.part.blocks {
+            return Err(Error::OutOfRange);
+        }
+
+        self.dev.read_blocks(self.part.start + lba, buf)
+    }
+}
+
+/// Whether `block` holds an MBR partition table for a device of `blocks`
+/// blocks: every entry has a valid boot flag, and the used ones, at least
+/// one, lie within the device.
+///
+/// Boot loaders such as GRUB and syslinux start their MBR code with a jump
+/// just like a FAT boot sector, which shares the 0x55aa signature as well,
+/// so only the entries tell the two apart. Those of a FAT boot sector are
+/// boot code, or zeros for the short one mkfs.fat writes.
+fn is_mbr(block: &[u8; BLOCK_SIZE], blocks: u64) -> bool {
+    if block[510..] != [0x55, 0xaa] {
+        return false;
+    }
+
+    let mut used = 0;
+
+    for entry in block[446..510].chunks_exact(16) {
+        let kind = entry[4];
+        let start = le32(entry, 8) as u64;
+        let size = le32(entry, 12) as u64;
+
+        if !matches!(entry[0], 0x00 | 0x80) {
+            return false;
+        }
+
+        if kind == 0 {
+            continue;
+        }
+
+        // A protective MBR may cover more than the device with 0xffffffff
+        let fits = start + size <= blocks || (kind == MBR_TYPE_GPT && size == 0xffff_ffff);
+
+        if start == 0 || size == 0 || !fits {
+            return false;
+        }
+
+        used += 1;
+    }
+
+    used > 0
+}
+
+/// Returns slot `n` of the partition table, None past the last slot or when
+/// the device has no partition table. A protective MBR leads to the GPT.
+pub fn nth<D: BlockDevice>(dev: &mut D, n: u64) -> Result<Option<Partition>, Error> {
+    let mut block = [0u8; BLOCK_SIZE];
+    dev.read_blocks(0, &mut block)?;
+
+    if !is_mbr(&block, dev.block_count()) {
+        return Ok(None);
+    }
+
+    if block[450] == MBR_TYPE_GPT {
+        return gpt_nth(dev, n);
+    }
+
+    if n >= MBR_ENTRIES {
+        return Ok(None);
+    }
+
+    let entry = 446 + n as usize * 16;
+    let start = le32(&block, entry + 8) as u64;
+    let blocks = le32(&block, entry + 12) as u64;
+    let used = block.get(entry + 4).is_some_and(|&t| t != 0);
+
+    Ok(Some(Partition {
+        start,
+        blocks: if used { blocks } else { 0 },
+    }))
+}
+
+fn gpt_nth<D: BlockDevice>(dev: &mut D, n: u64) -> Result<Option<Partition>, Error> {
+    let mut block = [0u8; BLOCK_SIZE];
+    dev.read_blocks(1, &mut block)?;
+
+    if &block[..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let entries_lba = le64(&block, 72);
+    let entries = le32(&block, 80) as u64;
+    let entry_size = le32(&block, 84) as u64;
+
+    // Entries are 128 * 2^k bytes
+    if n >= entries || entry_size < 128 || !entry_size.is_power_of_two() {
+        return Ok(None);
+    }
+
+    let offset = n * entry_size;
+    dev.read_blocks(entries_lba + offset / BLOCK_SIZE as u64, &mut block)?;
+
+    let entry = (offset % BLOCK_SIZE as u64) as usize;
+    let used = block.get(entry..entry + 16).is_some_and(|guid| guid.iter().any(|&b| b != 0));
+    let first = le64(&block, entry + 32);
+    let last = le64(&block, entry + 40);
+
+    Ok(Some(Partition {
+        start: first,
+        blocks: if used && last >= first { last - first + 1 } else { 0 },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Start of GRUB's boot.img: jmp, nop
+    const GRUB_CODE: &[u8] = &[0xeb, 0x63, 0x90];
+
+    /// Disk of `blocks` blocks with an MBR holding `entries` (boot flag,
+    /// type, first block, blocks) after `code`
+    fn mbr_disk(blocks: usize, code: &[u8], entries: &[(u8, u8, u32, u32)]) -> Vec<u8> {
+        let mut disk = vec![0u8; blocks * BLOCK_SIZE];
+
+        disk[..code.len()].copy_from_slice(code);
+        for (i, &(flag, kind, start, size)) in entries.iter().enumerate() {
+            let e = &mut disk[446 + i * 16..][..16];
+            e[0] = flag;
+            e[4] = kind;
+            e[8..12].copy_from_slice(&start.to_le_bytes());
+            e[12..16].copy_from_slice(&size.to_le_bytes());
+        }
+        disk[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        disk
+    }
+
+    /// Disk of `blocks` blocks with a protective MBR and a GPT of 128 entries
+    /// of `entry_size` bytes at LBA 2, `parts` (first, last) from entry 0 on,
+    /// unused where the first block is 0
+    fn gpt_disk(blocks: usize, entry_size: u32, parts: &[(u64, u64)]) -> Vec<u8> {
+        let size = (blocks - 1).min(0xffff_ffff) as u32;
+        let mut disk = mbr_disk(blocks, &[], &[(0, MBR_TYPE_GPT, 1, size)]);
+
+        let h = &mut disk[BLOCK_SIZE..2 * BLOCK_SIZE];
+        h[..8].copy_from_slice(GPT_SIGNATURE);
+        h[72..80].copy_from_slice(&2u64.to_le_bytes());
+        h[80..84].copy_from_slice(&128u32.to_le_bytes());
+        h[84..88].copy_from_slice(&entry_size.to_le_bytes());
+
+        for (i, &(first, last)) in parts.iter().enumerate() {
+            if first == 0 {
+                continue;
+            }
+
+            let e = &mut disk[2 * BLOCK_SIZE + i * entry_size as usize..][..128];
+            // Linux filesystem data
+            e[..16].copy_from_slice(&[
+                0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4,
+            ]);
+            e[16] = i as u8 + 1;
+            e[32..40].copy_from_slice(&first.to_le_bytes());
+            e[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+
+        disk
+    }
+
+    fn table(disk: &mut Vec<u8>) -> Vec<Option<Partition>> {
+        (0..5).map(|n| nth(disk, n).unwrap()).collect()
+    }
+
+    fn part(start: u64, blocks: u64) -> Option<Partition> {
+        Some(Partition { start, blocks })
+    }
+
+    #[test]
+    fn mbr() {
+        let entries = [(0x80, 0x0c, 2048, 1024), (0, 0, 0, 0), (0, 0x83, 3072, 1024)];
+
+        // Plain and GRUB boot code in front of the table
+        for code in [&[][..], GRUB_CODE] {
+            let mut disk = mbr_disk(4096, code, &entries);
+            assert_eq!(table(&mut disk), [part(2048, 1024), part(0, 0), part(3072, 1024), part(0, 0), None]);
+        }
+    }
+
+    #[test]
+    fn gpt() {
+        for entry_size in [128, 512] {
+            let mut disk = gpt_disk(8192, entry_size, &[(2048, 4095), (0, 0), (4096, 8158)]);
+            assert_eq!(table(&mut disk), [part(2048, 2048), part(0, 0), part(4096, 4063), part(0, 0), part(0, 0)]);
+            assert_eq!(nth(&mut disk, 127).unwrap(), part(0, 0));
+            assert_eq!(nth(&mut disk, 128).unwrap(), None);
+        }
+
+        // Over 2 TiB the protective partition stops at 0xffffffff blocks
+        let mut disk = gpt_disk(8192, 128, &[(2048, 4095)]);
+        disk[446 + 12..446 + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(nth(&mut disk, 0).unwrap(), part(2048, 2048));
+
+        // A protective MBR without a GPT behind it
+        disk[BLOCK_SIZE] = 0;
+        assert_eq!(nth(&mut disk, 0).unwrap(), None);
+
+        let mut disk = gpt_disk(8192, 100, &[]);
+        assert_eq!(nth(&mut disk, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn no_table() {
+        let entries = [(0x80, 0x0c, 2048, 1024)];
+        let cases: [(&str, Vec<u8>); 6] = [
+            ("no signature", {
+                let mut disk = mbr_disk(4096, &[], &entries);
+                disk[511] = 0;
+                disk
+            }),
+            ("all entries unused", mbr_disk(4096, GRUB_CODE, &[])),
+            ("boot flag", mbr_disk(4096, &[], &[(0x80, 0x0c, 2048, 1024), (0x01, 0x83, 3072, 100)])),
+            ("past the end", mbr_disk(4096, &[], &[(0, 0x0c, 2048, 2049)])),
+            ("overlapping the MBR", mbr_disk(4096, &[], &[(0, 0x0c, 0, 1024)])),
+            ("empty partition", mbr_disk(4096, &[], &[(0, 0x0c, 2048, 0)])),
+        ];
+
+        for (what, mut disk) in cases {
+            assert_eq!(nth(&mut disk, 0).unwrap(), None, "{what}");
+        }
+    }
+
+    /// Boot sectors of file systems on whole devices
+    #[cfg(feature = "fat")]
+    #[test]
+    fn file_system_without_table() {
+        use crate::fat::{FatType, tests::Image};
+
+        for kind in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let mut disk = Image::new(kind).data();
+            assert_eq!(nth(&mut disk, 0).unwrap(), None, "{kind:?}");
+        }
+    }
+
+    /// [`crate::fs::load`] picking the first partition with a file system
+    #[cfg(feature = "fat")]
+    #[test]
+    fn load_from_partitions() {
+        use crate::fat::{FatType, tests::Image, tests::pattern};
+        use crate::fs::{Error, load};
+
+        let fs = |kind| {
+            let mut image = Image::new(kind);
+            image.mkdir("/boot").add("/boot/kernel.elf", &pattern(5000, kind as u32)).data()
+        };
+
+        let place = |disk: &mut Vec<u8>, start: usize, image: &[u8]| {
+            disk[start * BLOCK_SIZE..][..image.len()].copy_from_slice(image);
+        };
+
+        let fat16 = fs(FatType::Fat16);
+        let fat32 = fs(FatType::Fat32);
+        let blocks16 = (fat16.len() / BLOCK_SIZE) as u32;
+        let blocks32 = (fat32.len() / BLOCK_SIZE) as u32;
+
+        // MBR after GRUB code, a Linux partition without a file system first
+        let mut mbr = mbr_disk(2048 + 1024 + blocks16 as usize, GRUB_CODE, &[
+            (0, 0x83, 2048, 1024),
+            (0x80, 0x0e, 3072, blocks16),
+        ]);
+        place(&mut mbr, 3072, &fat16);
+
+        // GPT with FAT32 in the second used entry
+        let mut gpt = gpt_disk(2048 + 1024 + blocks32 as usize + 34, 128, &[
+            (2048, 3071),
+            (0, 0),
+            (3072, 3071 + blocks32 as u64),
+        ]);
+        place(&mut gpt, 3072, &fat32);
+
+        let mut whole = fs(FatType::Fat12);
+
+        for (disk, kind) in [(&mut mbr, FatType::Fat16), (&mut gpt, FatType::Fat32), (&mut whole, FatType::Fat12)] {
+            let mut buf = vec![0u8; 8192];
+            assert_eq!(load(disk, "/boot/kernel.elf", &mut buf), Ok(5000), "{kind:?}");
+            assert_eq!(buf[..5000], pattern(5000, kind as u32), "{kind:?}");
+            assert_eq!(load(disk, "/boot/missing", &mut buf), Err(Error::NotFound));
+        }
+
+        // A table without any file system
+        let mut empty = mbr_disk(4096, &[], &[(0, 0x83, 2048, 1024)]);
+        assert_eq!(load(&mut empty, "/boot/kernel.elf", &mut [0; 512]), Err(Error::NoFileSystem));
+    }
+}
@@ -0,0 +1,21 @@
+//! RTC general purpose registers, which keep their value across resets
+
+use crate::mmio::*;
+
+const RTC_BASE: u64 = 0x0709_0000;
+
+const RTC_GP_DATA: u64 = 0x0100;
+const RTC_GP_DATA_COUNT: usize = 8;
+
+/// Address of GP_DATA register `n`, out of range numbers wrap around
+pub fn gp_addr(n: usize) -> u64 {
+    RTC_BASE + RTC_GP_DATA + (n % RTC_GP_DATA_COUNT) as u64 * 4
+}
+
+pub fn gp_read(n: usize) -> u32 {
+    unsafe { read32(gp_addr(n)) }
+}
+
+pub fn gp_write(n: usize, v: u32) {
+    unsafe { write32(gp_addr(n), v) }
+}
@@ -0,0 +1,471 @@
+//! Simulated register file behind [`crate::mmio`] in the host builds, for
+//! running drivers in `cargo test` and with the `sim` feature. It needs std,
+//! so it's never in the bootloader image.
+//!
+//! A register reads back what was last written to it, 0 before that. Reads
+//! can be scripted to return a sequence of values, the last of which stays
+//! in the register like a status bit that got set, and a register can
+//! have a callback that sees every access to it and decides what's stored,
+//! to model status bits such as a PLL lock flag. Every access is logged, one
+//! [`Access`] each, whose Display form is a line of a golden trace:
+//!
+//! ```text
+//! W32 0x02001000 0x80000000
+//! R32 0x02001000 0x90000000
+//! ```
+//!
+//! The system timer advances 1 us per reading, so delays and timeouts end.
+//!
+//! UART0 is a console rather than registers: what the code prints is kept
+//! for [`take_console`], what it reads comes from [`input`], and neither is
+//! logged. DRAM is memory as a 512 MiB chip behind the D1's controller
+//! would be, mirrors included, and isn't logged either. [`d1`] adds the status
+//! bits the clock and DRAM init wait for, which is enough to run them and
+//! compare their accesses with the golden traces in testdata/:
+//!
+//! ```text
+//! cargo run --features sim -- clocks > testdata/init_clocks.trace
+//! cargo run --features sim -- dram > testdata/init_dram.trace
+//! ```
+
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Range;
+use std::string::String;
+use std::vec::Vec;
+
+use crate::mmio::{Backend, Word};
+use crate::time::TIMER_FREQ;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Access {
+    pub op: Op,
+    pub addr: u64,
+    pub value: u64,
+    /// In bytes
+    pub width: u8,
+}
+
+impl core::fmt::Display for Access {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let op = match self.op {
+            Op::Read => 'R',
+            Op::Write => 'W',
+        };
+
+        write!(f, "{}{} {:#010x} {:#x}", op, self.width * 8, self.addr, self.value)
+    }
+}
+
+/// Called with the access to its register: the value written, or for a read
+/// the stored one. What it returns is stored, and what a read returns.
+pub type Callback = Box<dyn FnMut(Access) -> u64>;
+
+const UART0: Range<u64> = 0x0250_0000..0x0250_0400;
+const UART_RBR: u64 = 0x0250_0000;
+const UART_LCR: u64 = 0x0250_000c;
+const UART_USR: u64 = 0x0250_007c;
+/// LCR.DLAB, RBR and THR are the divisor latch while it's set
+const UART_LCR_DLAB: u64 = 1 << 7;
+/// USR.RFNE, TFE and TFNF
+const UART_USR_RFNE: u64 = 1 << 3;
+const UART_USR_TX_READY: u64 = 1 << 2 | 1 << 1;
+
+const DRAM: Range<u64> = 0x4000_0000..u64::MAX;
+
+/// The simulated DRAM: one rank of 16 bit DDR3 with 2 KiB pages, 8 banks and
+/// 32768 rows, 512 MiB
+const DRAM_PAGE_BITS: u64 = 11;
+const DRAM_BANK_BITS: u64 = 3;
+const DRAM_ROW_BITS: u64 = 15;
+
+/// How the controller splits addresses into page offset, bank and row
+const MC_WORK_MODE0: u64 = 0x0310_2000;
+
+#[derive(Default)]
+struct State {
+    regs: BTreeMap<u64, u64>,
+    scripts: BTreeMap<u64, VecDeque<u64>>,
+    callbacks: BTreeMap<u64, Callback>,
+    log: Vec<Access>,
+    ticks: u64,
+    /// Written to UART0
+    console: Vec<u8>,
+    /// Waiting in UART0's RX FIFO
+    input: VecDeque<u8>,
+    /// By [`dram_cell`]
+    dram: BTreeMap<u64, u64>,
+}
+
+std::thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+/// [`crate::mmio::Bus`] with the `sim` feature
+pub struct Sim;
+
+impl Sim {
+    fn access(op: Op, addr: u64, value: u64, width: u8) -> u64 {
+        if UART0.contains(&addr) {
+            return Self::uart(op, addr, value);
+        }
+
+        if DRAM.contains(&addr) {
+            return STATE.with_borrow_mut(|s| {
+                let cell = dram_cell(s, addr);
+                match op {
+                    Op::Read => s.dram.get(&cell).copied().unwrap_or(0),
+                    Op::Write => {
+                        s.dram.insert(cell, value);
+                        value
+                    }
+                }
+            });
+        }
+
+        let mut access = Access { op, addr, value, width };
+
+        let callback = STATE.with_borrow_mut(|s| {
+            if op == Op::Read {
+                let scripted = s.scripts.get_mut(&addr).and_then(VecDeque::pop_front);
+                access.value = scripted.unwrap_or_else(|| s.regs.get(&addr).copied().unwrap_or(0));
+            }
+
+            s.callbacks.remove(&addr)
+        });
+
+        // Run without the state borrowed, the callback may poke at other
+        // registers
+        if let Some(mut callback) = callback {
+            access.value = callback(access);
+            STATE.with_borrow_mut(|s| {
+                s.callbacks.entry(addr).or_insert(callback);
+            });
+        }
+
+        STATE.with_borrow_mut(|s| {
+            s.regs.insert(addr, access.value);
+            s.log.push(access);
+        });
+
+        access.value
+    }
+
+    fn uart(op: Op, addr: u64, value: u64) -> u64 {
+        STATE.with_borrow_mut(|s| {
+            let dlab = s.regs.get(&UART_LCR).is_some_and(|lcr| lcr & UART_LCR_DLAB != 0);
+
+            match (op, addr) {
+                (Op::Read, UART_USR) => {
+                    UART_USR_TX_READY | if s.input.is_empty() { 0 } else { UART_USR_RFNE }
+                }
+                (Op::Read, UART_RBR) if !dlab => s.input.pop_front().unwrap_or(0) as u64,
+                (Op::Write, UART_RBR) if !dlab => {
+                    s.console.push(value as u8);
+                    value
+                }
+                (Op::Read, _) => s.regs.get(&addr).copied().unwrap_or(0),
+                (Op::Write, _) => {
+                    s.regs.insert(addr, value);
+                    value
+                }
+            }
+        })
+    }
+}
+
+impl Backend for Sim {
+    unsafe fn read<T: Word>(addr: u64) -> T {
+        T::from_u64(Self::access(Op::Read, addr, 0, T::BYTES))
+    }
+
+    unsafe fn write<T: Word>(addr: u64, v: T) {
+        Self::access(Op::Write, addr, v.to_u64(), T::BYTES);
+    }
+}
+
+/// Where `addr` ends up in the simulated DRAM: MC_WORK_MODE0 says which
+/// address bits are the page offset, bank and row, the chip ignores those
+/// beyond its own, so smaller chips show up as mirrors
+fn dram_cell(s: &State, addr: u64) -> u64 {
+    let mode = s.regs.get(&MC_WORK_MODE0).copied().unwrap_or(0);
+    let page_bits = (mode >> 8 & 0xf) + 3;
+    let bank_bits = (mode >> 2 & 0x3) + 2;
+    let row_bits = (mode >> 4 & 0xf) + 1;
+
+    let bits = |v: u64, n: u64| v & ((1 << n) - 1);
+    let offset = addr - DRAM.start;
+
+    let col = bits(bits(offset, page_bits), DRAM_PAGE_BITS);
+    let bank = bits(bits(offset >> page_bits, bank_bits), DRAM_BANK_BITS);
+    let row = bits(bits(offset >> (page_bits + bank_bits), row_bits), DRAM_ROW_BITS);
+
+    (row << DRAM_BANK_BITS | bank) << DRAM_PAGE_BITS | col
+}
+
+/// Clears the registers, scripts, callbacks, log, timer, console and DRAM
+pub fn reset() {
+    STATE.with_borrow_mut(|s| *s = State::default());
+}
+
+/// Sets a register without logging an access
+pub fn set(addr: u64, value: u64) {
+    STATE.with_borrow_mut(|s| s.regs.insert(addr, value));
+}
+
+/// Reads a register without logging an access or running its callback
+pub fn get(addr: u64) -> u64 {
+    STATE.with_borrow(|s| s.regs.get(&addr).copied().unwrap_or(0))
+}
+
+/// Makes the next reads of `addr` return `values` in order, each replacing
+/// what's stored
+pub fn script(addr: u64, values: &[u64]) {
+    STATE.with_borrow_mut(|s| s.scripts.entry(addr).or_default().extend(values));
+}
+
+/// Runs `callback` on every access to `addr`, replacing an earlier one
+pub fn on_access(addr: u64, callback: impl FnMut(Access) -> u64 + 'static) {
+    STATE.with_borrow_mut(|s| s.callbacks.insert(addr, Box::new(callback)));
+}
+
+/// Returns the accesses since the last call or [`reset`]
+pub fn take_log() -> Vec<Access> {
+    STATE.with_borrow_mut(|s| core::mem::take(&mut s.log))
+}
+
+/// System timer ticks, 1 us more on every call
+pub fn ticks() -> u64 {
+    STATE.with_borrow_mut(|s| {
+        s.ticks += TIMER_FREQ / 1_000_000;
+        s.ticks
+    })
+}
+
+/// Queues `bytes` in UART0's RX FIFO
+pub fn input(bytes: &[u8]) {
+    STATE.with_borrow_mut(|s| s.input.extend(bytes));
+}
+
+/// Returns what was written to UART0 since the last call or [`reset`]
+pub fn take_console() -> String {
+    let console = STATE.with_borrow_mut(|s| core::mem::take(&mut s.console));
+
+    String::from_utf8_lossy(&console).into_owned()
+}
+
+/// One [`Access`] per line
+pub fn format_log(log: &[Access]) -> String {
+    log.iter().map(|a| std::format!("{a}\n")).collect()
+}
+
+/// Panics unless `trace` is `golden`, the contents of testdata/`file`,
+/// showing the first line that differs and how to update the golden file if
+/// the change is intended
+pub fn assert_trace(trace: &str, golden: &str, what: &str, file: &str) {
+    let Some((n, (ours, theirs))) = trace
+        .lines()
+        .chain(std::iter::repeat(""))
+        .zip(golden.lines().chain(std::iter::repeat("")))
+        .take(trace.lines().count().max(golden.lines().count()))
+        .enumerate()
+        .find(|(_, (ours, theirs))| ours != theirs)
+    else {
+        return;
+    };
+
+    panic!(
+        "{what} differs from testdata/{file} at line {}:\n  now:    {ours}\n  golden: {theirs}\n\
+         if that's intended: cargo run --features sim -- {what} > testdata/{file}",
+        n + 1
+    );
+}
+
+/// PLL control registers of the CCU, whose LOCK bit (28) follows PLL_EN (31)
+/// and LOCK_ENABLE (29)
+const PLLS: [u64; 9] = [
+    0x0200_1000, // PLL_CPU
+    0x0200_1010, // PLL_DDR
+    0x0200_1020, // PLL_PERI
+    0x0200_1040, // PLL_VIDEO0
+    0x0200_1048, // PLL_VIDEO1
+    0x0200_1058, // PLL_VE
+    0x0200_1078, // PLL_AUDIO0
+    0x0200_1080, // PLL_AUDIO1
+    0x0200_10e0, // PLL_CSI
+];
+
+/// PLL_PERI as the boot ROM leaves it: enabled and locked, N = 100, P0 = 2
+/// and P1 = 3, for 1.2 GHz at 2X, 600 MHz at 1X and 800 MHz
+const PLL_PERI_BOOT: u64 = 0xf821_6300;
+
+/// DRAM controller: MCTL_COM and MCTL_PHY
+const MCTL_PHY_PIR: u64 = 0x0310_3000;
+const MCTL_PHY_PGSR0: u64 = 0x0310_3010;
+const MCTL_PHY_STATR: u64 = 0x0310_3018;
+/// PGSR0.IDONE
+const PGSR0_IDONE: u64 = 1 << 0;
+/// PGSR0.QSGERR, DQS gating failed on the rank that isn't there
+const PGSR0_QSGERR: u64 = 1 << 22;
+/// DX0GSR0 and DX1GSR0, whose bits 25:24 are 2 for a byte lane that trained
+const MCTL_PHY_DX0GSR0: u64 = 0x0310_3348;
+const MCTL_PHY_DX1GSR0: u64 = 0x0310_33c8;
+/// PWRCTL, 0x3103004 bit 0, asks for self-refresh, which STATR reports as 3
+const MCTL_PHY_PWRCTL: u64 = 0x0310_3004;
+
+/// Models the D1 as far as the clock and DRAM init wait for it, on top of
+/// what [`reset`] left:
+/// - PLL_PERI running as the boot ROM leaves it
+/// - every PLL locking as soon as it's enabled with its lock detection
+/// - the DRAM PHY finishing each PIR command at once, finding a single rank
+///   with both byte lanes (16 bit DDR3), and the controller entering and
+///   leaving self-refresh at once
+/// - 512 MiB of DRAM, which the size scan finds through its mirrors
+pub fn d1() {
+    set(PLLS[2], PLL_PERI_BOOT);
+
+    for pll in PLLS {
+        on_access(pll, |a| {
+            let locked = a.value & (1 << 31 | 1 << 29) == 1 << 31 | 1 << 29;
+            a.value & !(1 << 28) | (locked as u64) << 28
+        });
+    }
+
+    on_access(MCTL_PHY_PIR, |a| {
+        if a.op == Op::Write && a.value & 1 != 0 {
+            set(MCTL_PHY_PGSR0, PGSR0_QSGERR | PGSR0_IDONE);
+        }
+        a.value
+    });
+
+    on_access(MCTL_PHY_PWRCTL, |a| {
+        if a.op == Op::Write {
+            set(MCTL_PHY_STATR, if a.value & 1 != 0 { 3 } else { 1 });
+        }
+        a.value
+    });
+
+    set(MCTL_PHY_STATR, 1);
+    set(MCTL_PHY_DX0GSR0, 2 << 24);
+    set(MCTL_PHY_DX1GSR0, 2 << 24);
+}
+
+/// Every [`register!`](crate::mmio::register) definition, one line per
+/// register and field, for comparing with the D1 user manual:
+///
+/// ```text
+/// CPU_AXI_CFG 0x02001500
+///   CPU_CLK_SEL 26:24 Hosc=0 Rtc32k=1 ...
+///   CPU_DIV2 9:8
+/// ```
+pub fn registers() -> String {
+    use crate::mmio::RegisterDef;
+
+    let all: [&[RegisterDef]; 3] = [crate::ccu::regs::REGISTERS, crate::uart::uart0::REGISTERS, crate::uart::gpio::REGISTERS];
+    let mut out = String::new();
+
+    for reg in all.into_iter().flatten() {
+        out += &std::format!("{} 0x{:08x}\n", reg.name, reg.addr);
+
+        for f in reg.fields {
+            let bits = match f.len {
+                1 => std::format!("{}", f.shift),
+                _ => std::format!("{}:{}", f.shift + f.len - 1, f.shift),
+            };
+            let values: String = f.values.iter().map(|(name, v)| std::format!(" {name}={v}")).collect();
+
+            out += &std::format!("  {} {bits}{values}\n", f.name);
+        }
+    }
+
+    out
+}
+
+/// The register accesses of [`crate::ccu::init_clocks`] on [`d1`]
+pub fn init_clocks_trace() -> String {
+    reset();
+    d1();
+
+    unsafe { crate::ccu::init_clocks() };
+
+    format_log(&take_log())
+}
+
+/// The register accesses of [`crate::dram::init_dram`] on [`d1`], after the
+/// clock init
+pub fn init_dram_trace() -> String {
+    reset();
+    d1();
+
+    unsafe { crate::ccu::init_clocks() };
+    take_log();
+
+    unsafe { crate::dram::init_dram() };
+
+    format_log(&take_log())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmio::{read32, write32, write8};
+
+    #[test]
+    fn scripts_callbacks_and_log() {
+        reset();
+        script(0x1000, &[1, 2]);
+        on_access(0x2000, |a| a.value | 0x100);
+
+        let reads: Vec<u32> = (0..3).map(|_| unsafe { read32(0x1000) }).collect();
+        assert_eq!(reads, [1, 2, 2]);
+
+        unsafe { write32(0x2000, 5) };
+        assert_eq!(get(0x2000), 0x105);
+        unsafe { write8(0x3000, 0xab) };
+
+        let log = format_log(&take_log());
+        assert_eq!(
+            log,
+            "R32 0x00001000 0x1\nR32 0x00001000 0x2\nR32 0x00001000 0x2\n\
+             W32 0x00002000 0x105\nW8 0x00003000 0xab\n"
+        );
+        assert!(take_log().is_empty());
+    }
+
+    #[test]
+    fn uart0_is_a_console() {
+        reset();
+        input(b"ok\r");
+
+        crate::uart::printf!("%d\r\n", 42u32);
+        let line: Vec<u8> = (0..3).map(|_| crate::uart::uart_read()).collect();
+
+        assert_eq!(line, b"ok\r");
+        assert_eq!(crate::uart::uart_try_read(), None);
+        assert_eq!(take_console(), "42\r\n");
+        assert!(take_log().is_empty());
+    }
+
+    #[test]
+    fn dram_mirrors_beyond_the_chip() {
+        reset();
+        // 4 KiB pages, 8 banks, 16 rows: one row and page bit too many
+        set(MC_WORK_MODE0, 0x9f4);
+
+        unsafe { write32(0x4000_0000, 1) };
+        unsafe { write32(0x4000_0800, 2) };
+        unsafe { write32(0x4000_0000 + (1 << 29), 4) };
+
+        assert_eq!(unsafe { read32(0x4000_0000) }, 2);
+        assert_eq!(unsafe { read32(0x4000_0000 + (1 << 30)) }, 2);
+        assert_eq!(unsafe { read32(0x4000_0000 + (1 << 29)) }, 4);
+        assert!(take_log().is_empty());
+    }
+}
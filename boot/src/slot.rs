@@ -0,0 +1,314 @@
+//! A/B kernel slots with a boot counter
+//!
+//! Each reset runs [`State::select`] once: a slot the kernel confirmed boots
+//! as is, an unconfirmed one gets a limited number of tries before the other
+//! slot takes over. Installing a new kernel goes through
+//! [`State::set_active`], which starts that slot over with no confirmation.
+//!
+//! The state machine only deals with [`State`] values and their 32-bit
+//! encoding, where the word is kept is up to [`load`] and [`store`].
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Slot {
+    A = 0,
+    B = 1,
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Slot::A => "a",
+            Slot::B => "b",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Slot> {
+        match s {
+            "a" => Some(Slot::A),
+            "b" => Some(Slot::B),
+            _ => None,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct State {
+    pub active: Slot,
+    /// Boots of the active slot so far, only counted while it's unconfirmed
+    pub tries: u8,
+    /// Slots confirmed by their kernel, one bit per slot
+    successful: u8,
+    /// Slots that ran out of tries, one bit per slot
+    failed: u8,
+}
+
+/// Marks a valid state word in bits 31:16
+const MAGIC: u32 = 0xab51;
+const TRIES_MAX: u8 = 0x3f;
+
+impl State {
+    /// Where a board without any state starts
+    pub const fn new() -> Self {
+        Self {
+            active: Slot::A,
+            tries: 0,
+            successful: 0,
+            failed: 0,
+        }
+    }
+
+    pub fn is_successful(&self, slot: Slot) -> bool {
+        self.successful & slot.bit() != 0
+    }
+
+    pub fn is_failed(&self, slot: Slot) -> bool {
+        self.failed & slot.bit() != 0
+    }
+
+    /// Picks the slot to boot and counts the attempt. A slot that's still
+    /// unconfirmed after `max_tries` boots is marked failed and the other
+    /// one is used. None if both failed.
+    pub fn select(self, max_tries: u8) -> (State, Option<Slot>) {
+        let mut state = self;
+
+        for _ in 0..2 {
+            let slot = state.active;
+
+            if state.is_successful(slot) {
+                return (state, Some(slot));
+            }
+
+            if !state.is_failed(slot) && state.tries < max_tries.min(TRIES_MAX) {
+                state.tries += 1;
+                return (state, Some(slot));
+            }
+
+            state.failed |= slot.bit();
+            state.active = slot.other();
+            state.tries = 0;
+        }
+
+        (state, None)
+    }
+
+    /// What the kernel reports once it's up: the active slot is good
+    pub fn confirm(self) -> State {
+        State {
+            tries: 0,
+            successful: self.successful | self.active.bit(),
+            failed: self.failed & !self.active.bit(),
+            ..self
+        }
+    }
+
+    /// Makes `slot` active as if it had a new kernel: unconfirmed, no tries
+    pub fn set_active(self, slot: Slot) -> State {
+        State {
+            active: slot,
+            tries: 0,
+            successful: self.successful & !slot.bit(),
+            failed: self.failed & !slot.bit(),
+        }
+    }
+
+    /// Magic in 31:16, failed in 11:10, successful in 9:8, tries in 6:1 and
+    /// the active slot in bit 0
+    pub fn encode(&self) -> u32 {
+        (MAGIC << 16)
+            | (self.failed as u32) << 10
+            | (self.successful as u32) << 8
+            | (self.tries as u32) << 1
+            | self.active as u32
+    }
+
+    pub fn decode(word: u32) -> Option<State> {
+        if word >> 16 != MAGIC || word & 0xf080 != 0 {
+            return None;
+        }
+
+        Some(State {
+            active: if word & 1 == 0 { Slot::A } else { Slot::B },
+            tries: ((word >> 1) & TRIES_MAX as u32) as u8,
+            successful: ((word >> 8) & 3) as u8,
+            failed: ((word >> 10) & 3) as u8,
+        })
+    }
+}
+
+/// RTC general purpose register holding the state word. It keeps its value
+/// across resets for as long as the RTC has power.
+const STATE_REG: usize = 7;
+
+pub fn state_addr() -> u64 {
+    crate::rtc::gp_addr(STATE_REG)
+}
+
+/// The stored state, [`State::new`] if there's none
+pub fn load() -> State {
+    State::decode(crate::rtc::gp_read(STATE_REG)).unwrap_or(State::new())
+}
+
+pub fn store(state: State) {
+    crate::rtc::gp_write(STATE_REG, state.encode());
+}
+
+/// Slots set in a [`State`] bit mask
+fn slots_str(bits: u8) -> &'static str {
+    match bits & 3 {
+        0 => "none",
+        1 => "a",
+        2 => "b",
+        _ => "a b",
+    }
+}
+
+pub fn print_state(state: &State) {
+    crate::uart::printf!(
+        "slot %s, try %d, successful: %s, failed: %s\r\n",
+        state.active.as_str(),
+        state.tries,
+        slots_str(state.successful),
+        slots_str(state.failed)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Slot::{A, B};
+
+    const NONE: u8 = 0;
+    const BIT_A: u8 = 1;
+    const BIT_B: u8 = 2;
+    const BOTH: u8 = 3;
+
+    fn state(active: Slot, tries: u8, successful: u8, failed: u8) -> State {
+        State {
+            active,
+            tries,
+            successful,
+            failed,
+        }
+    }
+
+    #[test]
+    fn select() {
+        let cases = [
+            // Unconfirmed slots count their tries
+            (state(A, 0, NONE, NONE), 3, state(A, 1, NONE, NONE), Some(A)),
+            (state(A, 2, NONE, NONE), 3, state(A, 3, NONE, NONE), Some(A)),
+            (state(B, 1, BIT_A, NONE), 3, state(B, 2, BIT_A, NONE), Some(B)),
+            // Out of tries: fall back to the other slot
+            (state(A, 3, NONE, NONE), 3, state(B, 1, NONE, BIT_A), Some(B)),
+            (state(A, 3, BIT_B, NONE), 3, state(B, 0, BIT_B, BIT_A), Some(B)),
+            (state(B, 3, BIT_A, NONE), 3, state(A, 0, BIT_A, BIT_B), Some(A)),
+            (state(A, 0, NONE, BIT_A), 3, state(B, 1, NONE, BIT_A), Some(B)),
+            // Confirmed slots boot without counting
+            (state(A, 0, BIT_A, NONE), 3, state(A, 0, BIT_A, NONE), Some(A)),
+            (state(B, 0, BOTH, BIT_A), 3, state(B, 0, BOTH, BIT_A), Some(B)),
+            // Nothing left to boot
+            (state(A, 3, NONE, BIT_B), 3, state(A, 0, NONE, BOTH), None),
+            (state(B, 0, NONE, BOTH), 3, state(B, 0, NONE, BOTH), None),
+            (state(A, 0, NONE, NONE), 0, state(A, 0, NONE, BOTH), None),
+            // Tries are capped by what the state word holds
+            (state(A, 62, NONE, NONE), 100, state(A, 63, NONE, NONE), Some(A)),
+            (state(A, 63, NONE, NONE), 100, state(B, 1, NONE, BIT_A), Some(B)),
+        ];
+
+        for (before, max_tries, after, slot) in cases {
+            assert_eq!(before.select(max_tries), (after, slot), "{before:?} {max_tries}");
+        }
+    }
+
+    #[test]
+    fn confirm() {
+        let cases = [
+            (state(A, 2, NONE, NONE), state(A, 0, BIT_A, NONE)),
+            (state(B, 1, NONE, BIT_A), state(B, 0, BIT_B, BIT_A)),
+            (state(A, 1, BIT_B, BOTH), state(A, 0, BOTH, BIT_B)),
+            (state(A, 0, BIT_A, NONE), state(A, 0, BIT_A, NONE)),
+        ];
+
+        for (before, after) in cases {
+            assert_eq!(before.confirm(), after, "{before:?}");
+        }
+
+        assert_eq!(state(A, 0, BIT_A, BIT_B).set_active(B), state(B, 0, BIT_A, NONE));
+        assert_eq!(state(B, 3, BOTH, NONE).set_active(B), state(B, 0, BIT_A, NONE));
+    }
+
+    /// Resets with the state word kept across them, as in the RTC, and
+    /// whether the kernel confirms on each
+    fn boots(word: &mut u32, confirms: &[bool]) -> std::vec::Vec<Option<Slot>> {
+        let boot = |word: &mut u32, confirm| {
+            let (state, slot) = State::decode(*word).unwrap_or(State::new()).select(3);
+            *word = if confirm { state.confirm() } else { state }.encode();
+            slot
+        };
+
+        confirms.iter().map(|&confirm| boot(word, confirm)).collect()
+    }
+
+    #[test]
+    fn boot_sequence() {
+        let mut word = 0;
+
+        // A new A that never comes up
+        assert_eq!(boots(&mut word, &[false, false, false]), [Some(A); 3]);
+        // B takes over and confirms, from then on it's kept
+        assert_eq!(boots(&mut word, &[true, false, false, false]), [Some(B); 4]);
+
+        // A new kernel in A gets its tries back, then confirms
+        word = State::decode(word).unwrap().set_active(A).encode();
+        assert_eq!(boots(&mut word, &[false, true, false, false]), [Some(A); 4]);
+        assert_eq!(State::decode(word), Some(state(A, 0, BOTH, NONE)));
+    }
+
+    #[test]
+    fn unconfirmed_slot_arms_the_watchdog() {
+        let cases = [
+            (State::new(), Some(A), 16000),
+            (state(B, 0, BIT_B, NONE), Some(B), 0),
+            (state(A, 2, NONE, NONE), Some(A), 16000),
+            (state(A, 3, BIT_B, NONE), Some(B), 0),
+            (state(A, 0, NONE, BOTH), None, 0),
+        ];
+
+        for (before, slot, watchdog_ms) in cases {
+            crate::sim::reset();
+            store(before);
+            crate::handoff::init();
+
+            let selected = crate::select_slot().map(|(bootcmd, _)| bootcmd);
+            assert_eq!(selected, slot.map(|s| if s == A { crate::BOOTCMD } else { crate::BOOTCMD_B }), "{before:?}");
+            assert_eq!(crate::handoff::boot_info().watchdog_ms, watchdog_ms, "{before:?}");
+
+            crate::handoff::hand_over();
+            assert_eq!(crate::wdt::WDT.is_enabled(), watchdog_ms != 0, "{before:?}");
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        for s in [state(A, 0, NONE, NONE), state(B, 63, BOTH, BOTH), state(A, 5, BIT_B, BIT_A)] {
+            assert_eq!(State::decode(s.encode()), Some(s));
+        }
+
+        assert_eq!(state(B, 3, BIT_A, BIT_B).encode(), 0xab51_0907);
+        assert_eq!(State::decode(0), None);
+        assert_eq!(State::decode(0xab52_0000), None);
+        assert_eq!(State::decode(0xab51_1000), None);
+        assert_eq!(State::decode(0xab51_0080), None);
+    }
+}
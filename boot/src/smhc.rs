@@ -0,0 +1,780 @@
+//! SD card driver for the SMHC0 host controller (PF0-PF5 on the D1)
+//!
+//! Data goes through the FIFO by CPU reads, no DMA. Cards are identified as
+//! described in the SD Physical Layer Simplified Specification: SD v1 and v2
+//! standard capacity cards are byte addressed, SDHC/SDXC cards are block
+//! addressed. All register accesses go through [`Smhc::read`] and
+//! [`Smhc::write`] relative to the controller base.
+
+use crate::block::{BLOCK_SIZE, BlockDevice, Error};
+use crate::ccu::Peripheral;
+use crate::mmio::*;
+use crate::time::*;
+
+pub const SMHC0_BASE: u64 = 0x0402_0000;
+
+const GPIO_BASE: u64 = 0x0200_0000;
+const GPIO_PF_CFG0: u64 = 0x00f0;
+const GPIO_PF_DRV0: u64 = 0x0104;
+const GPIO_PF_PULL0: u64 = 0x0114;
+
+const SMHC_CTRL: u64 = 0x0000;
+const SMHC_CLKDIV: u64 = 0x0004;
+const SMHC_TMOUT: u64 = 0x0008;
+const SMHC_CTYPE: u64 = 0x000c;
+const SMHC_BLKSIZ: u64 = 0x0010;
+const SMHC_BYTCNT: u64 = 0x0014;
+const SMHC_CMD: u64 = 0x0018;
+const SMHC_CMDARG: u64 = 0x001c;
+const SMHC_RESP0: u64 = 0x0020;
+const SMHC_INTMASK: u64 = 0x0030;
+const SMHC_RINTSTS: u64 = 0x0038;
+const SMHC_STATUS: u64 = 0x003c;
+const SMHC_NTSR: u64 = 0x005c;
+const SMHC_FIFO: u64 = 0x0200;
+
+const CTRL_SOFT_RST: u32 = 1 << 0;
+const CTRL_FIFO_RST: u32 = 1 << 1;
+const CTRL_DMA_RST: u32 = 1 << 2;
+const CTRL_FIFO_AC_MOD: u32 = 1 << 31; // FIFO accessed over AHB
+
+const CLKDIV_CCLK_ENB: u32 = 1 << 16;
+
+const CMD_RESP_RCV: u32 = 1 << 6;
+const CMD_LONG_RESP: u32 = 1 << 7;
+const CMD_CHK_RESP_CRC: u32 = 1 << 8;
+const CMD_DATA_TRANS: u32 = 1 << 9;
+const CMD_STOP_CMD_FLAG: u32 = 1 << 12; // auto CMD12 after the transfer
+const CMD_WAIT_PRE_OVER: u32 = 1 << 13;
+const CMD_SEND_INIT_SEQ: u32 = 1 << 15;
+const CMD_PRG_CLK: u32 = 1 << 21;
+const CMD_USE_HOLD_REG: u32 = 1 << 29;
+const CMD_LOAD: u32 = 1 << 31;
+
+const INT_CC: u32 = 1 << 2;
+const INT_DTC: u32 = 1 << 3;
+const INT_RTO: u32 = 1 << 8;
+const INT_ACD: u32 = 1 << 14;
+const INT_CRC: u32 = (1 << 6) | (1 << 7); // RCE | DCE
+const INT_TIMEOUT: u32 = INT_RTO | (1 << 9) | (1 << 10); // RTO | DTO | DSTO
+const INT_ERRORS: u32 = INT_CRC | INT_TIMEOUT | (1 << 1) | (1 << 11) | (1 << 12) | (1 << 13) | (1 << 15);
+
+const STATUS_FIFO_EMPTY: u32 = 1 << 2;
+const STATUS_CARD_BUSY: u32 = 1 << 9;
+
+const IDENT_CLOCK: u64 = 400_000;
+const TRANSFER_CLOCK: u64 = 25_000_000;
+
+const CMD_TIMEOUT_US: u64 = 100_000;
+const DATA_TIMEOUT_US: u64 = 500_000;
+const OCR_TIMEOUT_US: u64 = 1_000_000;
+
+/// Voltage window 2.7-3.6 V
+const OCR_VOLTAGES: u32 = 0x00ff_8000;
+const OCR_HCS: u32 = 1 << 30;
+const OCR_BUSY: u32 = 1 << 31;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Resp {
+    None,
+    /// 48 bit response with CRC (R1, R6, R7)
+    Short,
+    /// R1 followed by busy signalling on DAT0
+    ShortBusy,
+    /// 136 bit response (R2)
+    Long,
+    /// 48 bit response without a valid CRC (R3)
+    NoCrc,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CardType {
+    /// SD v1.x, standard capacity
+    SdV1,
+    /// SD v2.0+, standard capacity
+    SdV2,
+    /// SDHC/SDXC, block addressed
+    Sdhc,
+}
+
+pub struct Smhc {
+    base: u64,
+    card: Option<CardType>,
+    rca: u32,
+    blocks: u64,
+}
+
+static mut SD0: Smhc = Smhc::new(SMHC0_BASE);
+
+/// The card in the SMHC0 slot, initialized on first use
+pub fn sd0() -> Result<&'static mut Smhc, Error> {
+    let sd = unsafe { &mut *core::ptr::addr_of_mut!(SD0) };
+
+    if sd.card.is_none() {
+        unsafe { init_pins() };
+        sd.init()?;
+    }
+
+    Ok(sd)
+}
+
+/// Routes PF0-PF5 to SMHC0 with pull-ups on CMD and DAT
+unsafe fn init_pins() {
+    unsafe {
+        Reg32::read(GPIO_BASE + GPIO_PF_CFG0)
+            .set_field::<0, 4>(2) // PF0 = SDC0_D1
+            .set_field::<4, 4>(2) // PF1 = SDC0_D0
+            .set_field::<8, 4>(2) // PF2 = SDC0_CLK
+            .set_field::<12, 4>(2) // PF3 = SDC0_CMD
+            .set_field::<16, 4>(2) // PF4 = SDC0_D3
+            .set_field::<20, 4>(2) // PF5 = SDC0_D2
+            .write();
+
+        Reg32::read(GPIO_BASE + GPIO_PF_DRV0)
+            .set_field::<0, 24>(0x22_2222) // PF0-PF5 drive level 2, 4 bits per pin
+            .write();
+
+        Reg32::read(GPIO_BASE + GPIO_PF_PULL0)
+            .set_field::<0, 12>(0x545) // pull-up on all but PF2 (CLK), 2 bits per pin
+            .write();
+    }
+}
+
+/// Bits `start..start + len` of a 128 bit register (CSD, CID) with bit 0 in
+/// the LSB of `regs[0]`
+fn bits(regs: &[u32; 4], start: u32, len: u32) -> u32 {
+    let v = (regs[3] as u128) << 96 | (regs[2] as u128) << 64 | (regs[1] as u128) << 32 | regs[0] as u128;
+    ((v >> start) as u32) & ((1u64 << len) - 1) as u32
+}
+
+/// Capacity in blocks from the CSD register
+fn csd_blocks(csd: &[u32; 4]) -> u64 {
+    match bits(csd, 126, 2) {
+        // CSD version 1.0: (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) * 2^READ_BL_LEN bytes
+        0 => {
+            let c_size = bits(csd, 62, 12) as u64;
+            let mult = bits(csd, 47, 3);
+            let read_bl_len = bits(csd, 80, 4);
+            ((c_size + 1) << (mult + 2 + read_bl_len)) / BLOCK_SIZE as u64
+        }
+        // CSD version 2.0: (C_SIZE + 1) * 512 KiB
+        _ => (bits(csd, 48, 22) as u64 + 1) * 1024,
+    }
+}
+
+impl Smhc {
+    pub const fn new(base: u64) -> Self {
+        Self {
+            base,
+            card: None,
+            rca: 0,
+            blocks: 0,
+        }
+    }
+
+    fn read(&self, reg: u64) -> u32 {
+        unsafe { read32(self.base + reg) }
+    }
+
+    fn write(&self, reg: u64, v: u32) {
+        unsafe { write32(self.base + reg, v) }
+    }
+
+    /// Polls `reg` until `(value & mask) == expected`, returns the last value
+    fn wait(&self, reg: u64, mask: u32, expected: u32, timeout_us: u64) -> Result<u32, Error> {
+        unsafe { wait_mask(self.base + reg, mask, expected, timeout_us) }.map_err(|_| Error::Timeout)
+    }
+
+    pub fn card_type(&self) -> Option<CardType> {
+        self.card
+    }
+
+    /// Resets the controller and clears all card state
+    fn reset(&mut self) -> Result<(), Error> {
+        self.card = None;
+        self.rca = 0;
+        self.blocks = 0;
+
+        self.write(SMHC_CTRL, CTRL_SOFT_RST | CTRL_FIFO_RST | CTRL_DMA_RST);
+        self.wait(SMHC_CTRL, CTRL_SOFT_RST | CTRL_FIFO_RST | CTRL_DMA_RST, 0, CMD_TIMEOUT_US)?;
+
+        self.write(SMHC_CTRL, CTRL_FIFO_AC_MOD);
+        self.write(SMHC_TMOUT, 0xffff_ffff);
+        self.write(SMHC_INTMASK, 0);
+        self.write(SMHC_RINTSTS, 0xffff_ffff);
+        self.write(SMHC_CTYPE, 0); // 1 bit bus
+        self.write(SMHC_NTSR, 0); // old timing mode, card clock = module clock / (2 * div)
+
+        Ok(())
+    }
+
+    /// Makes the controller pick up a new CLKDIV setting
+    fn update_clock(&self) -> Result<(), Error> {
+        self.write(SMHC_CMD, CMD_LOAD | CMD_PRG_CLK | CMD_WAIT_PRE_OVER);
+        self.wait(SMHC_CMD, CMD_LOAD, 0, CMD_TIMEOUT_US)?;
+        self.write(SMHC_RINTSTS, 0xffff_ffff);
+        Ok(())
+    }
+
+    fn set_card_clock(&self, hz: u64) -> Result<(), Error> {
+        self.write(SMHC_CLKDIV, self.read(SMHC_CLKDIV) & !CLKDIV_CCLK_ENB);
+        self.update_clock()?;
+
+        // The module clock runs at twice the card clock, CCLK_DIV = 1 halves it
+        unsafe {
+            crate::ccu::enable(Peripheral::Smhc0);
+            crate::ccu::set_module_clock(Peripheral::Smhc0, hz * 2);
+        }
+
+        self.write(SMHC_CLKDIV, CLKDIV_CCLK_ENB | 1);
+        self.update_clock()
+    }
+
+    /// Sends a command and waits for its completion. For data commands
+    /// `data` receives `data.len() / BLOCK_SIZE` blocks, transfers of more than
+    /// one block end with an automatic CMD12. Returns RESP0-RESP3.
+    fn command(&self, idx: u32, arg: u32, resp: Resp, data: Option<&mut [u8]>) -> Result<[u32; 4], Error> {
+        let mut flags = CMD_LOAD | CMD_USE_HOLD_REG | CMD_WAIT_PRE_OVER | idx;
+
+        flags |= match resp {
+            Resp::None => 0,
+            Resp::Short | Resp::ShortBusy => CMD_RESP_RCV | CMD_CHK_RESP_CRC,
+            Resp::Long => CMD_RESP_RCV | CMD_LONG_RESP | CMD_CHK_RESP_CRC,
+            Resp::NoCrc => CMD_RESP_RCV,
+        };
+
+        if idx == 0 {
+            flags |= CMD_SEND_INIT_SEQ;
+        }
+
+        let mut done = INT_CC;
+
+        if let Some(buf) = &data {
+            flags |= CMD_DATA_TRANS;
+            done |= INT_DTC;
+
+            if buf.len() > BLOCK_SIZE {
+                flags |= CMD_STOP_CMD_FLAG;
+                done |= INT_ACD;
+            }
+
+            self.write(SMHC_CTRL, self.read(SMHC_CTRL) | CTRL_FIFO_RST);
+            self.wait(SMHC_CTRL, CTRL_FIFO_RST, 0, CMD_TIMEOUT_US)?;
+            self.write(SMHC_BLKSIZ, BLOCK_SIZE as u32);
+            self.write(SMHC_BYTCNT, buf.len() as u32);
+        }
+
+        self.write(SMHC_RINTSTS, 0xffff_ffff);
+        self.write(SMHC_CMDARG, arg);
+        self.write(SMHC_CMD, flags);
+
+        let result = match data {
+            Some(buf) => self.read_fifo(buf),
+            None => Ok(()),
+        };
+
+        let result = result.and_then(|_| self.wait_done(done));
+
+        if let Err(e) = result {
+            // Leave the controller ready for the next command
+            self.write(SMHC_CTRL, self.read(SMHC_CTRL) | CTRL_FIFO_RST);
+            self.write(SMHC_RINTSTS, 0xffff_ffff);
+            return Err(e);
+        }
+
+        if resp == Resp::ShortBusy {
+            self.wait(SMHC_STATUS, STATUS_CARD_BUSY, 0, DATA_TIMEOUT_US)?;
+        }
+
+        Ok([
+            self.read(SMHC_RESP0),
+            self.read(SMHC_RESP0 + 4),
+            self.read(SMHC_RESP0 + 8),
+            self.read(SMHC_RESP0 + 12),
+        ])
+    }
+
+    /// Waits until all bits in `done` are set in RINTSTS or an error shows up
+    fn wait_done(&self, done: u32) -> Result<(), Error> {
+        let deadline = deadline(DATA_TIMEOUT_US);
+
+        loop {
+            let status = self.check_status()?;
+            if status & done == done {
+                return Ok(());
+            }
+            if unsafe { timer_csr() } > deadline {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    /// Reads RINTSTS and maps error bits to an error
+    fn check_status(&self) -> Result<u32, Error> {
+        let status = self.read(SMHC_RINTSTS);
+
+        if status & INT_ERRORS == 0 {
+            Ok(status)
+        } else if status & INT_TIMEOUT != 0 {
+            Err(Error::Timeout)
+        } else if status & INT_CRC != 0 {
+            Err(Error::Crc)
+        } else {
+            Err(Error::Io)
+        }
+    }
+
+    fn read_fifo(&self, buf: &mut [u8]) -> Result<(), Error> {
+        let deadline = deadline(DATA_TIMEOUT_US);
+
+        for word in buf.chunks_exact_mut(4) {
+            while self.read(SMHC_STATUS) & STATUS_FIFO_EMPTY != 0 {
+                self.check_status()?;
+                if unsafe { timer_csr() } > deadline {
+                    return Err(Error::Timeout);
+                }
+            }
+
+            word.copy_from_slice(&self.read(SMHC_FIFO).to_le_bytes());
+        }
+
+        Ok(())
+    }
+
+    fn app_command(&self, idx: u32, arg: u32, resp: Resp) -> Result<[u32; 4], Error> {
+        self.command(55, self.rca << 16, Resp::Short, None)?; // APP_CMD
+        self.command(idx, arg, resp, None)
+    }
+
+    /// Resets the controller and runs the SD identification sequence, leaving
+    /// the card selected on a 4 bit bus at 25 MHz
+    pub fn init(&mut self) -> Result<(), Error> {
+        self.reset()?;
+        self.set_card_clock(IDENT_CLOCK)?;
+
+        self.command(0, 0, Resp::None, None)?; // GO_IDLE_STATE
+
+        // SEND_IF_COND: 2.7-3.6 V, check pattern 0xaa. Only v2 cards answer.
+        let v2 = match self.command(8, 0x1aa, Resp::Short, None) {
+            Ok(r) if r[0] & 0xfff == 0x1aa => true,
+            Ok(_) => return Err(Error::Unsupported),
+            Err(Error::Timeout) => false,
+            Err(e) => return Err(e),
+        };
+
+        let hcs = if v2 { OCR_HCS } else { 0 };
+        let deadline = deadline(OCR_TIMEOUT_US);
+
+        let ocr = loop {
+            // SD_SEND_OP_COND
+            let ocr = self
+                .app_command(41, hcs | OCR_VOLTAGES, Resp::NoCrc)
+                .map_err(|e| if e == Error::Timeout { Error::NoDevice } else { e })?[0];
+
+            if ocr & OCR_BUSY != 0 {
+                break ocr;
+            }
+            if unsafe { timer_csr() } > deadline {
+                return Err(Error::Timeout);
+            }
+
+            udelay(1000);
+        };
+
+        let card = match (v2, ocr & OCR_HCS != 0) {
+            (true, true) => CardType::Sdhc,
+            (true, false) => CardType::SdV2,
+            (false, _) => CardType::SdV1,
+        };
+
+        self.command(2, 0, Resp::Long, None)?; // ALL_SEND_CID
+        self.rca = self.command(3, 0, Resp::Short, None)?[0] >> 16; // SEND_RELATIVE_ADDR
+
+        let csd = self.command(9, self.rca << 16, Resp::Long, None)?; // SEND_CSD
+        let blocks = csd_blocks(&csd);
+
+        self.command(7, self.rca << 16, Resp::ShortBusy, None)?; // SELECT_CARD
+
+        self.app_command(6, 2, Resp::Short)?; // SET_BUS_WIDTH 4 bit
+        self.write(SMHC_CTYPE, 1);
+
+        if card != CardType::Sdhc {
+            self.command(16, BLOCK_SIZE as u32, Resp::Short, None)?; // SET_BLOCKLEN
+        }
+
+        self.set_card_clock(TRANSFER_CLOCK)?;
+
+        self.card = Some(card);
+        self.blocks = blocks;
+
+        Ok(())
+    }
+
+    pub fn print_info(&self) {
+        let kind = match self.card {
+            None => "none",
+            Some(CardType::SdV1) => "SD v1",
+            Some(CardType::SdV2) => "SD v2",
+            Some(CardType::Sdhc) => "SDHC/SDXC",
+        };
+
+        crate::uart::printf!(
+            "SMHC0: %s card, rca %04x, %d blocks (%d MiB)\r\n",
+            kind,
+            self.rca,
+            self.blocks,
+            self.blocks / 2048
+        );
+    }
+}
+
+impl BlockDevice for Smhc {
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let Some(card) = self.card else {
+            return Err(Error::NoDevice);
+        };
+
+        let count = (buf.len() / BLOCK_SIZE) as u64;
+        if count == 0 {
+            return Ok(());
+        }
+        if lba + count > self.blocks {
+            return Err(Error::OutOfRange);
+        }
+
+        // BYTCNT is 32 bits wide, keep transfers well below that
+        for (i, chunk) in buf[..count as usize * BLOCK_SIZE].chunks_mut(BLOCK_SIZE * 1024).enumerate() {
+            let lba = lba + i as u64 * 1024;
+            let arg = match card {
+                CardType::Sdhc => lba as u32,
+                _ => (lba * BLOCK_SIZE as u64) as u32,
+            };
+
+            let idx = if chunk.len() > BLOCK_SIZE {
+                18 // READ_MULTIPLE_BLOCK
+            } else {
+                17 // READ_SINGLE_BLOCK
+            };
+
+            self.command(idx, arg, Resp::Short, Some(chunk))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Op};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    const RCA: u32 = 0xb368;
+
+    /// CSD version 2.0 with C_SIZE = 15159, 7580 MiB
+    const CSD_V2: u128 = 1 << 126 | 15159 << 48;
+    /// CSD version 1.0 with C_SIZE = 4095, C_SIZE_MULT = 7 and 1 KiB blocks,
+    /// 2 GiB
+    const CSD_V1: u128 = 4095 << 62 | 7 << 47 | 10 << 80;
+
+    /// SD card behind the simulated SMHC0, answering commands the way the
+    /// Physical Layer Specification says
+    struct Card {
+        /// Answers SEND_IF_COND, i.e. SD v2.0+
+        v2: bool,
+        /// SDHC/SDXC, reported in the OCR once ready
+        high_capacity: bool,
+        /// SD_SEND_OP_COND replies before the card is ready, u32::MAX for
+        /// never
+        busy: u32,
+        csd: u128,
+        /// Commands seen, with their argument
+        commands: Vec<(u32, u32)>,
+        /// RINTSTS error bits replacing the completion of that command
+        fail: Option<(u32, u32)>,
+        /// Words of block data not read from the FIFO yet
+        fifo: VecDeque<u32>,
+        /// Stops sending data after this many words
+        data_limit: usize,
+        app: bool,
+    }
+
+    impl Card {
+        fn new(v2: bool, high_capacity: bool, csd: u128) -> Self {
+            Self {
+                v2,
+                high_capacity,
+                busy: 2,
+                csd,
+                commands: Vec::new(),
+                fail: None,
+                fifo: VecDeque::new(),
+                data_limit: usize::MAX,
+                app: false,
+            }
+        }
+
+        /// Block `lba` holds its number in every word
+        fn send_blocks(&mut self, lba: u32, count: u32) {
+            let words = (lba..lba + count).flat_map(|lba| [lba; BLOCK_SIZE / 4]);
+            self.fifo.extend(words.take(self.data_limit));
+        }
+
+        /// RINTSTS and the response registers after command `idx`
+        fn command(&mut self, idx: u32, arg: u32, flags: u32) -> (u32, u128) {
+            let app = core::mem::take(&mut self.app);
+            self.commands.push((idx, arg));
+
+            if let Some((fail_idx, status)) = self.fail
+                && fail_idx == idx
+            {
+                return (status, 0);
+            }
+
+            let r1 = 0x900; // READY_FOR_DATA, state tran
+            let resp = match (app, idx) {
+                (_, 0) => 0,
+                (_, 8) if !self.v2 => return (INT_RTO, 0),
+                (_, 8) => (arg & 0xfff) as u128,
+                (_, 55) => {
+                    self.app = true;
+                    r1 | 1 << 5
+                }
+                (true, 41) => {
+                    let ready = self.busy == 0;
+                    self.busy = self.busy.saturating_sub(1);
+
+                    let hcs = self.high_capacity && arg & OCR_HCS != 0;
+                    (OCR_VOLTAGES | (ready as u32) << 31 | (hcs as u32) << 30) as u128
+                }
+                // CID, which the driver doesn't look at
+                (_, 2) => 0x1234,
+                (_, 3) => (RCA << 16) as u128,
+                (_, 9) if arg == RCA << 16 => self.csd,
+                (_, 7) if arg == RCA << 16 => r1,
+                (true, 6) | (_, 16) => r1,
+                (_, 17 | 18) => {
+                    let blocks = if idx == 17 { 1 } else { sim::get(SMHC0_BASE + SMHC_BYTCNT) as u32 / 512 };
+                    let lba = if self.high_capacity { arg } else { arg / 512 };
+                    self.send_blocks(lba, blocks);
+                    r1
+                }
+                _ => return (INT_RTO, 0),
+            };
+
+            let mut status = INT_CC;
+            if flags & CMD_DATA_TRANS != 0 {
+                status |= INT_DTC;
+            }
+            if flags & CMD_STOP_CMD_FLAG != 0 {
+                status |= INT_ACD;
+            }
+
+            (status, resp)
+        }
+    }
+
+    /// Puts `card` behind SMHC0 on a fresh simulated D1
+    fn insert(card: Card) -> Rc<RefCell<Card>> {
+        sim::reset();
+        sim::d1();
+
+        let card = Rc::new(RefCell::new(card));
+        let reg = |r| SMHC0_BASE + r;
+
+        // Resets finish at once
+        sim::on_access(reg(SMHC_CTRL), |a| a.value & !((CTRL_SOFT_RST | CTRL_FIFO_RST | CTRL_DMA_RST) as u64));
+
+        // Write 1 to clear
+        sim::on_access(reg(SMHC_RINTSTS), |a| match a.op {
+            Op::Read => a.value,
+            Op::Write => sim::get(a.addr) & !a.value,
+        });
+
+        let c = card.clone();
+        sim::on_access(reg(SMHC_CMD), move |a| {
+            let flags = a.value as u32;
+            if a.op == Op::Read || flags & CMD_LOAD == 0 {
+                return a.value;
+            }
+
+            if flags & CMD_PRG_CLK == 0 {
+                let arg = sim::get(reg(SMHC_CMDARG)) as u32;
+                let (status, resp) = c.borrow_mut().command(flags & 0x3f, arg, flags);
+
+                sim::set(reg(SMHC_RINTSTS), status as u64);
+                for i in 0..4 {
+                    sim::set(reg(SMHC_RESP0 + i * 4), (resp >> (i * 32)) as u32 as u64);
+                }
+            }
+
+            (flags & !CMD_LOAD) as u64
+        });
+
+        let c = card.clone();
+        sim::on_access(reg(SMHC_STATUS), move |_| if c.borrow().fifo.is_empty() { STATUS_FIFO_EMPTY as u64 } else { 0 });
+
+        let c = card.clone();
+        sim::on_access(reg(SMHC_FIFO), move |_| c.borrow_mut().fifo.pop_front().unwrap_or(0) as u64);
+
+        card
+    }
+
+    #[test]
+    fn csd_capacity() {
+        let words = |csd: u128| [csd as u32, (csd >> 32) as u32, (csd >> 64) as u32, (csd >> 96) as u32];
+
+        assert_eq!(csd_blocks(&words(CSD_V2)), 15160 * 1024);
+        assert_eq!(csd_blocks(&words(CSD_V1)), 4 << 20);
+        // 512 byte blocks, C_SIZE_MULT = 0: (C_SIZE + 1) * 4 blocks
+        assert_eq!(csd_blocks(&words(99 << 62 | 9 << 80)), 400);
+        assert_eq!(bits(&words(0xab << 120), 120, 8), 0xab);
+    }
+
+    #[test]
+    fn pins() {
+        sim::reset();
+        // Reset values: pins disabled, drive level 1
+        sim::set(GPIO_BASE + GPIO_PF_CFG0, 0xffff_ffff);
+        sim::set(GPIO_BASE + GPIO_PF_DRV0, 0x1111_1111);
+
+        unsafe { init_pins() };
+
+        assert_eq!(sim::get(GPIO_BASE + GPIO_PF_CFG0), 0xff22_2222);
+        assert_eq!(sim::get(GPIO_BASE + GPIO_PF_DRV0), 0x1122_2222);
+        assert_eq!(sim::get(GPIO_BASE + GPIO_PF_PULL0), 0x545);
+    }
+
+    #[test]
+    fn init_sdhc() {
+        let card = insert(Card::new(true, true, CSD_V2));
+        let mut sd = Smhc::new(SMHC0_BASE);
+
+        assert_eq!(sd.init(), Ok(()));
+        assert!(sd.card_type() == Some(CardType::Sdhc));
+        assert_eq!(sd.block_count(), 15160 * 1024);
+        assert_eq!(sd.rca, RCA);
+
+        let acmd41 = OCR_HCS | OCR_VOLTAGES;
+        assert_eq!(
+            card.borrow().commands,
+            [
+                (0, 0),
+                (8, 0x1aa),
+                (55, 0),
+                (41, acmd41),
+                (55, 0),
+                (41, acmd41),
+                (55, 0),
+                (41, acmd41),
+                (2, 0),
+                (3, 0),
+                (9, RCA << 16),
+                (7, RCA << 16),
+                (55, RCA << 16),
+                (6, 2),
+            ]
+        );
+
+        assert_eq!(sim::get(SMHC0_BASE + SMHC_CTYPE), 1);
+        assert_eq!(sim::get(SMHC0_BASE + SMHC_CLKDIV), (CLKDIV_CCLK_ENB | 1) as u64);
+    }
+
+    #[test]
+    fn init_standard_capacity() {
+        // v2 card without HCS in its OCR, and a v1 card not answering CMD8
+        for (v2, card_type, acmd41) in [(true, CardType::SdV2, OCR_HCS | OCR_VOLTAGES), (false, CardType::SdV1, OCR_VOLTAGES)] {
+            let card = insert(Card::new(v2, false, CSD_V1));
+            let mut sd = Smhc::new(SMHC0_BASE);
+
+            assert_eq!(sd.init(), Ok(()));
+            assert!(sd.card_type() == Some(card_type));
+            assert_eq!(sd.block_count(), 4 << 20);
+
+            let commands = &card.borrow().commands;
+            assert_eq!(commands[3], (41, acmd41));
+            assert_eq!(commands.last(), Some(&(16, 512)));
+        }
+    }
+
+    #[test]
+    fn read_blocks() {
+        for (high_capacity, csd) in [(true, CSD_V2), (false, CSD_V1)] {
+            let card = insert(Card::new(true, high_capacity, csd));
+            let mut sd = Smhc::new(SMHC0_BASE);
+            sd.init().unwrap();
+            card.borrow_mut().commands.clear();
+
+            let mut buf = vec![0u8; 3 * BLOCK_SIZE];
+            assert_eq!(sd.read_blocks(100, &mut buf[..BLOCK_SIZE]), Ok(()));
+            assert_eq!(sd.read_blocks(7, &mut buf), Ok(()));
+
+            let words: Vec<u32> = buf.chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+            assert!(words.chunks(128).zip(7..).all(|(block, lba)| block.iter().all(|&w| w == lba)));
+
+            let addr = |lba: u32| if high_capacity { lba } else { lba * 512 };
+            assert_eq!(card.borrow().commands, [(17, addr(100)), (18, addr(7))]);
+            assert_eq!(sim::get(SMHC0_BASE + SMHC_BYTCNT), 3 * 512);
+            assert!(card.borrow().fifo.is_empty());
+
+            assert_eq!(sd.read_blocks(sd.block_count() - 2, &mut buf), Err(Error::OutOfRange));
+        }
+    }
+
+    #[test]
+    fn init_errors() {
+        // Nothing in the slot: CMD8 and APP_CMD time out
+        let card = insert(Card::new(false, false, CSD_V2));
+        card.borrow_mut().fail = Some((55, INT_RTO));
+        assert_eq!(Smhc::new(SMHC0_BASE).init(), Err(Error::NoDevice));
+
+        // CMD8 echoing the wrong check pattern
+        let card = insert(Card::new(true, true, CSD_V2));
+        card.borrow_mut().fail = Some((8, INT_CC));
+        assert_eq!(Smhc::new(SMHC0_BASE).init(), Err(Error::Unsupported));
+
+        // Never leaving the busy state in SD_SEND_OP_COND
+        let card = insert(Card::new(true, true, CSD_V2));
+        card.borrow_mut().busy = u32::MAX;
+        assert_eq!(Smhc::new(SMHC0_BASE).init(), Err(Error::Timeout));
+        assert!(card.borrow().commands.len() > 100);
+
+        // Response CRC error on SEND_CSD
+        let card = insert(Card::new(true, true, CSD_V2));
+        card.borrow_mut().fail = Some((9, 1 << 6));
+        let mut sd = Smhc::new(SMHC0_BASE);
+        assert_eq!(sd.init(), Err(Error::Crc));
+        assert!(sd.card_type().is_none());
+    }
+
+    #[test]
+    fn read_errors() {
+        let card = insert(Card::new(true, true, CSD_V2));
+        let mut sd = Smhc::new(SMHC0_BASE);
+        sd.init().unwrap();
+
+        let mut buf = vec![0u8; 2 * BLOCK_SIZE];
+
+        // Data CRC error
+        card.borrow_mut().fail = Some((18, 1 << 7));
+        assert_eq!(sd.read_blocks(0, &mut buf), Err(Error::Crc));
+
+        // The card stops sending halfway, the FIFO stays empty until the
+        // deadline
+        card.borrow_mut().fail = None;
+        card.borrow_mut().data_limit = 200;
+        assert_eq!(sd.read_blocks(0, &mut buf), Err(Error::Timeout));
+
+        // Both leave the FIFO reset and the status cleared for the next one
+        card.borrow_mut().data_limit = usize::MAX;
+        card.borrow_mut().fifo.clear();
+        assert_eq!(sd.read_blocks(0, &mut buf), Ok(()));
+        assert_eq!(sim::get(SMHC0_BASE + SMHC_RINTSTS), (INT_CC | INT_DTC | INT_ACD) as u64);
+    }
+}
@@ -0,0 +1,522 @@
+//! SPI0 controller in master mode (PC2-PC7 on the D1)
+//!
+//! Transfers are polled through the 64 byte FIFOs. Chip select is driven
+//! by software so one [`SpiBus::transfer`] maps to exactly one burst: the
+//! controller clocks out MTC bytes and then keeps clocking for the bytes to
+//! receive, discarding what came in while sending.
+
+use crate::block::Error;
+use crate::ccu::Peripheral;
+use crate::mmio::*;
+use crate::spinor::SpiBus;
+use crate::time::*;
+
+pub const SPI0_BASE: u64 = 0x0402_5000;
+
+const GPIO_BASE: u64 = 0x0200_0000;
+const GPIO_PC_CFG0: u64 = 0x0060;
+const GPIO_PC_DRV0: u64 = 0x0074;
+const GPIO_PC_PULL0: u64 = 0x0084;
+
+const SPI_GCR: u64 = 0x0004;
+const SPI_TCR: u64 = 0x0008;
+const SPI_IER: u64 = 0x0010;
+const SPI_ISR: u64 = 0x0014;
+const SPI_FCR: u64 = 0x0018;
+const SPI_FSR: u64 = 0x001c;
+const SPI_MBC: u64 = 0x0030;
+const SPI_MTC: u64 = 0x0034;
+const SPI_BCC: u64 = 0x0038;
+const SPI_TXD: u64 = 0x0200;
+const SPI_RXD: u64 = 0x0300;
+
+const GCR_EN: u32 = 1 << 0;
+const GCR_MASTER: u32 = 1 << 1;
+const GCR_TP_EN: u32 = 1 << 7; // pause the burst while the RX FIFO is full
+const GCR_SRST: u32 = 1 << 31;
+
+const TCR_SPOL: u32 = 1 << 2; // chip select active low
+const TCR_SS_OWNER: u32 = 1 << 6; // chip select driven by SS_LEVEL
+const TCR_SS_LEVEL: u32 = 1 << 7;
+const TCR_DHB: u32 = 1 << 8; // discard the bytes received while sending
+const TCR_SDM: u32 = 1 << 13; // normal sampling, for SCLK up to 24 MHz
+const TCR_XCH: u32 = 1 << 31;
+
+const ISR_TC: u32 = 1 << 12;
+
+const FCR_RF_RST: u32 = 1 << 15;
+const FCR_TF_RST: u32 = 1 << 31;
+
+const FIFO_SIZE: u32 = 64;
+/// Burst counters are 24 bits wide
+const BURST_MAX: usize = 0xff_ffff;
+
+const SCLK: u64 = 24_000_000;
+
+const RESET_TIMEOUT_US: u64 = 1_000;
+/// Per byte, on top of a fixed 10 ms
+const BYTE_TIMEOUT_US: u64 = 1;
+
+pub struct Spi {
+    base: u64,
+}
+
+/// Routes PC2-PC7 to SPI0, with pull-ups on CS, WP and HOLD
+pub unsafe fn init_spi0_pins() {
+    unsafe {
+        Reg32::read(GPIO_BASE + GPIO_PC_CFG0)
+            .set_field::<8, 4>(2) // PC2 = SPI0_CLK
+            .set_field::<12, 4>(2) // PC3 = SPI0_CS0
+            .set_field::<16, 4>(2) // PC4 = SPI0_MOSI
+            .set_field::<20, 4>(2) // PC5 = SPI0_MISO
+            .set_field::<24, 4>(2) // PC6 = SPI0_WP
+            .set_field::<28, 4>(2) // PC7 = SPI0_HOLD
+            .write();
+
+        Reg32::read(GPIO_BASE + GPIO_PC_DRV0)
+            .set_field::<8, 24>(0x22_2222) // PC2-PC7 drive level 2, 4 bits per pin
+            .write();
+
+        Reg32::read(GPIO_BASE + GPIO_PC_PULL0)
+            .set_field::<4, 12>(0x504) // pull-up on PC3, PC6 and PC7, 2 bits per pin
+            .write();
+    }
+}
+
+impl Spi {
+    pub const fn new(base: u64) -> Self {
+        Self { base }
+    }
+
+    fn read(&self, reg: u64) -> u32 {
+        unsafe { read32(self.base + reg) }
+    }
+
+    fn write(&self, reg: u64, v: u32) {
+        unsafe { write32(self.base + reg, v) }
+    }
+
+    /// Polls `reg` until `(value & mask) == expected`
+    fn wait(&self, reg: u64, mask: u32, expected: u32, timeout_us: u64) -> Result<(), Error> {
+        unsafe { wait_mask(self.base + reg, mask, expected, timeout_us) }
+            .map(drop)
+            .map_err(|_| Error::Timeout)
+    }
+
+    /// Resets the controller into master mode 0 with chip select deasserted
+    pub fn init(&self) -> Result<(), Error> {
+        unsafe {
+            crate::ccu::enable(Peripheral::Spi0);
+            crate::ccu::set_module_clock(Peripheral::Spi0, SCLK);
+        }
+
+        self.write(SPI_GCR, GCR_SRST);
+        self.wait(SPI_GCR, GCR_SRST, 0, RESET_TIMEOUT_US)?;
+        self.write(SPI_GCR, GCR_EN | GCR_MASTER | GCR_TP_EN);
+
+        self.write(SPI_TCR, TCR_SPOL | TCR_SS_OWNER | TCR_SS_LEVEL | TCR_DHB | TCR_SDM);
+        self.write(SPI_IER, 0);
+        self.write(SPI_ISR, 0xffff_ffff);
+
+        Ok(())
+    }
+
+    fn set_cs(&self, active: bool) {
+        let tcr = self.read(SPI_TCR) & !TCR_SS_LEVEL;
+        self.write(SPI_TCR, if active { tcr } else { tcr | TCR_SS_LEVEL });
+    }
+
+    fn burst(&self, tx: &[&[u8]], rx: &mut [u8]) -> Result<(), Error> {
+        let tx_len: usize = tx.iter().map(|t| t.len()).sum();
+        let total = tx_len + rx.len();
+
+        if total > BURST_MAX {
+            return Err(Error::OutOfRange);
+        }
+
+        self.write(SPI_FCR, self.read(SPI_FCR) | FCR_RF_RST | FCR_TF_RST);
+        self.wait(SPI_FCR, FCR_RF_RST | FCR_TF_RST, 0, RESET_TIMEOUT_US)?;
+        self.write(SPI_ISR, 0xffff_ffff);
+
+        self.write(SPI_MBC, total as u32);
+        self.write(SPI_MTC, tx_len as u32);
+        self.write(SPI_BCC, tx_len as u32); // STC, single wire all along
+        self.write(SPI_TCR, self.read(SPI_TCR) | TCR_XCH);
+
+        let timeout_us = 10_000 + total as u64 * BYTE_TIMEOUT_US;
+        let deadline = deadline(timeout_us);
+
+        let mut tx_bytes = tx.iter().flat_map(|t| t.iter());
+        let mut rx_bytes = rx.iter_mut();
+        let mut tx_left = tx_len;
+        let mut rx_left = rx_bytes.len();
+
+        while tx_left > 0 || rx_left > 0 {
+            let fsr = self.read(SPI_FSR);
+            let rx_count = fsr & 0xff; // RF_CNT
+            let tx_count = (fsr >> 16) & 0xff; // TF_CNT
+
+            for _ in tx_count..FIFO_SIZE {
+                let Some(&b) = tx_bytes.next() else {
+                    break;
+                };
+                unsafe { write8(self.base + SPI_TXD, b) };
+                tx_left -= 1;
+            }
+
+            for _ in 0..rx_count {
+                let Some(b) = rx_bytes.next() else {
+                    break;
+                };
+                *b = unsafe { read8(self.base + SPI_RXD) };
+                rx_left -= 1;
+            }
+
+            if unsafe { timer_csr() } > deadline {
+                return Err(Error::Timeout);
+            }
+        }
+
+        self.wait(SPI_ISR, ISR_TC, ISR_TC, timeout_us)?;
+        self.write(SPI_ISR, ISR_TC);
+
+        Ok(())
+    }
+}
+
+impl SpiBus for Spi {
+    fn transfer(&mut self, tx: &[&[u8]], rx: &mut [u8]) -> Result<(), Error> {
+        self.set_cs(true);
+        let result = self.burst(tx, rx);
+        self.set_cs(false);
+
+        if result.is_err() {
+            // Stop a transfer that didn't complete
+            let _ = self.init();
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Op};
+    use crate::spinor::SpiNor;
+    use crate::spinor::tests::Chip;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    /// Bytes the controller shifts per FIFO status read, more than a FIFO
+    /// holds so the CPU falls behind
+    const BYTES_PER_POLL: usize = 80;
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    enum Event {
+        /// Soft reset through GCR
+        Reset,
+        /// Chip select asserted or deasserted
+        Cs(bool),
+        /// A burst started with MBC, MTC and BCC
+        Burst(u32, u32, u32),
+    }
+
+    struct Burst {
+        mbc: usize,
+        mtc: usize,
+        tx: Vec<u8>,
+        /// Filled once all of `tx` went out
+        rx: Option<VecDeque<u8>>,
+    }
+
+    /// SPI0 as far as the driver uses it, with an emulated flash chip on the
+    /// bus. Time passes on FSR and ISR reads.
+    struct Controller {
+        chip: Chip,
+        tcr: u32,
+        isr: u32,
+        tx_fifo: VecDeque<u8>,
+        rx_fifo: VecDeque<u8>,
+        burst: Option<Burst>,
+        /// ISR reads after the last byte until TC sets
+        tc_delay: u32,
+        /// The clock stops, as with a stuck burst
+        stalled: bool,
+        /// Most bytes the RX FIFO held
+        rx_peak: usize,
+        events: Vec<Event>,
+    }
+
+    impl Controller {
+        fn cs_active(tcr: u32) -> bool {
+            tcr & TCR_SS_OWNER != 0 && tcr & TCR_SS_LEVEL == 0
+        }
+
+        fn start(&mut self) {
+            assert!(Self::cs_active(self.tcr), "burst without chip select");
+            assert!(self.tcr & TCR_DHB != 0, "received bytes kept while sending");
+            assert!(self.tx_fifo.is_empty() && self.rx_fifo.is_empty(), "stale FIFO contents");
+
+            let reg = |r| sim::get(SPI0_BASE + r) as u32;
+            let (mbc, mtc, bcc) = (reg(SPI_MBC), reg(SPI_MTC), reg(SPI_BCC));
+            self.events.push(Event::Burst(mbc, mtc, bcc));
+
+            self.burst = Some(Burst {
+                mbc: mbc as usize,
+                mtc: mtc as usize,
+                tx: Vec::new(),
+                rx: None,
+            });
+        }
+
+        fn shift(&mut self) {
+            let Some(burst) = &mut self.burst else {
+                return;
+            };
+
+            if self.stalled {
+                return;
+            }
+
+            let n = self.tx_fifo.len().min(burst.mtc - burst.tx.len()).min(BYTES_PER_POLL);
+            burst.tx.extend(self.tx_fifo.drain(..n));
+
+            if burst.tx.len() < burst.mtc {
+                return;
+            }
+
+            let rx = burst.rx.get_or_insert_with(|| {
+                let mut rx = vec![0; burst.mbc - burst.mtc];
+                self.chip.transfer(&[&burst.tx], &mut rx).unwrap();
+                rx.into()
+            });
+
+            // GCR.TP_EN pauses the clock while the RX FIFO is full
+            let gcr = sim::get(SPI0_BASE + SPI_GCR) as u32;
+            for _ in 0..BYTES_PER_POLL {
+                if self.rx_fifo.len() == FIFO_SIZE as usize {
+                    assert!(gcr & GCR_TP_EN != 0, "RX FIFO overflow");
+                    break;
+                }
+                let Some(b) = rx.pop_front() else {
+                    break;
+                };
+                self.rx_fifo.push_back(b);
+            }
+
+            self.rx_peak = self.rx_peak.max(self.rx_fifo.len());
+        }
+
+        /// On ISR reads, TC sets `tc_delay` reads after the last byte
+        fn complete(&mut self) {
+            self.shift();
+
+            let done = self.burst.as_ref().is_some_and(|b| b.rx.as_ref().is_some_and(VecDeque::is_empty));
+
+            if done && !self.stalled {
+                if self.tc_delay > 0 {
+                    self.tc_delay -= 1;
+                    return;
+                }
+
+                self.burst = None;
+                self.tcr &= !TCR_XCH;
+                self.isr |= ISR_TC;
+            }
+        }
+    }
+
+    /// Puts the simulated controller with `chip` behind SPI0's registers
+    fn attach(chip: Chip) -> Rc<RefCell<Controller>> {
+        sim::reset();
+
+        let ctrl = Rc::new(RefCell::new(Controller {
+            chip,
+            tcr: 0,
+            isr: 0,
+            tx_fifo: VecDeque::new(),
+            rx_fifo: VecDeque::new(),
+            burst: None,
+            tc_delay: 0,
+            stalled: false,
+            rx_peak: 0,
+            events: Vec::new(),
+        }));
+
+        let on = |reg, f: fn(&mut Controller, Op, u32) -> u32| {
+            let ctrl = ctrl.clone();
+            sim::on_access(SPI0_BASE + reg, move |a| f(&mut ctrl.borrow_mut(), a.op, a.value as u32) as u64);
+        };
+
+        on(SPI_GCR, |c, op, v| {
+            if op == Op::Write && v & GCR_SRST != 0 {
+                c.events.push(Event::Reset);
+                c.tcr = 0;
+                c.burst = None;
+                c.tx_fifo.clear();
+                c.rx_fifo.clear();
+            }
+            // The reset completes at once
+            v & !GCR_SRST
+        });
+        on(SPI_TCR, |c, op, v| {
+            if op == Op::Write {
+                let was_active = Controller::cs_active(c.tcr);
+                c.tcr = v;
+
+                if Controller::cs_active(v) != was_active {
+                    c.events.push(Event::Cs(!was_active));
+                }
+                if v & TCR_XCH != 0 && c.burst.is_none() {
+                    c.start();
+                }
+            }
+            c.tcr
+        });
+        on(SPI_FCR, |c, op, v| {
+            if op == Op::Write {
+                if v & FCR_RF_RST != 0 {
+                    c.rx_fifo.clear();
+                }
+                if v & FCR_TF_RST != 0 {
+                    c.tx_fifo.clear();
+                }
+            }
+            v & !(FCR_RF_RST | FCR_TF_RST)
+        });
+        on(SPI_FSR, |c, _, _| {
+            c.shift();
+            (c.tx_fifo.len() as u32) << 16 | c.rx_fifo.len() as u32
+        });
+        on(SPI_ISR, |c, op, v| {
+            match op {
+                Op::Write => {
+                    c.isr &= !v;
+                    v
+                }
+                Op::Read => {
+                    c.complete();
+                    c.isr
+                }
+            }
+        });
+        on(SPI_TXD, |c, _, v| {
+            assert!(c.tx_fifo.len() < FIFO_SIZE as usize, "TX FIFO overflow");
+            c.tx_fifo.push_back(v as u8);
+            v
+        });
+        on(SPI_RXD, |c, _, _| c.rx_fifo.pop_front().expect("read from an empty RX FIFO") as u32);
+
+        ctrl
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i * 13) as u8 ^ seed).collect()
+    }
+
+    fn take_events(ctrl: &Rc<RefCell<Controller>>) -> Vec<Event> {
+        core::mem::take(&mut ctrl.borrow_mut().events)
+    }
+
+    #[test]
+    fn flash_behind_the_controller() {
+        use Event::*;
+
+        let mut chip = Chip::new();
+        let data = pattern(3000, 0x3c);
+        chip.data[0x1_0f00..0x1_0f00 + data.len()].copy_from_slice(&data);
+
+        let ctrl = attach(chip);
+        let spi = Spi::new(SPI0_BASE);
+        spi.init().unwrap();
+
+        assert_eq!(take_events(&ctrl), [Reset]);
+        assert_eq!(sim::get(SPI0_BASE + SPI_GCR) as u32, GCR_EN | GCR_MASTER | GCR_TP_EN);
+        assert_eq!(ctrl.borrow().tcr, TCR_SPOL | TCR_SS_OWNER | TCR_SS_LEVEL | TCR_DHB | TCR_SDM);
+
+        let mut flash = SpiNor::probe(spi).unwrap();
+        assert_eq!(flash.part().name, "W25Q16");
+        assert_eq!(take_events(&ctrl), [Cs(true), Burst(4, 1, 1), Cs(false)]);
+
+        // The RX FIFO fills up and pauses the burst many times over
+        let mut buf = vec![0; data.len()];
+        flash.read(0x1_0f00, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(take_events(&ctrl), [Cs(true), Burst(3005, 5, 5), Cs(false)]);
+        assert_eq!(ctrl.borrow().rx_peak, FIFO_SIZE as usize);
+
+        // A page program sends more than the TX FIFO holds
+        let page = pattern(256, 0xa5);
+        flash.write(0x2_0000, &page).unwrap();
+        assert_eq!(ctrl.borrow().chip.data[0x2_0000..0x2_0100], page);
+
+        let events = take_events(&ctrl);
+        assert_eq!(events[..6], [Cs(true), Burst(1, 1, 1), Cs(false), Cs(true), Burst(2, 1, 1), Cs(false)]);
+        assert_eq!(events[6..9], [Cs(true), Burst(260, 260, 260), Cs(false)]);
+
+        // Every burst ended with TC acknowledged
+        assert_eq!(ctrl.borrow().isr, 0);
+    }
+
+    #[test]
+    fn transfer_complete() {
+        let ctrl = attach(Chip::new());
+        let mut spi = Spi::new(SPI0_BASE);
+        spi.init().unwrap();
+
+        ctrl.borrow_mut().tc_delay = 20;
+        let mut id = [0; 3];
+        spi.transfer(&[&[0x9f]], &mut id).unwrap();
+        assert_eq!(id, [0xef, 0x40, 0x15]);
+
+        // Polled ISR until TC, then cleared it
+        let log = sim::take_log();
+        let isr_reads = log.iter().filter(|a| a.addr == SPI0_BASE + SPI_ISR && a.op == Op::Read).count();
+        assert_eq!(isr_reads, 21);
+        assert!(log.iter().any(|a| a.addr == SPI0_BASE + SPI_ISR && a.op == Op::Write && a.value == ISR_TC as u64));
+        assert_eq!(ctrl.borrow().isr, 0);
+
+        // TC never sets
+        ctrl.borrow_mut().tc_delay = u32::MAX;
+        assert_eq!(spi.transfer(&[&[0x9f]], &mut id), Err(Error::Timeout));
+    }
+
+    #[test]
+    fn reinit_after_an_error() {
+        use Event::*;
+
+        let mut chip = Chip::new();
+        chip.data[..4].copy_from_slice(&[1, 2, 3, 4]);
+
+        let ctrl = attach(chip);
+        let mut spi = Spi::new(SPI0_BASE);
+        spi.init().unwrap();
+        take_events(&ctrl);
+
+        // The clock stops halfway, with bytes left in both FIFOs
+        ctrl.borrow_mut().stalled = true;
+        let start = sim::ticks();
+        let mut buf = [0; 200];
+        assert_eq!(spi.transfer(&[&[0x0b, 0, 0, 0, 0]], &mut buf), Err(Error::Timeout));
+
+        let waited = (sim::ticks() - start) / (crate::time::TIMER_FREQ / 1_000_000);
+        assert!((10_000..10_300).contains(&waited), "{waited} us");
+        assert_eq!(take_events(&ctrl), [Cs(true), Burst(205, 5, 5), Cs(false), Reset]);
+        assert_eq!(ctrl.borrow().tcr, TCR_SPOL | TCR_SS_OWNER | TCR_SS_LEVEL | TCR_DHB | TCR_SDM);
+
+        // The next transfer starts from a clean controller
+        ctrl.borrow_mut().stalled = false;
+        let mut buf = [0; 4];
+        spi.transfer(&[&[0x0b, 0, 0, 0, 0]], &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert_eq!(take_events(&ctrl), [Cs(true), Burst(9, 5, 5), Cs(false)]);
+
+        // Too long for the burst counters, caught before the controller sees it
+        let mut buf = vec![0; BURST_MAX];
+        assert_eq!(spi.transfer(&[&[0x0b, 0, 0, 0, 0]], &mut buf), Err(Error::OutOfRange));
+        assert_eq!(take_events(&ctrl), [Cs(true), Cs(false), Reset]);
+    }
+}
@@ -0,0 +1,439 @@
+//! SPI NOR flash on SPI0
+//!
+//! Parts are identified by their JEDEC ID (RDID) and only used through the
+//! commands every serial NOR flash understands: fast read, page program and
+//! 4 KiB sector erase, all with 3 byte addresses. Parts above 16 MiB are
+//! limited to their first 16 MiB.
+//!
+//! The driver only sees the [`SpiBus`] trait, so the command sequences can be
+//! run against an emulated chip.
+
+use crate::block::Error;
+use crate::mmio::deadline;
+use crate::time::*;
+
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_READ_ID: u8 = 0x9f;
+const CMD_FAST_READ: u8 = 0x0b;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+
+const STATUS_WIP: u8 = 1 << 0;
+const STATUS_WEL: u8 = 1 << 1;
+
+pub const PAGE_SIZE: u32 = 256;
+pub const SECTOR_SIZE: u32 = 4096;
+
+/// Largest size reachable with 3 byte addresses
+const MAX_SIZE: u32 = 1 << 24;
+
+const WRITE_ENABLE_TIMEOUT_US: u64 = 1_000;
+/// Worst case of the parts below is 3 ms for a page, 400 ms for a sector
+const PROGRAM_TIMEOUT_US: u64 = 10_000;
+const ERASE_TIMEOUT_US: u64 = 1_000_000;
+
+/// What the flash driver needs from the controller
+pub trait SpiBus {
+    /// Runs one transaction with chip select asserted: clocks out the `tx`
+    /// slices in order, then clocks in `rx.len()` bytes
+    fn transfer(&mut self, tx: &[&[u8]], rx: &mut [u8]) -> Result<(), Error>;
+}
+
+impl<S: SpiBus + ?Sized> SpiBus for &mut S {
+    fn transfer(&mut self, tx: &[&[u8]], rx: &mut [u8]) -> Result<(), Error> {
+        (**self).transfer(tx, rx)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Part {
+    pub name: &'static str,
+    /// Manufacturer, memory type and capacity (log2 of the size in bytes)
+    pub id: [u8; 3],
+}
+
+impl Part {
+    pub fn size(&self) -> u32 {
+        1u32.checked_shl(self.id[2] as u32).unwrap_or(MAX_SIZE).min(MAX_SIZE)
+    }
+}
+
+const fn part(name: &'static str, id: [u8; 3]) -> Part {
+    Part { name, id }
+}
+
+pub static PARTS: [Part; 28] = [
+    part("W25Q16", [0xef, 0x40, 0x15]),
+    part("W25Q32", [0xef, 0x40, 0x16]),
+    part("W25Q64", [0xef, 0x40, 0x17]),
+    part("W25Q128", [0xef, 0x40, 0x18]),
+    part("W25Q256", [0xef, 0x40, 0x19]),
+    part("W25Q64JW", [0xef, 0x60, 0x17]),
+    part("W25Q128JW", [0xef, 0x60, 0x18]),
+    part("W25Q128JV-DTR", [0xef, 0x70, 0x18]),
+    part("GD25Q16", [0xc8, 0x40, 0x15]),
+    part("GD25Q32", [0xc8, 0x40, 0x16]),
+    part("GD25Q64", [0xc8, 0x40, 0x17]),
+    part("GD25Q128", [0xc8, 0x40, 0x18]),
+    part("GD25Q256", [0xc8, 0x40, 0x19]),
+    part("MX25L1606E", [0xc2, 0x20, 0x15]),
+    part("MX25L3206E", [0xc2, 0x20, 0x16]),
+    part("MX25L6406E", [0xc2, 0x20, 0x17]),
+    part("MX25L12835F", [0xc2, 0x20, 0x18]),
+    part("MX25L25635F", [0xc2, 0x20, 0x19]),
+    part("XM25QH64", [0x20, 0x70, 0x17]),
+    part("XM25QH128", [0x20, 0x70, 0x18]),
+    part("N25Q128", [0x20, 0xba, 0x18]),
+    part("IS25LP064", [0x9d, 0x60, 0x17]),
+    part("IS25LP128", [0x9d, 0x60, 0x18]),
+    part("EN25QH64", [0x1c, 0x70, 0x17]),
+    part("EN25QH128", [0x1c, 0x70, 0x18]),
+    part("ZB25VQ64", [0x5e, 0x40, 0x17]),
+    part("ZB25VQ128", [0x5e, 0x40, 0x18]),
+    part("FM25Q64", [0xa1, 0x40, 0x17]),
+];
+
+pub struct SpiNor<S> {
+    bus: S,
+    part: &'static Part,
+}
+
+/// Command byte followed by a 3 byte address
+fn command(cmd: u8, addr: u32) -> [u8; 4] {
+    let [_, a2, a1, a0] = addr.to_be_bytes();
+    [cmd, a2, a1, a0]
+}
+
+impl<S: SpiBus> SpiNor<S> {
+    /// Reads the JEDEC ID and looks it up in [`PARTS`]
+    pub fn probe(mut bus: S) -> Result<Self, Error> {
+        let mut id = [0; 3];
+        bus.transfer(&[&[CMD_READ_ID]], &mut id)?;
+
+        // MISO floating high or held low: nothing there
+        if id == [0xff; 3] || id == [0; 3] {
+            return Err(Error::NoDevice);
+        }
+
+        let part = PARTS.iter().find(|p| p.id == id).ok_or(Error::Unsupported)?;
+
+        Ok(Self { bus, part })
+    }
+
+    pub fn part(&self) -> &'static Part {
+        self.part
+    }
+
+    pub fn size(&self) -> u32 {
+        self.part.size()
+    }
+
+    fn check_range(&self, offset: u32, len: usize) -> Result<(), Error> {
+        let end = (offset as u64).checked_add(len as u64);
+
+        match end {
+            Some(end) if end <= self.size() as u64 => Ok(()),
+            _ => Err(Error::OutOfRange),
+        }
+    }
+
+    fn status(&mut self) -> Result<u8, Error> {
+        let mut status = [0];
+        self.bus.transfer(&[&[CMD_READ_STATUS]], &mut status)?;
+        Ok(status[0])
+    }
+
+    /// Polls the status register until `(status & mask) == expected`
+    fn wait_status(&mut self, mask: u8, expected: u8, timeout_us: u64) -> Result<(), Error> {
+        let deadline = deadline(timeout_us);
+
+        loop {
+            if self.status()? & mask == expected {
+                return Ok(());
+            }
+            if unsafe { timer_csr() } > deadline {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    /// Sets the write enable latch, which the next program or erase clears
+    fn write_enable(&mut self) -> Result<(), Error> {
+        self.bus.transfer(&[&[CMD_WRITE_ENABLE]], &mut [])?;
+        self.wait_status(STATUS_WEL, STATUS_WEL, WRITE_ENABLE_TIMEOUT_US)
+    }
+
+    pub fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+        self.check_range(offset, buf.len())?;
+
+        // FAST_READ takes one dummy byte after the address
+        self.bus.transfer(&[&command(CMD_FAST_READ, offset), &[0]], buf)
+    }
+
+    /// Programs `data` at `offset`, page by page. Programming only clears
+    /// bits, the range has to be erased first.
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error> {
+        self.check_range(offset, data.len())?;
+
+        let mut offset = offset;
+        let mut data = data;
+
+        while !data.is_empty() {
+            // A page program wraps around within its page, so stop at the end
+            let room = (PAGE_SIZE - offset % PAGE_SIZE) as usize;
+            let (chunk, rest) = data.split_at_checked(room).unwrap_or((data, &[]));
+
+            self.write_enable()?;
+            self.bus.transfer(&[&command(CMD_PAGE_PROGRAM, offset), chunk], &mut [])?;
+            self.wait_status(STATUS_WIP, 0, PROGRAM_TIMEOUT_US)?;
+
+            offset += chunk.len() as u32;
+            data = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Erases the sectors in `offset..offset + len`, both have to be
+    /// multiples of [`SECTOR_SIZE`]
+    pub fn erase(&mut self, offset: u32, len: u32) -> Result<(), Error> {
+        if !offset.is_multiple_of(SECTOR_SIZE) || !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(Error::Unsupported);
+        }
+
+        self.check_range(offset, len as usize)?;
+
+        for sector in (offset..offset + len).step_by(SECTOR_SIZE as usize) {
+            self.write_enable()?;
+            self.bus.transfer(&[&command(CMD_SECTOR_ERASE, sector)], &mut [])?;
+            self.wait_status(STATUS_WIP, 0, ERASE_TIMEOUT_US)?;
+        }
+
+        Ok(())
+    }
+}
+
+static mut FLASH0: Option<SpiNor<crate::spi::Spi>> = None;
+
+/// The flash on SPI0, probed on first use
+pub fn flash0() -> Result<&'static mut SpiNor<crate::spi::Spi>, Error> {
+    let flash = unsafe { &mut *core::ptr::addr_of_mut!(FLASH0) };
+
+    if flash.is_none() {
+        let spi = crate::spi::Spi::new(crate::spi::SPI0_BASE);
+        unsafe { crate::spi::init_spi0_pins() };
+        spi.init()?;
+
+        *flash = Some(SpiNor::probe(spi)?);
+    }
+
+    flash.as_mut().ok_or(Error::NoDevice)
+}
+
+pub fn print_info(flash: &SpiNor<impl SpiBus>) {
+    let part = flash.part();
+
+    crate::uart::printf!(
+        "%s (%02x %02x %02x), %d KiB\r\n",
+        part.name,
+        part.id[0],
+        part.id[1],
+        part.id[2],
+        flash.size() / 1024
+    );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Serial NOR flash answering the commands the driver sends, also used
+    /// behind the simulated controller in the spi tests
+    pub(crate) struct Chip {
+        id: [u8; 3],
+        pub(crate) data: Vec<u8>,
+        wel: bool,
+        /// Status reads left with WIP set
+        busy: u32,
+        /// Status reads with WIP set after a program or erase
+        busy_reads: u32,
+        /// WRITE_ENABLE is ignored, e.g. with WP# held low
+        write_protected: bool,
+        /// Commands seen, with their address if they take one
+        pub(crate) log: Vec<(u8, Option<u32>)>,
+    }
+
+    impl Chip {
+        pub(crate) fn new() -> Self {
+            Self {
+                id: [0xef, 0x40, 0x15],
+                data: vec![0xff; 2 << 20],
+                wel: false,
+                busy: 0,
+                busy_reads: 3,
+                write_protected: false,
+                log: Vec::new(),
+            }
+        }
+
+        fn start(&mut self, what: &str) {
+            assert!(self.busy == 0, "{what} while busy");
+            assert!(self.wel, "{what} without write enable");
+            self.wel = false;
+            self.busy = self.busy_reads;
+        }
+    }
+
+    impl SpiBus for Chip {
+        fn transfer(&mut self, tx: &[&[u8]], rx: &mut [u8]) -> Result<(), Error> {
+            let tx = tx.concat();
+            let addr = |tx: &[u8]| u32::from_be_bytes([0, tx[1], tx[2], tx[3]]);
+            let cmd = tx[0];
+
+            let has_addr = matches!(cmd, CMD_FAST_READ | CMD_PAGE_PROGRAM | CMD_SECTOR_ERASE);
+            self.log.push((cmd, has_addr.then(|| addr(&tx))));
+
+            match cmd {
+                CMD_READ_ID => rx.copy_from_slice(&self.id),
+                CMD_READ_STATUS => {
+                    let wip = if self.busy > 0 { STATUS_WIP } else { 0 };
+                    let wel = if self.wel { STATUS_WEL } else { 0 };
+                    rx[0] = wip | wel;
+                    self.busy = self.busy.saturating_sub(1);
+                }
+                CMD_WRITE_ENABLE => self.wel = !self.write_protected,
+                CMD_FAST_READ => {
+                    assert_eq!(tx.len(), 5, "dummy byte");
+                    let start = addr(&tx) as usize;
+                    rx.copy_from_slice(&self.data[start..start + rx.len()]);
+                }
+                CMD_PAGE_PROGRAM => {
+                    self.start("program");
+                    let start = addr(&tx) as usize;
+                    let page = start & !(PAGE_SIZE as usize - 1);
+
+                    // Wraps around within the page like the real thing
+                    for (i, &b) in tx[4..].iter().enumerate() {
+                        let at = page + (start + i) % PAGE_SIZE as usize;
+                        self.data[at] &= b;
+                    }
+                }
+                CMD_SECTOR_ERASE => {
+                    self.start("erase");
+                    let start = addr(&tx) as usize & !(SECTOR_SIZE as usize - 1);
+                    self.data[start..start + SECTOR_SIZE as usize].fill(0xff);
+                }
+                _ => panic!("unknown command 0x{cmd:02x}"),
+            }
+
+            Ok(())
+        }
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8 ^ seed).collect()
+    }
+
+    #[test]
+    fn probe() {
+        let cases = [
+            ([0xef, 0x40, 0x15], Ok(("W25Q16", 2 << 20))),
+            ([0xef, 0x40, 0x19], Ok(("W25Q256", 16 << 20))),
+            ([0xc2, 0x20, 0x17], Ok(("MX25L6406E", 8 << 20))),
+            ([0xef, 0x40, 0x14], Err(Error::Unsupported)),
+            ([0xff; 3], Err(Error::NoDevice)),
+            ([0; 3], Err(Error::NoDevice)),
+        ];
+
+        for (id, expected) in cases {
+            let flash = SpiNor::probe(Chip { id, ..Chip::new() });
+            assert_eq!(flash.map(|f| (f.part().name, f.size())), expected, "{id:02x?}");
+        }
+    }
+
+    #[test]
+    fn read() {
+        let mut chip = Chip::new();
+        let data = pattern(3000, 0x5a);
+        chip.data[0x1_0f00..0x1_0f00 + data.len()].copy_from_slice(&data);
+
+        let mut flash = SpiNor::probe(&mut chip).unwrap();
+        let mut buf = vec![0; data.len()];
+        flash.read(0x1_0f00, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        let mut buf = [0; 16];
+        flash.read((2 << 20) - 16, &mut buf).unwrap();
+        assert_eq!(buf, [0xff; 16]);
+        assert_eq!(flash.read((2 << 20) - 15, &mut buf), Err(Error::OutOfRange));
+        assert_eq!(flash.read(u32::MAX, &mut buf), Err(Error::OutOfRange));
+
+        assert_eq!(chip.log[1..], [(CMD_FAST_READ, Some(0x1_0f00)), (CMD_FAST_READ, Some((2 << 20) - 16))]);
+    }
+
+    #[test]
+    fn erase() {
+        let mut chip = Chip::new();
+        chip.data.fill(0);
+
+        let mut flash = SpiNor::probe(&mut chip).unwrap();
+        flash.erase(0x3000, 0x2000).unwrap();
+        assert_eq!(flash.erase(0x3800, 0x1000), Err(Error::Unsupported));
+        assert_eq!(flash.erase(0x3000, 0x800), Err(Error::Unsupported));
+        assert_eq!(flash.erase(0x1f_f000, 0x2000), Err(Error::OutOfRange));
+
+        assert!(chip.data[..0x3000].iter().all(|&b| b == 0));
+        assert!(chip.data[0x3000..0x5000].iter().all(|&b| b == 0xff));
+        assert!(chip.data[0x5000..].iter().all(|&b| b == 0));
+
+        // Write enable before each sector, then polls until WIP clears
+        let commands: Vec<_> = chip.log[1..].iter().map(|&(cmd, _)| cmd).collect();
+        let sector = [CMD_WRITE_ENABLE, CMD_READ_STATUS, CMD_SECTOR_ERASE, CMD_READ_STATUS, CMD_READ_STATUS, CMD_READ_STATUS, CMD_READ_STATUS];
+        assert_eq!(commands, [sector, sector].concat());
+        assert_eq!(chip.log[3], (CMD_SECTOR_ERASE, Some(0x3000)));
+        assert_eq!(chip.log[10], (CMD_SECTOR_ERASE, Some(0x4000)));
+    }
+
+    #[test]
+    fn program() {
+        let mut chip = Chip::new();
+        let data = pattern(700, 0xa5);
+
+        let mut flash = SpiNor::probe(&mut chip).unwrap();
+        flash.write(0x20f0, &data).unwrap();
+        assert_eq!(flash.write((2 << 20) - 1, &[0, 0]), Err(Error::OutOfRange));
+
+        // Programming only clears bits
+        flash.write(0x20f0, &[0xf0]).unwrap();
+
+        let mut expected = data.clone();
+        expected[0] &= 0xf0;
+        assert_eq!(chip.data[0x20f0..0x20f0 + data.len()], expected);
+        assert!(chip.data[..0x20f0].iter().chain(&chip.data[0x20f0 + data.len()..]).all(|&b| b == 0xff));
+
+        // Split at page boundaries
+        let programs: Vec<_> = chip.log.iter().filter(|(cmd, _)| *cmd == CMD_PAGE_PROGRAM).map(|&(_, a)| a.unwrap()).collect();
+        assert_eq!(programs, [0x20f0, 0x2100, 0x2200, 0x2300, 0x20f0]);
+    }
+
+    #[test]
+    fn timeouts() {
+        // WEL never sets
+        let mut chip = Chip { write_protected: true, ..Chip::new() };
+        let mut flash = SpiNor::probe(&mut chip).unwrap();
+        assert_eq!(flash.erase(0, SECTOR_SIZE), Err(Error::Timeout));
+        assert_eq!(flash.write(0, &[0]), Err(Error::Timeout));
+        assert!(chip.log.iter().all(|&(cmd, _)| cmd != CMD_SECTOR_ERASE && cmd != CMD_PAGE_PROGRAM));
+
+        // WIP doesn't clear in time
+        let start = crate::sim::ticks();
+        let mut chip = Chip { busy_reads: u32::MAX, ..Chip::new() };
+        let mut flash = SpiNor::probe(&mut chip).unwrap();
+        assert_eq!(flash.write(0, &[0]), Err(Error::Timeout));
+        let waited = (crate::sim::ticks() - start) / (TIMER_FREQ / 1_000_000);
+        assert!((PROGRAM_TIMEOUT_US..PROGRAM_TIMEOUT_US + 100).contains(&waited), "{waited} us");
+        assert_eq!(chip.data[0], 0);
+    }
+}
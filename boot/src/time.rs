@@ -1,6 +1,16 @@
+#[cfg(not(any(test, feature = "sim")))]
 use core::arch::asm;
 
-unsafe fn timer_csr() -> u64 {
+/// Ticks of the 24 MHz system timer
+pub const TIMER_FREQ: u64 = 24_000_000;
+
+#[cfg(any(test, feature = "sim"))]
+pub unsafe fn timer_csr() -> u64 {
+    crate::sim::ticks()
+}
+
+#[cfg(not(any(test, feature = "sim")))]
+pub unsafe fn timer_csr() -> u64 {
     let mut timer = core::mem::MaybeUninit::<u64>::uninit();
 
     unsafe { asm!("csrr {timer}, time", timer = out(reg) * timer.as_mut_ptr()) };
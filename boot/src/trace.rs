@@ -0,0 +1,240 @@
+//! MMIO access trace with the `mmio-trace` feature: every register read and
+//! write through [`crate::mmio`] is logged with its call site, to compare a
+//! DRAM init against another bootloader's.
+//!
+//! Until DRAM is up the entries go to a small ring in SRAM, [`use_dram`]
+//! moves them to a larger one right below the bootloader's stack. Either ring
+//! keeps the latest entries. The `mmiotrace` monitor command prints it, one
+//! access per line:
+//!
+//! ```text
+//! W32 0x03103000 0x00000003 old=0x00000001 @0x0002123a
+//! R32 0x03103010 0x00000001 @0x00021246
+//! W32 0x04025200 0x0000009f @0x00022b10
+//! ```
+//!
+//! Writes only log the value before (`old=`) in [`OLD_VALUE`], where the
+//! extra read is harmless. Elsewhere it could pop a FIFO or clear a status
+//! bit behind the driver's back.
+//!
+//! The call site is the return address into the code making the access:
+//! `#[track_caller]` locations would cost SRAM at each of them, which the
+//! DRAM init alone has hundreds of. scripts/mmiodiff resolves it with
+//! addr2line given the ELF built with line tables, and compares two traces.
+//! Consecutive identical reads from one call site, as in a poll, are logged
+//! once.
+
+#[cfg(not(any(test, feature = "sim")))]
+use core::arch::asm;
+use core::mem::MaybeUninit;
+use core::ops::Range;
+
+use crate::mmio::{Backend, Volatile, Word};
+use crate::uart;
+
+/// Entries kept in SRAM until DRAM is up, printed if DRAM init fails. The
+/// oldest are lost beyond these, raise it if SRAM permits.
+const EARLY_ENTRIES: usize = 160;
+
+/// The ring in DRAM, right below the bootloader's stack
+pub const DRAM_RING_SIZE: u64 = 1024 * 1024;
+
+/// Console traffic isn't traced: the dump would trace itself, and reading a
+/// UART register before writing it can pop the receive FIFO
+const UNTRACED: Range<u64> = 0x0250_0000..0x0250_0400;
+
+/// Registers read before each write to log their old value: the CCU, and
+/// the DRAM controller and its PHY
+const OLD_VALUE: [Range<u64>; 2] = [0x0200_1000..0x0200_2000, 0x0310_2000..0x0310_4000];
+
+/// Return addresses are in SRAM A1, the top byte of [`Entry::site`] holds
+/// the access width in bytes, whether it's a write and whether
+/// [`Entry::old`] was read
+const SITE_MASK: u32 = 0x00ff_ffff;
+const WIDTH_SHIFT: u32 = 24;
+const WRITE: u32 = 1 << 31;
+const OLD: u32 = 1 << 30;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    site: u32,
+    addr: u32,
+    /// For writes in [`OLD_VALUE`], what the register read before
+    old: u32,
+    value: u32,
+}
+
+struct Ring {
+    buf: *mut Entry,
+    cap: usize,
+    /// Where the next entry goes
+    next: usize,
+    len: usize,
+    lost: usize,
+}
+
+static mut EARLY: [MaybeUninit<Entry>; EARLY_ENTRIES] = [const { MaybeUninit::uninit() }; EARLY_ENTRIES];
+
+/// A null `buf` stands for [`EARLY`]
+static mut RING: Ring = Ring {
+    buf: core::ptr::null_mut(),
+    cap: EARLY_ENTRIES,
+    next: 0,
+    len: 0,
+    lost: 0,
+};
+
+impl Ring {
+    fn buf(&self) -> *mut Entry {
+        if self.buf.is_null() {
+            core::ptr::addr_of_mut!(EARLY).cast()
+        } else {
+            self.buf
+        }
+    }
+
+    /// Index of the entry `back` places before `next`, `back` <= `cap`
+    fn before(&self, back: usize) -> usize {
+        if self.next >= back {
+            self.next - back
+        } else {
+            self.next + self.cap - back
+        }
+    }
+
+    fn push(&mut self, entry: Entry) {
+        unsafe {
+            if self.len > 0 {
+                let last = &*self.buf().add(self.before(1));
+                if entry.site & WRITE == 0
+                    && entry.site == last.site
+                    && entry.addr == last.addr
+                    && entry.value == last.value
+                {
+                    return;
+                }
+            }
+
+            self.buf().add(self.next).write(entry);
+        }
+
+        self.next += 1;
+        if self.next == self.cap {
+            self.next = 0;
+        }
+
+        if self.len == self.cap {
+            self.lost += 1;
+        } else {
+            self.len += 1;
+        }
+    }
+}
+
+/// The return address of the function calling this, which must be the
+/// first thing it does: `ra` still holds it until the first call
+#[cfg(not(any(test, feature = "sim")))]
+#[inline(always)]
+fn return_address() -> u32 {
+    let ra: u64;
+    unsafe { asm!("mv {}, ra", out(reg) ra, options(nomem, nostack, preserves_flags)) };
+    ra as u32
+}
+
+/// The simulated register file of the host builds is logged by itself
+#[cfg(any(test, feature = "sim"))]
+fn return_address() -> u32 {
+    0
+}
+
+fn record(ra: u32, kind: u32, addr: u64, old: u64, value: u64) {
+    let entry = Entry {
+        site: ra & SITE_MASK | kind,
+        addr: addr as u32,
+        old: old as u32,
+        value: value as u32,
+    };
+
+    unsafe { (*core::ptr::addr_of_mut!(RING)).push(entry) };
+}
+
+/// [`crate::mmio::Bus`] with the `mmio-trace` feature. Out of line, so that
+/// `ra` points at the access.
+pub struct Traced;
+
+impl Backend for Traced {
+    #[inline(never)]
+    unsafe fn read<T: Word>(addr: u64) -> T {
+        let ra = return_address();
+        let v = unsafe { Volatile::read::<T>(addr) };
+
+        if !UNTRACED.contains(&addr) {
+            record(ra, (T::BYTES as u32) << WIDTH_SHIFT, addr, 0, v.to_u64());
+        }
+
+        v
+    }
+
+    #[inline(never)]
+    unsafe fn write<T: Word>(addr: u64, v: T) {
+        let ra = return_address();
+
+        if UNTRACED.contains(&addr) {
+            unsafe { Volatile::write(addr, v) };
+            return;
+        }
+
+        let kind = (T::BYTES as u32) << WIDTH_SHIFT | WRITE;
+
+        if !OLD_VALUE.iter().any(|r| r.contains(&addr)) {
+            unsafe { Volatile::write(addr, v) };
+            record(ra, kind, addr, 0, v.to_u64());
+            return;
+        }
+
+        let old = unsafe { Volatile::read::<T>(addr) };
+        unsafe { Volatile::write(addr, v) };
+
+        record(ra, kind | OLD, addr, old.to_u64(), v.to_u64());
+    }
+}
+
+/// Where the DRAM ring goes once DRAM is up
+pub fn dram_ring() -> u64 {
+    crate::handoff::addr() - DRAM_RING_SIZE
+}
+
+/// Moves the trace to the ring in DRAM, right after DRAM init
+pub unsafe fn use_dram() {
+    let ring = unsafe { &mut *core::ptr::addr_of_mut!(RING) };
+    let buf = dram_ring() as *mut Entry;
+
+    for i in 0..ring.len {
+        unsafe { buf.add(i).write(ring.buf().add(ring.before(ring.len - i)).read()) };
+    }
+
+    ring.buf = buf;
+    ring.cap = DRAM_RING_SIZE as usize / size_of::<Entry>();
+    ring.next = ring.len;
+}
+
+/// Prints the trace, oldest access first
+pub fn dump() {
+    let ring = unsafe { &*core::ptr::addr_of!(RING) };
+
+    uart::printf!("mmio trace: %d accesses, %d lost\r\n", ring.len, ring.lost);
+
+    for i in 0..ring.len {
+        let e = unsafe { &*ring.buf().add(ring.before(ring.len - i)) };
+        let bits = (e.site & !(WRITE | OLD)) >> WIDTH_SHIFT << 3;
+        let op = if e.site & WRITE != 0 { "W" } else { "R" };
+
+        uart::printf!("%s%d 0x%08x 0x%08x", op, bits, e.addr, e.value);
+
+        if e.site & OLD != 0 {
+            uart::printf!(" old=0x%08x", e.old);
+        }
+
+        uart::printf!(" @0x%08x\r\n", e.site & SITE_MASK);
+    }
+}
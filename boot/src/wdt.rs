@@ -0,0 +1,182 @@
+//! Watchdog of the D1
+//!
+//! Once enabled it resets the whole system unless it's fed within the
+//! period.
+
+use crate::mmio::*;
+
+const WDT_BASE: u64 = 0x020500a0;
+
+pub const WDT_CTRL: u64 = 0x10;
+pub const WDT_CFG: u64 = 0x14;
+pub const WDT_MODE: u64 = 0x18;
+
+/// Has to be in 31:16 of every CFG and MODE write
+const WDT_KEY: u32 = 0x16aa;
+/// Has to be in 12:1 of CTRL together with WDOG_RESTART
+const WDT_RESTART_KEY: u32 = 0x0a57;
+
+/// Periods in ms, indexed by WDOG_INTV_VALUE
+const PERIODS_MS: [u32; 12] = [
+    500, 1000, 2000, 3000, 4000, 5000, 6000, 8000, 10000, 12000, 14000, 16000,
+];
+
+/// What fatal errors do once they printed their message
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OnFatal {
+    /// Spin until the board is power cycled, leaves the state for a debugger
+    Hang,
+    /// Reset the board through the watchdog
+    Reset,
+}
+
+pub struct Watchdog {
+    base: u64,
+}
+
+impl Watchdog {
+    pub const fn new(base: u64) -> Self {
+        Self { base }
+    }
+
+    fn read(&self, reg: u64) -> u32 {
+        unsafe { read32(self.base + reg) }
+    }
+
+    fn write(&self, reg: u64, v: u32) {
+        unsafe { write32(self.base + reg, v) }
+    }
+
+    /// Starts the countdown with the shortest period of at least `ms`, 16 s
+    /// at most. Returns the period in ms.
+    pub fn enable(&self, ms: u32) -> u32 {
+        let intv = PERIODS_MS.iter().position(|&p| p >= ms).unwrap_or(PERIODS_MS.len() - 1);
+        let period = PERIODS_MS.get(intv).copied().unwrap_or(0);
+
+        self.write(
+            WDT_CFG,
+            WDT_KEY << 16 // KEY_FIELD
+                | 1, // WDOG_CONFIG = to whole system
+        );
+        self.write(
+            WDT_MODE,
+            WDT_KEY << 16 // KEY_FIELD
+                | (intv as u32) << 4 // WDOG_INTV_VALUE
+                | 1, // WDOG_EN
+        );
+        self.feed();
+
+        period
+    }
+
+    pub fn disable(&self) {
+        self.write(WDT_MODE, WDT_KEY << 16);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.read(WDT_MODE) & 1 != 0
+    }
+
+    /// Restarts the countdown
+    pub fn feed(&self) {
+        self.write(
+            WDT_CTRL,
+            WDT_RESTART_KEY << 1 // KEY_FIELD
+                | 1, // WDOG_RESTART
+        );
+    }
+
+    /// Lets the watchdog reset the system after the shortest period
+    pub fn trigger_reset(&self) {
+        self.enable(0);
+    }
+}
+
+pub static WDT: Watchdog = Watchdog::new(WDT_BASE);
+
+/// Resets the whole system through the watchdog
+pub fn reset() -> ! {
+    WDT.trigger_reset();
+
+    // Nothing resets the host builds
+    #[cfg(any(test, feature = "sim"))]
+    panic!("watchdog reset");
+
+    #[cfg(not(any(test, feature = "sim")))]
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Ends a fatal error path the way [`crate::ON_FATAL`] says
+pub fn fatal() -> ! {
+    if crate::ON_FATAL == OnFatal::Reset {
+        crate::uart::printf!("resetting\r\n");
+        reset();
+    }
+
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Access, Op};
+
+    fn write(reg: u64, value: u32) -> Access {
+        Access {
+            op: Op::Write,
+            addr: WDT_BASE + reg,
+            value: value as u64,
+            width: 4,
+        }
+    }
+
+    #[test]
+    fn enable() {
+        let cases = [
+            (0, 0, 500),
+            (500, 0, 500),
+            (501, 1, 1000),
+            (4000, 4, 4000),
+            (7000, 7, 8000),
+            (16000, 11, 16000),
+            (60000, 11, 16000),
+        ];
+
+        for (ms, intv, period) in cases {
+            sim::reset();
+            assert_eq!(WDT.enable(ms), period, "{ms} ms");
+            assert_eq!(
+                sim::take_log(),
+                [write(WDT_CFG, 0x16aa_0001), write(WDT_MODE, 0x16aa_0001 | intv << 4), write(WDT_CTRL, 0x14af)],
+                "{ms} ms"
+            );
+            assert!(WDT.is_enabled());
+        }
+    }
+
+    #[test]
+    fn disable_and_feed() {
+        sim::reset();
+        WDT.enable(1000);
+        WDT.disable();
+        assert!(!WDT.is_enabled());
+
+        sim::take_log();
+        WDT.feed();
+        assert_eq!(sim::take_log(), [write(WDT_CTRL, 0x14af)]);
+    }
+
+    #[test]
+    fn trigger_reset() {
+        sim::reset();
+        WDT.trigger_reset();
+
+        // Shortest period, resetting the whole system
+        assert_eq!(sim::get(WDT_BASE + WDT_CFG), 0x16aa_0001);
+        assert_eq!(sim::get(WDT_BASE + WDT_MODE), 0x16aa_0001);
+    }
+}
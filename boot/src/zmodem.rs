@@ -192,7 +192,7 @@ impl ZModem {
 
         if crc != 0 {
             boot_panic!(
-                "invalid hex header CRC: %x %x %x %x %x",
+                "invalid hex header CRC: %02x %02x %02x %02x %02x",
                 unsafe { buf.get_unchecked(0) },
                 unsafe { buf.get_unchecked(1) },
                 unsafe { buf.get_unchecked(2) },
@@ -223,7 +223,7 @@ impl ZModem {
 
         if crc != 0 {
             boot_panic!(
-                "invalid BIN16 header CRC: %x %x %x %x %x",
+                "invalid BIN16 header CRC: %02x %02x %02x %02x %02x",
                 unsafe { buf.get_unchecked(0) },
                 unsafe { buf.get_unchecked(1) },
                 unsafe { buf.get_unchecked(2) },
@@ -277,14 +277,14 @@ impl ZModem {
         }
 
         if ![ZCRCE, ZCRCG, ZCRCQ, ZCRCW].contains(&typ) {
-            boot_panic!("invalid subpacket type: 0x%x", typ);
+            boot_panic!("invalid subpacket type: 0x%02x", typ);
         }
 
         crc.update(self.rx_bin().as_u8());
         crc.update(self.rx_bin().as_u8());
 
         if crc.finish() != 0 {
-            boot_panic!("invalid subpacket CRC (subpacket 0x%x)", typ);
+            boot_panic!("invalid subpacket CRC (subpacket 0x%02x)", typ);
         }
 
         Subpacket {
@@ -338,7 +338,7 @@ impl ZModem {
             } else if packet.typ == ZCRCG {
                 continue;
             } else {
-                boot_panic!("unsupported subpacket type: 0x%x", packet.typ);
+                boot_panic!("unsupported subpacket type: 0x%02x", packet.typ);
             }
         }
 
@@ -0,0 +1,40 @@
+/// `core::fmt` based console on UART0, polled until `uart::init_irq` is called
+pub struct Console;
+
+impl core::fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &b in s.as_bytes() {
+            crate::uart::write(b);
+        }
+
+        Ok(())
+    }
+}
+
+pub fn printv(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    let _ = Console.write_fmt(args);
+}
+
+#[allow(unused_macros)]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::console::printv(format_args!($($arg)*))
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! println {
+    () => {
+        $crate::console::printv(format_args!("\r\n"))
+    };
+    ($fmt:expr $(, $arg:expr)* $(,)?) => {
+        $crate::console::printv(format_args!(concat!($fmt, "\r\n") $(, $arg)*))
+    };
+}
+
+#[allow(unused_imports)]
+pub(crate) use print;
+#[allow(unused_imports)]
+pub(crate) use println;
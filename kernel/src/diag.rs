@@ -0,0 +1,71 @@
+//! Boot diagnostic record of the bootloader
+//!
+//! Fatal errors of the kernel end up in the record, so the bootloader can
+//! tell what ended the previous boot after the reset. Keep the layout in sync
+//! with boot/src/diag.rs.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const MAGIC: u32 = 0xd1a6;
+
+pub const ERROR_KERNEL_PANIC: u8 = 5;
+pub const ERROR_KERNEL_TRAP: u8 = 6;
+
+/// Address of the record from the boot information, 0 if there's none
+static ADDR: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn read32(addr: u64) -> u32 {
+    unsafe { core::ptr::read_volatile(addr as *const u32) }
+}
+
+unsafe fn write32(addr: u64, v: u32) {
+    unsafe { core::ptr::write_volatile(addr as *mut u32, v) }
+}
+
+/// CRC-16/CCITT-FALSE of the words in little endian byte order
+fn crc16(words: &[u32; 3]) -> u16 {
+    let mut crc = 0xffffu16;
+
+    for w in words {
+        for i in 0..4 {
+            crc ^= ((w >> (i * 8)) as u16 & 0xff) << 8;
+
+            for _ in 0..8 {
+                crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+            }
+        }
+    }
+
+    crc
+}
+
+pub fn init(addr: u64) {
+    ADDR.store(addr, Ordering::Relaxed);
+}
+
+/// Records the fatal error ending this boot. The first one is kept, so a
+/// trap isn't overwritten by the panic it turns into.
+pub fn set_error(error: u8, mcause: u64, mepc: u64) {
+    let addr = ADDR.load(Ordering::Relaxed);
+    if addr == 0 {
+        return;
+    }
+
+    unsafe {
+        let w1 = read32(addr + 4);
+        if w1 & 0xff != 0 {
+            return;
+        }
+
+        let body = [
+            w1 | error as u32,
+            (mcause & 0x7fff_ffff) as u32 | ((mcause >> 63) as u32) << 31,
+            mepc as u32,
+        ];
+
+        write32(addr + 4, body[0]);
+        write32(addr + 8, body[1]);
+        write32(addr + 12, body[2]);
+        write32(addr, MAGIC << 16 | crc16(&body) as u32);
+    }
+}
@@ -0,0 +1,88 @@
+//! Boot information from the bootloader
+//!
+//! `_start` gets a0 = hart id, a1 = device tree address (0 if none) and
+//! a2 = address of [`BootInfo`].
+//!
+//! The kernel owns the watchdog: with [`BootInfo::watchdog_ms`] set the
+//! bootloader left it running, and it resets the board unless the kernel
+//! feeds it or turns it off.
+
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"OS5BOOT\0");
+pub const BOOT_INFO_VERSION: u32 = 4;
+
+/// [`BootInfo::slot`] when the kernel wasn't booted from an A/B slot
+pub const NO_SLOT: u32 = u32::MAX;
+
+/// Keep in sync with boot/src/handoff.rs
+#[repr(C)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    /// Size of this structure, grows with new versions
+    pub size: u32,
+    pub dram_base: u64,
+    pub dram_size: u64,
+    pub fdt: u64,
+    pub initrd_start: u64,
+    pub initrd_size: u64,
+    /// NUL terminated, 0 if there's no command line
+    pub cmdline: u64,
+    /// A/B slot the kernel was booted from, 0 = A, 1 = B or [`NO_SLOT`]
+    pub slot: u32,
+    /// Written to the 32-bit register at `confirm_addr` by [`confirm_boot`]
+    ///
+    /// [`confirm_boot`]: BootInfo::confirm_boot
+    pub confirm_value: u32,
+    /// 0 if there's nothing to confirm
+    pub confirm_addr: u64,
+    /// Boots since power-on including this one, the rest is about the
+    /// previous boot and 0 if there was none
+    pub boot_count: u32,
+    pub last_stage: u16,
+    pub last_error: u16,
+    pub last_mcause: u64,
+    pub last_mepc: u64,
+    /// Boot diagnostic record to report fatal errors in, see [`crate::diag`]
+    pub diag_addr: u64,
+    /// Period of the watchdog the bootloader left running, 0 if it's off
+    pub watchdog_ms: u32,
+}
+
+impl BootInfo {
+    /// The structure at `addr` if it looks like one of a known version
+    pub unsafe fn from_addr(addr: u64) -> Option<&'static BootInfo> {
+        let info = unsafe { (addr as *const BootInfo).as_ref()? };
+
+        let valid = info.magic == BOOT_INFO_MAGIC
+            && info.version >= BOOT_INFO_VERSION
+            && info.size as usize >= size_of::<BootInfo>();
+
+        valid.then_some(info)
+    }
+
+    pub fn cmdline(&self) -> Option<&'static str> {
+        if self.cmdline == 0 {
+            return None;
+        }
+
+        let cmdline = unsafe { core::ffi::CStr::from_ptr(self.cmdline as *const core::ffi::c_char) };
+
+        cmdline.to_str().ok()
+    }
+
+    /// Tells the bootloader this slot boots, otherwise it falls back to the
+    /// other one after a few tries. Returns the slot name if there was one.
+    pub fn confirm_boot(&self) -> Option<&'static str> {
+        if self.confirm_addr == 0 {
+            return None;
+        }
+
+        unsafe { core::ptr::write_volatile(self.confirm_addr as *mut u32, self.confirm_value) };
+
+        match self.slot {
+            0 => Some("a"),
+            1 => Some("b"),
+            _ => Some("?"),
+        }
+    }
+}
@@ -1,28 +1,76 @@
 #![no_std]
 #![no_main]
+#![allow(dead_code)]
 
-const UART0_BASE: u64 = 0x02500000;
-const UART_USR: u64 = 0x7c;
-const UART_THR: u64 = 0x00;
-
+mod console;
+mod diag;
+mod handoff;
 mod panic;
+mod plic;
+mod ring;
+mod trap;
+mod uart;
+mod wdt;
 
-fn uart_write(b: u8) {
-    unsafe {
-        while core::ptr::read_volatile((UART0_BASE + UART_USR) as *mut u32) & (1 << 1) == 0 {}
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn _start(hartid: u64, fdt: u64, info: u64) -> ! {
+    console::println!("hello from kernel on hart {}", hartid);
+
+    match unsafe { handoff::BootInfo::from_addr(info) } {
+        Some(info) => {
+            console::println!(
+                "DRAM: {} MiB at {:#x}, fdt: {:#x}",
+                info.dram_size / (1024 * 1024),
+                info.dram_base,
+                fdt
+            );
 
-        core::ptr::write_volatile((UART0_BASE + UART_THR) as *mut u32, b as u32);
+            if info.initrd_size != 0 {
+                console::println!("initrd: {} bytes at {:#x}", info.initrd_size, info.initrd_start);
+            }
+
+            if let Some(cmdline) = info.cmdline() {
+                console::println!("cmdline: {}", cmdline);
+            }
+
+            if info.boot_count > 1 {
+                console::println!(
+                    "boot #{}, last boot reached stage {}, error {}, mcause {:#x}, mepc {:#x}",
+                    info.boot_count,
+                    info.last_stage,
+                    info.last_error,
+                    info.last_mcause,
+                    info.last_mepc
+                );
+            }
+
+            diag::init(info.diag_addr);
+        }
+        None => console::println!("no boot information from the bootloader"),
     }
-}
 
-fn puts(s: &[u8]) {
-    for &b in s {
-        uart_write(b);
+    unsafe {
+        trap::init();
+        uart::init_irq();
+        trap::enable_interrupts();
     }
-}
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn _start() -> ! {
-    puts(b"hello from kernel\r\n");
-    loop {}
+    console::println!("UART0 is interrupt driven now");
+
+    // Getting this far is what counts as a good boot
+    if let Some(info) = unsafe { handoff::BootInfo::from_addr(info) } {
+        if let Some(slot) = info.confirm_boot() {
+            console::println!("confirmed boot slot {}", slot);
+        }
+
+        // It only guarded the boot up to here
+        if info.watchdog_ms != 0 {
+            wdt::disable();
+        }
+    }
+
+    loop {
+        let b = uart::read();
+        uart::write(b);
+    }
 }
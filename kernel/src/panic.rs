@@ -1,6 +1,29 @@
 use core::panic::PanicInfo;
 
+/// Reset the board through the watchdog after a panic instead of hanging
+const RESET_ON_PANIC: bool = true;
+
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    unsafe { crate::trap::disable_interrupts() };
+    crate::uart::enter_polled();
+    crate::diag::set_error(crate::diag::ERROR_KERNEL_PANIC, 0, 0);
+
+    match info.location() {
+        Some(location) => crate::console::println!(
+            "kernel panic at {}:{}:{}: {}",
+            location.file(),
+            location.line(),
+            location.column(),
+            info.message()
+        ),
+        None => crate::console::println!("kernel panic: {}", info.message()),
+    }
+
+    if RESET_ON_PANIC {
+        crate::console::println!("resetting");
+        crate::wdt::reset();
+    }
+
     loop {}
 }
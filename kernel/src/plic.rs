@@ -0,0 +1,52 @@
+//! Platform-level interrupt controller, M-mode context of hart 0
+
+const PLIC_BASE: u64 = 0x1000_0000;
+const PLIC_PRIO: u64 = 0x0000; // 4 bytes per interrupt source
+const PLIC_M_IE: u64 = 0x2000; // 1 bit per interrupt source
+const PLIC_M_TH: u64 = 0x20_0000;
+const PLIC_M_CLAIM: u64 = 0x20_0004;
+
+pub const IRQ_UART0: u32 = 18;
+
+unsafe fn read32(addr: u64) -> u32 {
+    unsafe { core::ptr::read_volatile(addr as *const u32) }
+}
+
+unsafe fn write32(addr: u64, v: u32) {
+    unsafe { core::ptr::write_volatile(addr as *mut u32, v) }
+}
+
+pub unsafe fn init() {
+    unsafe {
+        // Accept every interrupt with a priority above 0
+        write32(PLIC_BASE + PLIC_M_TH, 0);
+    }
+}
+
+pub unsafe fn enable(irq: u32, priority: u32) {
+    unsafe {
+        write32(PLIC_BASE + PLIC_PRIO + irq as u64 * 4, priority);
+
+        let ie = PLIC_BASE + PLIC_M_IE + (irq as u64 / 32) * 4;
+        write32(ie, read32(ie) | (1 << (irq % 32)));
+    }
+}
+
+pub unsafe fn disable(irq: u32) {
+    unsafe {
+        let ie = PLIC_BASE + PLIC_M_IE + (irq as u64 / 32) * 4;
+        write32(ie, read32(ie) & !(1 << (irq % 32)));
+    }
+}
+
+/// Returns the highest priority pending interrupt, if any
+pub unsafe fn claim() -> Option<u32> {
+    match unsafe { read32(PLIC_BASE + PLIC_M_CLAIM) } {
+        0 => None,
+        irq => Some(irq),
+    }
+}
+
+pub unsafe fn complete(irq: u32) {
+    unsafe { write32(PLIC_BASE + PLIC_M_CLAIM, irq) };
+}
@@ -0,0 +1,67 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Single-producer single-consumer byte queue shared between an interrupt
+/// handler and the rest of the kernel. `N` must be a power of two.
+pub struct RingBuffer<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    head: AtomicUsize, // free running write counter
+    tail: AtomicUsize, // free running read counter
+}
+
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    const MASK: usize = {
+        assert!(N.is_power_of_two());
+        N - 1
+    };
+
+    pub const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns false if the buffer is full
+    pub fn push(&self, b: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        if head.wrapping_sub(tail) == N {
+            return false;
+        }
+
+        unsafe { (*self.buf.get())[head & Self::MASK] = b };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+
+        true
+    }
+
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        let b = unsafe { (*self.buf.get())[tail & Self::MASK] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+
+        Some(b)
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        head.wrapping_sub(tail)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
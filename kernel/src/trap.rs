@@ -0,0 +1,51 @@
+use core::arch::{asm, global_asm};
+
+use crate::{plic, uart};
+
+global_asm!(include_str!("trap.S"));
+
+unsafe extern "C" {
+    fn trap_entry();
+}
+
+const MCAUSE_INTERRUPT: u64 = 1 << 63;
+const IRQ_M_EXT: u64 = 11;
+
+const MIE_MEIE: u64 = 1 << 11;
+const MSTATUS_MIE: u64 = 1 << 3;
+
+pub unsafe fn init() {
+    unsafe {
+        asm!("csrw mtvec, {}", in(reg) trap_entry as *const () as usize);
+        plic::init();
+    }
+}
+
+pub unsafe fn enable_interrupts() {
+    unsafe {
+        asm!("csrs mie, {}", in(reg) MIE_MEIE);
+        asm!("csrs mstatus, {}", in(reg) MSTATUS_MIE);
+    }
+}
+
+pub unsafe fn disable_interrupts() {
+    unsafe { asm!("csrc mstatus, {}", in(reg) MSTATUS_MIE) };
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn trap_handler(mcause: u64, mepc: u64) {
+    if mcause == MCAUSE_INTERRUPT | IRQ_M_EXT {
+        while let Some(irq) = unsafe { plic::claim() } {
+            if irq == plic::IRQ_UART0 {
+                uart::handle_irq();
+            }
+
+            unsafe { plic::complete(irq) };
+        }
+
+        return;
+    }
+
+    crate::diag::set_error(crate::diag::ERROR_KERNEL_TRAP, mcause, mepc);
+    panic!("unhandled trap: mcause = {:#x}, mepc = {:#x}", mcause, mepc);
+}
@@ -0,0 +1,243 @@
+//! UART0 driver
+//!
+//! Until [`init_irq`] is called every access polls the FIFO status in
+//! `UART_USR`, which is what early boot output (and the panic handler) relies
+//! on. Afterwards received bytes are drained into [`RX`] by the interrupt
+//! handler, and writes are queued in [`TX`] and fed to the FIFO from the THR
+//! empty interrupt, so nothing is lost while the kernel is busy.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::plic;
+use crate::ring::RingBuffer;
+
+const UART0_BASE: u64 = 0x02500000;
+const UART_RBR: u64 = 0x00;
+const UART_THR: u64 = 0x00;
+const UART_IER: u64 = 0x04;
+const UART_IIR: u64 = 0x08;
+const UART_LSR: u64 = 0x14;
+const UART_MSR: u64 = 0x18;
+const UART_USR: u64 = 0x7c;
+
+const IER_ERBFI: u32 = 1 << 0; // received data available
+const IER_ETBEI: u32 = 1 << 1; // transmit holding register empty
+const IER_ELSI: u32 = 1 << 2; // receiver line status
+
+const IIR_IID_MASK: u32 = 0xf;
+const IIR_MODEM_STATUS: u32 = 0x0;
+const IIR_NO_INTERRUPT: u32 = 0x1;
+const IIR_THR_EMPTY: u32 = 0x2;
+const IIR_RX_DATA: u32 = 0x4;
+const IIR_RX_LINE_STATUS: u32 = 0x6;
+const IIR_BUSY_DETECT: u32 = 0x7;
+const IIR_CHAR_TIMEOUT: u32 = 0xc;
+
+const LSR_DR: u32 = 1 << 0; // data ready
+const LSR_OE: u32 = 1 << 1; // overrun error
+const LSR_PE: u32 = 1 << 2; // parity error
+const LSR_FE: u32 = 1 << 3; // framing error
+const LSR_BI: u32 = 1 << 4; // break interrupt
+
+const USR_TFNF: u32 = 1 << 1; // TX FIFO not full
+const USR_RFNE: u32 = 1 << 3; // RX FIFO not empty
+
+static IRQ_MODE: AtomicBool = AtomicBool::new(false);
+
+static RX: RingBuffer<1024> = RingBuffer::new();
+static TX: RingBuffer<1024> = RingBuffer::new();
+
+static OVERRUN_ERRORS: AtomicU32 = AtomicU32::new(0);
+static PARITY_ERRORS: AtomicU32 = AtomicU32::new(0);
+static FRAMING_ERRORS: AtomicU32 = AtomicU32::new(0);
+static BREAKS: AtomicU32 = AtomicU32::new(0);
+static RX_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    /// Bytes lost because the hardware RX FIFO overflowed (`UART_LSR[OE]`)
+    pub overrun_errors: u32,
+    pub parity_errors: u32,
+    pub framing_errors: u32,
+    pub breaks: u32,
+    /// Bytes lost because [`RX`] was full
+    pub rx_dropped: u32,
+}
+
+unsafe fn read32(addr: u64) -> u32 {
+    unsafe { core::ptr::read_volatile(addr as *const u32) }
+}
+
+unsafe fn write32(addr: u64, v: u32) {
+    unsafe { core::ptr::write_volatile(addr as *mut u32, v) }
+}
+
+/// Switches UART0 to interrupt-driven operation. The line settings programmed
+/// by the bootloader are kept as is.
+pub unsafe fn init_irq() {
+    unsafe {
+        write32(UART0_BASE + UART_IER, IER_ERBFI | IER_ELSI);
+
+        IRQ_MODE.store(true, Ordering::Release);
+
+        plic::enable(plic::IRQ_UART0, 1);
+    }
+}
+
+/// Goes back to polled operation, flushing whatever is still queued in [`TX`].
+/// Used on fatal paths where interrupts can no longer be relied upon.
+pub fn enter_polled() {
+    unsafe { write32(UART0_BASE + UART_IER, 0) };
+
+    if IRQ_MODE.swap(false, Ordering::AcqRel) {
+        while let Some(b) = TX.pop() {
+            write_polled(b);
+        }
+    }
+}
+
+pub fn write_polled(b: u8) {
+    unsafe {
+        while read32(UART0_BASE + UART_USR) & USR_TFNF == 0 {}
+
+        write32(UART0_BASE + UART_THR, b as u32);
+    }
+}
+
+/// Queues `b` for transmission, returns false if it can't be sent right away
+pub fn try_write(b: u8) -> bool {
+    if !IRQ_MODE.load(Ordering::Acquire) {
+        if unsafe { read32(UART0_BASE + UART_USR) } & USR_TFNF == 0 {
+            return false;
+        }
+
+        unsafe { write32(UART0_BASE + UART_THR, b as u32) };
+        return true;
+    }
+
+    if !TX.push(b) {
+        return false;
+    }
+
+    // The handler masks the THR empty interrupt once TX runs dry
+    unsafe { write32(UART0_BASE + UART_IER, IER_ERBFI | IER_ELSI | IER_ETBEI) };
+
+    true
+}
+
+pub fn write(b: u8) {
+    while !try_write(b) {
+        core::hint::spin_loop();
+    }
+}
+
+pub fn try_read() -> Option<u8> {
+    if !IRQ_MODE.load(Ordering::Acquire) {
+        if unsafe { read32(UART0_BASE + UART_USR) } & USR_RFNE == 0 {
+            return None;
+        }
+
+        return Some((unsafe { read32(UART0_BASE + UART_RBR) } & 0xff) as u8);
+    }
+
+    RX.pop()
+}
+
+pub fn read() -> u8 {
+    loop {
+        if !IRQ_MODE.load(Ordering::Relaxed) {
+            if let Some(b) = try_read() {
+                return b;
+            }
+
+            continue;
+        }
+
+        // With MIE clear the RX interrupt can't fill the ring between the
+        // check and the wfi, and a pending interrupt still wakes the hart
+        unsafe { crate::trap::disable_interrupts() };
+
+        let b = try_read();
+        if b.is_none() {
+            unsafe { core::arch::asm!("wfi") };
+        }
+
+        unsafe { crate::trap::enable_interrupts() };
+
+        if let Some(b) = b {
+            return b;
+        }
+    }
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        overrun_errors: OVERRUN_ERRORS.load(Ordering::Relaxed),
+        parity_errors: PARITY_ERRORS.load(Ordering::Relaxed),
+        framing_errors: FRAMING_ERRORS.load(Ordering::Relaxed),
+        breaks: BREAKS.load(Ordering::Relaxed),
+        rx_dropped: RX_DROPPED.load(Ordering::Relaxed),
+    }
+}
+
+// Reading LSR clears the error bits, so every read has to be accounted for
+fn count_line_errors(lsr: u32) {
+    if lsr & LSR_OE != 0 {
+        OVERRUN_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+    if lsr & LSR_PE != 0 {
+        PARITY_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+    if lsr & LSR_FE != 0 {
+        FRAMING_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+    if lsr & LSR_BI != 0 {
+        BREAKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn drain_rx() {
+    loop {
+        let lsr = unsafe { read32(UART0_BASE + UART_LSR) };
+        count_line_errors(lsr);
+
+        if lsr & LSR_DR == 0 {
+            break;
+        }
+
+        let b = (unsafe { read32(UART0_BASE + UART_RBR) } & 0xff) as u8;
+        if !RX.push(b) {
+            RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn fill_tx() {
+    while unsafe { read32(UART0_BASE + UART_USR) } & USR_TFNF != 0 {
+        match TX.pop() {
+            Some(b) => unsafe { write32(UART0_BASE + UART_THR, b as u32) },
+            None => {
+                unsafe { write32(UART0_BASE + UART_IER, IER_ERBFI | IER_ELSI) };
+                break;
+            }
+        }
+    }
+}
+
+pub fn handle_irq() {
+    loop {
+        match unsafe { read32(UART0_BASE + UART_IIR) } & IIR_IID_MASK {
+            IIR_NO_INTERRUPT => break,
+            IIR_RX_DATA | IIR_CHAR_TIMEOUT | IIR_RX_LINE_STATUS => drain_rx(),
+            IIR_THR_EMPTY => fill_tx(),
+            IIR_BUSY_DETECT => {
+                // cleared by reading USR
+                let _ = unsafe { read32(UART0_BASE + UART_USR) };
+            }
+            _ => {
+                // IIR_MODEM_STATUS, cleared by reading MSR
+                let _ = unsafe { read32(UART0_BASE + UART_MSR) };
+            }
+        }
+    }
+}
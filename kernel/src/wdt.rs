@@ -0,0 +1,33 @@
+//! Watchdog of the D1, only used to reset the board and to stop the countdown
+//! the bootloader hands over
+
+const WDT_BASE: u64 = 0x020500a0;
+const WDT_CFG: u64 = 0x14;
+const WDT_MODE: u64 = 0x18;
+
+/// Has to be in 31:16 of every CFG and MODE write
+const WDT_KEY: u32 = 0x16aa;
+
+const CFG_WHOLE_SYSTEM: u32 = 1 << 0;
+const MODE_EN: u32 = 1 << 0; // with WDOG_INTV_VALUE = 0, 0.5 s
+
+unsafe fn write32(addr: u64, v: u32) {
+    unsafe { core::ptr::write_volatile(addr as *mut u32, v) }
+}
+
+/// Stops the countdown the bootloader may have left running
+pub fn disable() {
+    unsafe { write32(WDT_BASE + WDT_MODE, WDT_KEY << 16) };
+}
+
+/// Resets the whole system after 0.5 s, which leaves time to drain the UART
+pub fn reset() -> ! {
+    unsafe {
+        write32(WDT_BASE + WDT_CFG, WDT_KEY << 16 | CFG_WHOLE_SYSTEM);
+        write32(WDT_BASE + WDT_MODE, WDT_KEY << 16 | MODE_EN);
+    }
+
+    loop {
+        core::hint::spin_loop();
+    }
+}